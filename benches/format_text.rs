@@ -0,0 +1,102 @@
+use clipboard_formatter::{format_text, format_text_cow, AppConfig};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::borrow::Cow;
+
+const CONFIG: &str = r#"
+exclusions = ["　", "！", "？", "〜", "～"]
+
+[app]
+clipboard_poll_interval = 300
+config_reload_interval = 5000
+convert_katakana = true
+nfkc = true
+
+[replacements]
+"，" = ", "
+"．" = ". "
+"頚" = "頸"
+"CRLF" = "。"
+
+[regex_replacements]
+" {2,}" = " "
+"#;
+
+fn inputs() -> Vec<(&'static str, String)> {
+    let ascii = "The quick brown fox jumps over the lazy dog. ".repeat(2000);
+    let japanese = "本日はＡＢＣ株式会社の２０２４年度第３四半期決算について、カタカナのデータを含めて報告します。"
+        .repeat(500);
+    let replacements = "頚部，ＣＴ．CRLF".repeat(2000);
+    vec![
+        ("ascii", ascii),
+        ("full_width_japanese", japanese),
+        ("replacement_hits", replacements),
+    ]
+}
+
+fn bench_format_text(c: &mut Criterion) {
+    let config = AppConfig::from_toml(CONFIG).expect("Failed to parse benchmark config");
+    let mut group = c.benchmark_group("format_text");
+    for (name, input) in inputs() {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &input, |b, input| {
+            b.iter(|| format_text(input, &config).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_format_text_large_exclusions(c: &mut Criterion) {
+    // 全角英数字と記号をすべて除外リストに入れ、除外判定の負荷を測る
+    let exclusions: Vec<String> = ('！'..='～').map(|c| format!("\"{c}\"")).collect();
+    let config = AppConfig::from_toml(&CONFIG.replace(
+        r#"exclusions = ["　", "！", "？", "〜", "～"]"#,
+        &format!("exclusions = [{}]", exclusions.join(", ")),
+    ))
+    .expect("Failed to parse benchmark config");
+    let input = "ＡＢＣ株式会社の２０２４年度第３四半期決算（速報）！".repeat(1000);
+    let mut group = c.benchmark_group("format_text_large_exclusions");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("full_width_japanese", |b| {
+        b.iter(|| format_text(&input, &config).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_format_text_unchanged(c: &mut Criterion) {
+    // 置換ルールの対象も全角文字もない内容は、走査だけで整形を省略する
+    let config = AppConfig::from_toml(
+        r#"
+        [app]
+        clipboard_poll_interval = 300
+        config_reload_interval = 5000
+
+        [replacements]
+        "，" = ", "
+        "．" = ". "
+        "#,
+    )
+    .expect("Failed to parse benchmark config");
+    let input = "The quick brown fox jumps over the lazy dog. ".repeat(2000);
+    // 走査だけで省略できず整形した場合は、入力を借用したまま返せない
+    assert!(
+        matches!(format_text_cow(&input, &config).unwrap(), Cow::Borrowed(_)),
+        "Unchanged input did not take the fast path"
+    );
+    let mut group = c.benchmark_group("format_text_unchanged");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("ascii", |b| {
+        b.iter(|| format_text(&input, &config).unwrap())
+    });
+    group.bench_function("ascii_cow", |b| {
+        b.iter(|| format_text_cow(&input, &config).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_format_text,
+    bench_format_text_large_exclusions,
+    bench_format_text_unchanged
+);
+criterion_main!(benches);
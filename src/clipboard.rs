@@ -0,0 +1,562 @@
+//! クリップボードの読み書きと変更の検知
+
+use crate::config::{ChangeDetection, Selection};
+use anyhow::Result;
+#[cfg(not(feature = "arboard"))]
+use clipboard::{ClipboardContext, ClipboardProvider};
+use log::warn;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub(crate) enum ClipboardError {
+    #[error("Failed to create clipboard provider: {0}")]
+    CreateContext(String),
+    #[error("Failed to set clipboard contents: {0}")]
+    SetContents(String),
+    // 書き込みに続けて失敗した後の待ち時間の間で、書き込みを試さなかった
+    #[error("Skipped writing clipboard contents: {0}")]
+    WriteBackoff(String),
+    #[error("Failed to get clipboard contents: {0}")]
+    GetContents(String),
+    // 画像などテキスト以外の内容や、UTF-8として読めない内容。プロバイダー自体は正常に動作している
+    #[error("Clipboard does not contain text: {0}")]
+    NonText(String),
+    // 読み取った内容の整形に失敗した（整形中のpanicを含む）。プロバイダーの失敗ではない
+    #[error("Failed to format clipboard contents: {0}")]
+    Format(String),
+}
+
+// clipboardクレートのmacOSのバックエンドが、文字列として読める内容がない場合に返すメッセージ
+#[cfg(any(not(feature = "arboard"), test))]
+const MACOS_NO_STRING_ERROR: &str = "pasteboard#readObjectsForClasses:options: returned empty";
+
+// clipboardクレートの各バックエンドが、内容がテキストでない場合に返すエラーだけをNonTextとして扱う。
+// X11ではUTF-8として読めない内容のFromUtf8Error、WindowsではCF_UNICODETEXTがない場合の
+// エラーコード0のio::Error、macOSでは文字列がない場合のメッセージがこれにあたる。
+// それ以外はメッセージに"format"などを含んでいても、プロバイダーの失敗として扱う
+#[cfg(any(not(feature = "arboard"), test))]
+fn get_contents_error(e: Box<dyn std::error::Error>) -> ClipboardError {
+    let non_text = e.is::<std::string::FromUtf8Error>()
+        || e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.raw_os_error() == Some(0))
+        || e.to_string() == MACOS_NO_STRING_ERROR;
+    if non_text {
+        ClipboardError::NonText(e.to_string())
+    } else {
+        ClipboardError::GetContents(e.to_string())
+    }
+}
+
+// クリップボードの内容の識別情報。変更の検知に使う
+//
+// ハッシュだけでは衝突した場合に変更を見逃すため、長さと先頭・末尾のバイト列も比較する。
+// 直前の内容そのものを保持して比較すれば確実だが、最大でmax_content_bytesの文字列を
+// 確認のたびに複製することになるため、ここでは固定長の情報だけを保持する。
+// ハッシュ・長さ・先頭と末尾がすべて一致する別の内容は見逃すが、現実的には起こらない。
+// change_detectionで、ハッシュを先頭だけに限定する方法と、内容全体を保持する方法も選べる。
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ContentFingerprint {
+    hash: u64,
+    len: usize,
+    head: [u8; FINGERPRINT_EDGE_BYTES],
+    tail: [u8; FINGERPRINT_EDGE_BYTES],
+    // change_detection = "full"の場合のみ保持する
+    content: Option<String>,
+}
+
+const FINGERPRINT_EDGE_BYTES: usize = 16;
+
+// change_detection = "prefix"でハッシュ値を計算するバイト数
+const CHANGE_DETECTION_PREFIX_BYTES: usize = 4096;
+
+impl ContentFingerprint {
+    pub(crate) fn new(content: &str) -> Self {
+        Self::with_hasher(content, DefaultHasher::new())
+    }
+
+    pub(crate) fn with_detection(content: &str, detection: ChangeDetection) -> Self {
+        let bytes = content.as_bytes();
+        match detection {
+            ChangeDetection::Hash => Self::new(content),
+            ChangeDetection::Prefix => {
+                let prefix = &bytes[..bytes.len().min(CHANGE_DETECTION_PREFIX_BYTES)];
+                Self {
+                    hash: hash_with_length(prefix, DefaultHasher::new()),
+                    ..Self::with_edges(bytes)
+                }
+            }
+            // ハッシュ値は計算せず、長さ・先頭と末尾が一致した場合だけ内容全体を比較する
+            ChangeDetection::Full => Self {
+                hash: 0,
+                content: Some(content.to_string()),
+                ..Self::with_edges(bytes)
+            },
+        }
+    }
+
+    fn with_hasher<H: Hasher>(content: &str, hasher: H) -> Self {
+        let bytes = content.as_bytes();
+        Self {
+            hash: hash_with_length(bytes, hasher),
+            ..Self::with_edges(bytes)
+        }
+    }
+
+    fn with_edges(bytes: &[u8]) -> Self {
+        let edge = bytes.len().min(FINGERPRINT_EDGE_BYTES);
+        let mut head = [0; FINGERPRINT_EDGE_BYTES];
+        let mut tail = [0; FINGERPRINT_EDGE_BYTES];
+        head[..edge].copy_from_slice(&bytes[..edge]);
+        tail[..edge].copy_from_slice(&bytes[bytes.len() - edge..]);
+        Self {
+            hash: 0,
+            len: bytes.len(),
+            head,
+            tail,
+            content: None,
+        }
+    }
+}
+
+// 長さを先に書き込み、長さの異なる内容のハッシュ値が衝突しにくいようにする
+fn hash_with_length<H: Hasher>(bytes: &[u8], mut hasher: H) -> u64 {
+    hasher.write_usize(bytes.len());
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+pub(crate) fn calculate_hash<T: Hash>(t: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    s.finish()
+}
+
+pub(crate) trait Clipboard {
+    fn get_contents(&mut self) -> Result<String, ClipboardError>;
+    fn set_contents(&mut self, content: String) -> Result<(), ClipboardError>;
+}
+
+// クリップボードを読み書きするバックエンド。Cargoのarboard featureで切り替える
+#[cfg(not(feature = "arboard"))]
+pub(crate) const CLIPBOARD_BACKEND: &str = "clipboard";
+#[cfg(feature = "arboard")]
+pub(crate) const CLIPBOARD_BACKEND: &str = "arboard";
+
+// [app] selectionで選んだセレクションのクリップボード。X11以外では常にクリップボードを使う
+#[cfg(not(feature = "arboard"))]
+pub(crate) enum SystemClipboard {
+    Clipboard(ClipboardContext),
+    #[cfg(all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    ))]
+    Primary(clipboard::x11_clipboard::X11ClipboardContext<clipboard::x11_clipboard::Primary>),
+}
+
+#[cfg(not(feature = "arboard"))]
+impl SystemClipboard {
+    pub(crate) fn new(selection: Selection) -> Result<Self, ClipboardError> {
+        let create_error =
+            |e: Box<dyn std::error::Error>| ClipboardError::CreateContext(e.to_string());
+        match selection {
+            Selection::Clipboard => Ok(Self::Clipboard(
+                ClipboardContext::new().map_err(create_error)?,
+            )),
+            #[cfg(all(
+                unix,
+                not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+            ))]
+            Selection::Primary => Ok(Self::Primary(
+                ClipboardProvider::new().map_err(create_error)?,
+            )),
+            #[cfg(not(all(
+                unix,
+                not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+            )))]
+            Selection::Primary => {
+                warn!(
+                    "The PRIMARY selection is not available on this platform; using the clipboard"
+                );
+                Ok(Self::Clipboard(
+                    ClipboardContext::new().map_err(create_error)?,
+                ))
+            }
+        }
+    }
+}
+
+// 整形前の内容の退避先としてPRIMARYセレクションを開く。区別のないプラットフォームではNone
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub(crate) fn open_primary_stash() -> Result<Option<SystemClipboard>, ClipboardError> {
+    SystemClipboard::new(Selection::Primary).map(Some)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+pub(crate) fn open_primary_stash() -> Result<Option<SystemClipboard>, ClipboardError> {
+    Ok(None)
+}
+
+#[cfg(not(feature = "arboard"))]
+impl Clipboard for SystemClipboard {
+    fn get_contents(&mut self) -> Result<String, ClipboardError> {
+        let result = match self {
+            Self::Clipboard(ctx) => ClipboardProvider::get_contents(ctx),
+            #[cfg(all(
+                unix,
+                not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+            ))]
+            Self::Primary(ctx) => ClipboardProvider::get_contents(ctx),
+        };
+        result.map_err(get_contents_error)
+    }
+
+    fn set_contents(&mut self, content: String) -> Result<(), ClipboardError> {
+        let result = match self {
+            Self::Clipboard(ctx) => ClipboardProvider::set_contents(ctx, content),
+            #[cfg(all(
+                unix,
+                not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+            ))]
+            Self::Primary(ctx) => ClipboardProvider::set_contents(ctx, content),
+        };
+        result.map_err(|e| ClipboardError::SetContents(e.to_string()))
+    }
+}
+
+// arboardはX11に加えてWaylandのクリップボード（data-controlプロトコル）にも対応する
+#[cfg(feature = "arboard")]
+pub(crate) struct SystemClipboard {
+    ctx: arboard::Clipboard,
+    selection: Selection,
+}
+
+#[cfg(feature = "arboard")]
+impl SystemClipboard {
+    pub(crate) fn new(selection: Selection) -> Result<Self, ClipboardError> {
+        let ctx =
+            arboard::Clipboard::new().map_err(|e| ClipboardError::CreateContext(e.to_string()))?;
+        #[cfg(not(all(
+            unix,
+            not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+        )))]
+        let selection = if selection == Selection::Primary {
+            warn!("The PRIMARY selection is not available on this platform; using the clipboard");
+            Selection::Clipboard
+        } else {
+            selection
+        };
+        Ok(Self { ctx, selection })
+    }
+}
+
+#[cfg(all(
+    feature = "arboard",
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn linux_clipboard_kind(selection: Selection) -> arboard::LinuxClipboardKind {
+    match selection {
+        Selection::Clipboard => arboard::LinuxClipboardKind::Clipboard,
+        Selection::Primary => arboard::LinuxClipboardKind::Primary,
+    }
+}
+
+// 空のクリップボードやテキスト以外の内容は、プロバイダーの失敗ではなくNonTextとして扱う
+#[cfg(feature = "arboard")]
+fn arboard_get_error(e: arboard::Error) -> ClipboardError {
+    match e {
+        arboard::Error::ContentNotAvailable | arboard::Error::ConversionFailure => {
+            ClipboardError::NonText(e.to_string())
+        }
+        e => ClipboardError::GetContents(e.to_string()),
+    }
+}
+
+#[cfg(feature = "arboard")]
+impl Clipboard for SystemClipboard {
+    fn get_contents(&mut self) -> Result<String, ClipboardError> {
+        let get = self.ctx.get();
+        #[cfg(all(
+            unix,
+            not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+        ))]
+        let get = arboard::GetExtLinux::clipboard(get, linux_clipboard_kind(self.selection));
+        get.text().map_err(arboard_get_error)
+    }
+
+    fn set_contents(&mut self, content: String) -> Result<(), ClipboardError> {
+        let set = self.ctx.set();
+        #[cfg(all(
+            unix,
+            not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+        ))]
+        let set = arboard::SetExtLinux::clipboard(set, linux_clipboard_kind(self.selection));
+        set.text(content)
+            .map_err(|e| ClipboardError::SetContents(e.to_string()))
+    }
+}
+
+// クリップボードの変更通知。変更された可能性があればtrueを返す
+pub(crate) trait ClipboardChangeListener {
+    fn wait_for_change(&self, timeout: Duration) -> bool;
+}
+
+pub(crate) struct ChannelChangeListener {
+    pub(crate) rx: Receiver<()>,
+}
+
+impl ClipboardChangeListener for ChannelChangeListener {
+    fn wait_for_change(&self, timeout: Duration) -> bool {
+        match self.rx.recv_timeout(timeout) {
+            Ok(()) => {
+                // 連続したイベントは1回の変更としてまとめる
+                while self.rx.try_recv().is_ok() {}
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => {
+                // 通知スレッドが終了した場合はポーリングと同じ挙動にする
+                thread::sleep(timeout);
+                true
+            }
+        }
+    }
+}
+
+// 失敗した場合は待ち時間を倍にしながら最大attempts回まで試行する
+pub(crate) fn retry_with_backoff<T, E: std::fmt::Display>(
+    attempts: u32,
+    initial_delay: Duration,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut delay = initial_delay;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "{e}; retrying in {}ms ({attempt}/{attempts})",
+                    delay.as_millis()
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockClipboard;
+
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_clipboard_trait_with_mock() {
+        // 実際のバックエンド（clipboardまたはarboard）に依存せず、Clipboardトレイトだけで読み書きする
+        fn round_trip(clipboard: &mut dyn Clipboard, text: &str) -> Result<String, ClipboardError> {
+            clipboard.set_contents(text.to_string())?;
+            clipboard.get_contents()
+        }
+        let mut clipboard = MockClipboard::default();
+        assert_eq!(round_trip(&mut clipboard, "ＡＢ").unwrap(), "ＡＢ");
+
+        let mut clipboard = MockClipboard {
+            set_failures: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            round_trip(&mut clipboard, "ＡＢ"),
+            Err(ClipboardError::SetContents(_))
+        ));
+        let mut clipboard = MockClipboard {
+            non_text: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            round_trip(&mut clipboard, "ＡＢ"),
+            Err(ClipboardError::NonText(_))
+        ));
+    }
+
+    #[cfg(feature = "arboard")]
+    #[test]
+    fn test_arboard_get_error() {
+        assert!(matches!(
+            arboard_get_error(arboard::Error::ContentNotAvailable),
+            ClipboardError::NonText(_)
+        ));
+        assert!(matches!(
+            arboard_get_error(arboard::Error::ConversionFailure),
+            ClipboardError::NonText(_)
+        ));
+        assert!(matches!(
+            arboard_get_error(arboard::Error::ClipboardOccupied),
+            ClipboardError::GetContents(_)
+        ));
+    }
+
+    #[test]
+    fn test_get_contents_error() {
+        let non_text: [Box<dyn std::error::Error>; 3] = [
+            Box::new(String::from_utf8(vec![0xFF]).unwrap_err()),
+            Box::new(std::io::Error::from_raw_os_error(0)),
+            MACOS_NO_STRING_ERROR.into(),
+        ];
+        for e in non_text {
+            let message = e.to_string();
+            assert!(
+                matches!(get_contents_error(e), ClipboardError::NonText(_)),
+                "{message}"
+            );
+        }
+        // "format"や"utf-8"を含むメッセージでも、プロバイダーの失敗として扱う
+        let failures: [Box<dyn std::error::Error>; 4] = [
+            "Clipboard format conversion failed".into(),
+            "invalid utf-8 in XCB reply".into(),
+            "Selection timed out".into(),
+            Box::new(std::io::Error::from_raw_os_error(1418)),
+        ];
+        for e in failures {
+            let message = e.to_string();
+            assert!(
+                matches!(get_contents_error(e), ClipboardError::GetContents(_)),
+                "{message}"
+            );
+        }
+    }
+
+    // すべての内容に同じハッシュ値を返すハッシュ関数
+    #[derive(Default)]
+    struct CollidingHasher;
+
+    impl Hasher for CollidingHasher {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    #[test]
+    fn test_content_fingerprint_with_hash_collision() {
+        let fingerprint = |content: &str| ContentFingerprint::with_hasher(content, CollidingHasher);
+        assert_eq!(fingerprint("ＡＢＣ"), fingerprint("ＡＢＣ"));
+        // ハッシュが一致しても、長さや先頭・末尾が異なれば別の内容とみなす
+        assert_ne!(fingerprint("ＡＢＣ"), fingerprint("ＡＢＣＤ"));
+        assert_ne!(fingerprint("ＡＢＣ"), fingerprint("ＤＥＦ"));
+        let long = "あ".repeat(100);
+        assert_ne!(
+            fingerprint(&format!("{long}Ａ")),
+            fingerprint(&format!("{long}Ｂ"))
+        );
+        assert_ne!(
+            fingerprint(&format!("Ａ{long}")),
+            fingerprint(&format!("Ｂ{long}"))
+        );
+    }
+
+    #[test]
+    fn test_change_detection_prefix_ignores_middle_of_large_content() {
+        let middle = |c: char| {
+            format!(
+                "{}{c}{}",
+                "a".repeat(CHANGE_DETECTION_PREFIX_BYTES),
+                "b".repeat(100)
+            )
+        };
+        let fingerprint =
+            |content: &str, detection| ContentFingerprint::with_detection(content, detection);
+        // prefixは先頭以降の、長さと末尾が同じ変更を見逃す
+        assert_eq!(
+            fingerprint(&middle('x'), ChangeDetection::Prefix),
+            fingerprint(&middle('y'), ChangeDetection::Prefix)
+        );
+        assert_ne!(
+            fingerprint(&middle('x'), ChangeDetection::Hash),
+            fingerprint(&middle('y'), ChangeDetection::Hash)
+        );
+        assert_ne!(
+            fingerprint(&middle('x'), ChangeDetection::Full),
+            fingerprint(&middle('y'), ChangeDetection::Full)
+        );
+        // 長さや末尾が変われば検知する
+        assert_ne!(
+            fingerprint(&middle('x'), ChangeDetection::Prefix),
+            fingerprint(&format!("{}c", middle('x')), ChangeDetection::Prefix)
+        );
+    }
+
+    // Tests for startup retry
+    #[test]
+    fn test_retry_with_backoff_succeeds_on_nth_attempt() {
+        let mut calls = 0;
+        let result = retry_with_backoff(5, Duration::from_millis(1), || {
+            calls += 1;
+            if calls < 3 {
+                Err(format!("attempt {calls} failed"))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_retry_with_backoff_gives_up() {
+        let mut calls = 0;
+        let result: Result<(), String> = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            Err(format!("attempt {calls} failed"))
+        });
+        // 最後のエラーを返す
+        assert_eq!(result, Err("attempt 3 failed".to_string()));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_retry_with_backoff_zero_attempts_runs_once() {
+        let mut calls = 0;
+        let result: Result<(), &str> = retry_with_backoff(0, Duration::ZERO, || {
+            calls += 1;
+            Err("failed")
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_channel_change_listener_coalesces_events() {
+        let (change_tx, change_rx) = channel();
+        let listener = ChannelChangeListener { rx: change_rx };
+
+        change_tx.send(()).unwrap();
+        change_tx.send(()).unwrap();
+        assert!(listener.wait_for_change(Duration::from_millis(1)));
+        // 連続したイベントはまとめて処理済み
+        assert!(!listener.wait_for_change(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_channel_change_listener_timeout() {
+        let (_change_tx, change_rx) = channel();
+        let listener = ChannelChangeListener { rx: change_rx };
+        assert!(!listener.wait_for_change(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,2781 @@
+//! 設定ファイルの読み込みと再読み込み
+
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+use log::{debug, error, info, warn};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+pub(crate) const DEFAULT_CONFIG: &str = include_str!("default_config.toml");
+// --initで作成する、すべての設定項目を説明付きで記載したテンプレート
+const CONFIG_TEMPLATE: &str = include_str!("config_template.toml");
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+// 設定ファイルの代わりにTOMLの内容を直接指定する環境変数
+const CONFIG_ENV_VAR: &str = "CLIPBOARD_FORMATTER_CONFIG";
+
+pub(crate) const ENV_VAR_PATTERN: &str =
+    r"\$(?:\$|\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))";
+
+static ENV_VAR_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(ENV_VAR_PATTERN).expect("Failed to create regex pattern"));
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    #[default]
+    ToHalf,
+    ToFull,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ClipboardMode {
+    Event,
+    #[default]
+    Poll,
+}
+
+// 整形するタイミング。pasteは貼り付けられたときだけ整形する（X11のみ）
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FormatOn {
+    #[default]
+    Copy,
+    Paste,
+}
+
+// X11のセレクション。PRIMARYは選択しただけの文字列（中クリックで貼り付け）
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Selection {
+    #[default]
+    Clipboard,
+    Primary,
+}
+
+impl Selection {
+    pub(crate) fn atom_name(self) -> &'static str {
+        match self {
+            Selection::Clipboard => "CLIPBOARD",
+            Selection::Primary => "PRIMARY",
+        }
+    }
+}
+
+// 整形前の内容の退避先。"primary"以外はファイルのパスとして扱う
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(from = "String")]
+pub(crate) enum StashTarget {
+    Primary,
+    File(PathBuf),
+}
+
+impl From<String> for StashTarget {
+    fn from(target: String) -> Self {
+        if target == "primary" {
+            StashTarget::Primary
+        } else {
+            StashTarget::File(PathBuf::from(target))
+        }
+    }
+}
+
+// クリップボードの変更の検知方法。処理の重さと変更を見逃す可能性のトレードオフ
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChangeDetection {
+    // 内容全体のハッシュ値と、長さ・先頭と末尾のバイト列を比較する
+    #[default]
+    Hash,
+    // 先頭のCHANGE_DETECTION_PREFIX_BYTESバイトだけのハッシュ値と、長さ・末尾のバイト列を比較する
+    Prefix,
+    // 直前の内容を保持して、内容全体を比較する
+    Full,
+}
+
+// 波ダッシュ(U+301C)と全角チルダ(U+FF5E)の統一先
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WaveDash {
+    #[default]
+    Keep,
+    FullwidthTilde,
+    AsciiTilde,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LineEndings {
+    Lf,
+    Crlf,
+    #[default]
+    Keep,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct AppSettings {
+    pub(crate) clipboard_poll_interval: u64,
+    pub(crate) config_reload_interval: u64,
+    #[serde(default)]
+    pub(crate) remove_duplicate_previous_lines: bool,
+    #[serde(default)]
+    pub(crate) convert_katakana: bool,
+    #[serde(default)]
+    pub(crate) direction: Direction,
+    #[serde(default)]
+    pub(crate) clipboard_mode: ClipboardMode,
+    #[serde(default)]
+    pub(crate) selection: Selection,
+    #[serde(default)]
+    pub(crate) nfkc: bool,
+    #[serde(default)]
+    pub(crate) convert_ideographic_space: bool,
+    #[serde(default)]
+    pub(crate) skip_urls: bool,
+    #[serde(default)]
+    pub(crate) skip_if_code: bool,
+    #[serde(default)]
+    pub(crate) line_endings: LineEndings,
+    #[serde(default)]
+    pub(crate) preserve: Vec<String>,
+    #[serde(default)]
+    pub(crate) literal_delimiters: Vec<String>,
+    #[serde(default)]
+    pub(crate) trim: bool,
+    #[serde(default)]
+    pub(crate) trim_trailing_lines: bool,
+    #[serde(default = "default_max_content_bytes")]
+    pub(crate) max_content_bytes: usize,
+    #[serde(default)]
+    pub(crate) active_profile: Option<String>,
+    #[serde(default = "default_config_reload_debounce")]
+    pub(crate) config_reload_debounce: u64,
+    #[serde(default = "default_startup_retry_attempts")]
+    pub(crate) startup_retry_attempts: u32,
+    #[serde(default = "default_startup_retry_delay")]
+    pub(crate) startup_retry_delay: u64,
+    #[serde(default)]
+    pub(crate) trigger_suffix: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub(crate) watch_config: bool,
+    #[serde(default)]
+    pub(crate) convert_punctuation: bool,
+    #[serde(default = "default_enabled")]
+    pub(crate) convert_digits: bool,
+    #[serde(default = "default_enabled")]
+    pub(crate) convert_latin: bool,
+    #[serde(default = "default_enabled")]
+    pub(crate) convert_symbols: bool,
+    #[serde(default)]
+    pub(crate) notify_on_format: bool,
+    #[serde(default)]
+    pub(crate) notify_digest_minutes: u64,
+    #[serde(default)]
+    pub(crate) max_shrink_ratio: Option<f64>,
+    #[serde(default)]
+    pub(crate) verify_idempotent: bool,
+    #[serde(default)]
+    pub(crate) stash_original_to: Option<StashTarget>,
+    #[serde(default)]
+    pub(crate) min_format_interval_ms: u64,
+    #[serde(default)]
+    pub(crate) format_on: FormatOn,
+    #[serde(default)]
+    pub(crate) strip_invisible: bool,
+    #[serde(
+        default = "default_invisible_chars",
+        deserialize_with = "deserialize_invisible_chars"
+    )]
+    pub(crate) invisible_chars: Vec<char>,
+    #[serde(default)]
+    pub(crate) heartbeat_file: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) change_detection: ChangeDetection,
+    #[serde(default)]
+    pub(crate) wave_dash: WaveDash,
+    #[serde(default)]
+    pub(crate) tee_stdout: bool,
+}
+
+fn default_startup_retry_attempts() -> u32 {
+    5
+}
+
+fn default_startup_retry_delay() -> u64 {
+    500
+}
+
+fn default_config_reload_debounce() -> u64 {
+    200
+}
+
+fn default_max_content_bytes() -> usize {
+    1024 * 1024
+}
+
+// strip_invisibleで削除する文字。タブ・改行(LF, CR)は含めない。
+// 絵文字の結合に使うゼロ幅接合子(U+200D)と、一部の言語で必要なゼロ幅非接合子(U+200C)も含めない
+fn default_invisible_chars() -> Vec<char> {
+    let mut chars: Vec<char> = ('\u{0}'..='\u{8}').collect();
+    chars.extend(['\u{B}', '\u{C}']);
+    chars.extend('\u{E}'..='\u{1F}');
+    chars.extend('\u{7F}'..='\u{9F}');
+    chars.extend(['\u{AD}', '\u{200B}', '\u{2060}', '\u{FEFF}']);
+    chars
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        Self {
+            clipboard_poll_interval: 300,
+            config_reload_interval: 5000,
+            remove_duplicate_previous_lines: false,
+            convert_katakana: false,
+            direction: Direction::default(),
+            clipboard_mode: ClipboardMode::default(),
+            selection: Selection::default(),
+            nfkc: false,
+            convert_ideographic_space: false,
+            skip_urls: false,
+            skip_if_code: false,
+            line_endings: LineEndings::default(),
+            preserve: Vec::new(),
+            literal_delimiters: Vec::new(),
+            trim: false,
+            trim_trailing_lines: false,
+            max_content_bytes: default_max_content_bytes(),
+            active_profile: None,
+            config_reload_debounce: default_config_reload_debounce(),
+            startup_retry_attempts: default_startup_retry_attempts(),
+            startup_retry_delay: default_startup_retry_delay(),
+            trigger_suffix: None,
+            watch_config: true,
+            convert_punctuation: false,
+            convert_digits: true,
+            convert_latin: true,
+            convert_symbols: true,
+            notify_on_format: false,
+            notify_digest_minutes: 0,
+            max_shrink_ratio: None,
+            verify_idempotent: false,
+            stash_original_to: None,
+            min_format_interval_ms: 0,
+            format_on: FormatOn::default(),
+            strip_invisible: false,
+            invisible_chars: default_invisible_chars(),
+            heartbeat_file: None,
+            change_detection: ChangeDetection::default(),
+            wave_dash: WaveDash::default(),
+            tee_stdout: false,
+        }
+    }
+}
+
+/// 置換ルール1件分の置換後の文字列と有効・無効の設定
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(from = "ReplacementEntry")]
+pub struct Replacement {
+    pub to: String,
+    pub enabled: bool,
+    /// 指定した場合は行頭または行末に一致した場合のみ置換する
+    pub anchor: Option<Anchor>,
+    /// 設定ファイル上の説明。整形には影響せず、`--check-config`とデバッグログに表示される
+    pub note: Option<String>,
+    /// 指定した場合は内容の主な文字種が一致した場合のみ置換する
+    pub when_script: Option<Script>,
+    /// trueの場合は英字（ASCII）の大文字・小文字を区別せずに一致させる
+    pub case_insensitive: bool,
+}
+
+/// 内容の主な文字種
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Script {
+    Latin,
+    Japanese,
+}
+
+/// 置換ルールを適用する位置
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    LineStart,
+    LineEnd,
+}
+
+// "original" = "replacement" または "original" = { to = "replacement", enabled = false, anchor = "line_start", note = "説明", when_script = "japanese", expand_env = true, interpret_escapes = true, case_insensitive = true }
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ReplacementEntry {
+    To(String),
+    Table {
+        to: String,
+        #[serde(default = "default_enabled")]
+        enabled: bool,
+        #[serde(default)]
+        anchor: Option<Anchor>,
+        #[serde(default)]
+        note: Option<String>,
+        #[serde(default)]
+        when_script: Option<Script>,
+        #[serde(default)]
+        expand_env: bool,
+        #[serde(default)]
+        interpret_escapes: bool,
+        #[serde(default)]
+        case_insensitive: bool,
+    },
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl From<ReplacementEntry> for Replacement {
+    fn from(entry: ReplacementEntry) -> Self {
+        match entry {
+            ReplacementEntry::To(to) => Self {
+                to,
+                enabled: true,
+                anchor: None,
+                note: None,
+                when_script: None,
+                case_insensitive: false,
+            },
+            ReplacementEntry::Table {
+                to,
+                enabled,
+                anchor,
+                note,
+                when_script,
+                expand_env,
+                interpret_escapes,
+                case_insensitive,
+            } => Self {
+                to: resolve_replacement_to(to, interpret_escapes, expand_env),
+                enabled,
+                anchor,
+                note,
+                when_script,
+                case_insensitive,
+            },
+        }
+    }
+}
+
+// [[replacement]] from = "original", to = "replacement"（同じfromを複数回指定できる）
+#[derive(serde::Deserialize)]
+struct ReplacementRule {
+    from: String,
+    to: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    anchor: Option<Anchor>,
+    #[serde(default)]
+    note: Option<String>,
+    #[serde(default)]
+    when_script: Option<Script>,
+    #[serde(default)]
+    expand_env: bool,
+    #[serde(default)]
+    interpret_escapes: bool,
+    #[serde(default)]
+    case_insensitive: bool,
+}
+
+fn deserialize_replacement_list<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<(String, Replacement)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let rules = <Vec<ReplacementRule> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(rules
+        .into_iter()
+        .map(|rule| {
+            let replacement = Replacement {
+                to: resolve_replacement_to(rule.to, rule.interpret_escapes, rule.expand_env),
+                enabled: rule.enabled,
+                anchor: rule.anchor,
+                note: rule.note,
+                when_script: rule.when_script,
+                case_insensitive: rule.case_insensitive,
+            };
+            (rule.from, replacement)
+        })
+        .collect())
+}
+
+// 置換後の文字列のエスケープシーケンスを解釈してから環境変数を展開する。
+// 環境変数の値に含まれるバックスラッシュはエスケープシーケンスとして扱わない
+fn resolve_replacement_to(to: String, interpret_escapes: bool, expand_env: bool) -> String {
+    let to = if interpret_escapes {
+        interpret_escape_sequences(&to)
+    } else {
+        to
+    };
+    if expand_env {
+        expand_env_vars(&to)
+    } else {
+        to
+    }
+}
+
+// \nを改行、\tをタブ、\\を\に変換する。それ以外のバックスラッシュはそのまま残す
+fn interpret_escape_sequences(value: &str) -> String {
+    let mut interpreted = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            interpreted.push(c);
+            continue;
+        }
+        match chars.clone().next() {
+            Some('n') => interpreted.push('\n'),
+            Some('t') => interpreted.push('\t'),
+            Some('\\') => interpreted.push('\\'),
+            _ => {
+                interpreted.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    interpreted
+}
+
+// $NAMEと${NAME}を環境変数の値に置き換える。$$は$になる。
+// 設定されていない変数は設定の誤りに気付けるよう、空にせずそのまま残す
+fn expand_env_vars(value: &str) -> String {
+    ENV_VAR_REGEX
+        .replace_all(value, |caps: &regex::Captures| {
+            let Some(name) = caps.get(1).or_else(|| caps.get(2)) else {
+                return "$".to_string();
+            };
+            match env::var(name.as_str()) {
+                Ok(expanded) => expanded,
+                Err(_) => {
+                    warn!(
+                        "Environment variable {} is not set; leaving {} as is",
+                        name.as_str(),
+                        &caps[0]
+                    );
+                    caps[0].to_string()
+                }
+            }
+        })
+        .into_owned()
+}
+
+// 置換ルール（[replacements]、[[replacement]]）と除外リストの\uXXXX、\u{XXXXX}を文字に変換する。
+// プロファイル内の置換ルールと除外リストも対象にする
+fn decode_config_escapes(table: &mut toml::Table) -> Result<()> {
+    decode_rule_escapes(table)?;
+    if let Some(toml::Value::Table(profiles)) = table.get_mut("profiles") {
+        for (name, profile) in profiles.iter_mut() {
+            if let toml::Value::Table(profile) = profile {
+                decode_rule_escapes(profile).with_context(|| format!("In profile {name:?}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_rule_escapes(table: &mut toml::Table) -> Result<()> {
+    let decode_string = |value: &mut toml::Value, location: &str| -> Result<()> {
+        if let toml::Value::String(s) = value {
+            *s = decode_unicode_escapes(s).with_context(|| format!("In {location}"))?;
+        }
+        Ok(())
+    };
+    if let Some(toml::Value::Table(replacements)) = table.get_mut("replacements") {
+        let mut decoded = toml::Table::new();
+        for (key, mut value) in std::mem::take(replacements) {
+            let location = format!("replacement {key:?}");
+            let decoded_key =
+                decode_unicode_escapes(&key).with_context(|| format!("In {location}"))?;
+            match &mut value {
+                toml::Value::Table(entry) => {
+                    if let Some(to) = entry.get_mut("to") {
+                        decode_string(to, &location)?;
+                    }
+                }
+                value => decode_string(value, &location)?,
+            }
+            if decoded.insert(decoded_key.clone(), value).is_some() {
+                anyhow::bail!("Duplicate replacement {decoded_key:?} after decoding {key:?}");
+            }
+        }
+        *replacements = decoded;
+    }
+    if let Some(toml::Value::Array(rules)) = table.get_mut("replacement") {
+        for rule in rules.iter_mut().filter_map(|rule| rule.as_table_mut()) {
+            for key in ["from", "to"] {
+                if let Some(value) = rule.get_mut(key) {
+                    decode_string(value, "[[replacement]]")?;
+                }
+            }
+        }
+    }
+    if let Some(toml::Value::Array(exclusions)) = table.get_mut("exclusions") {
+        for exclusion in exclusions.iter_mut() {
+            decode_string(exclusion, "exclusions")?;
+        }
+    }
+    if let Some(toml::Value::Array(invisible_chars)) = table
+        .get_mut("app")
+        .and_then(|app| app.get_mut("invisible_chars"))
+    {
+        for invisible_char in invisible_chars.iter_mut() {
+            decode_string(invisible_char, "invisible_chars")?;
+        }
+    }
+    Ok(())
+}
+
+// \uXXXX（4桁）と\u{X...}（1～6桁）を文字に変換する。\\uは\uという文字列として残す。
+// 16進数も{も続かない\u（LaTeXの\usepackageなど）は警告を出してそのまま残し、
+// 桁数が足りない、閉じていない、不正なコードポイント（サロゲートなど）のエスケープはエラーにする
+fn decode_unicode_escapes(value: &str) -> Result<String> {
+    if !value.contains("\\u") {
+        return Ok(value.to_string());
+    }
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(position) = rest.find("\\u") {
+        if rest[..position].ends_with('\\') {
+            decoded.push_str(&rest[..position - 1]);
+            decoded.push_str("\\u");
+            rest = &rest[position + 2..];
+            continue;
+        }
+        decoded.push_str(&rest[..position]);
+        let after = &rest[position + 2..];
+        let escape: String = rest[position..].chars().take(8).collect();
+        if !after.starts_with(|c: char| c == '{' || c.is_ascii_hexdigit()) {
+            warn!("Leaving {escape:?} in {value:?} as is: not a unicode escape (write \\\\u for a literal \\u)");
+            decoded.push_str("\\u");
+            rest = after;
+            continue;
+        }
+        let (hex, consumed) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => (after.get(..4).unwrap_or(""), 4),
+        };
+        let c = Some(hex)
+            .filter(|hex| {
+                !hex.is_empty() && hex.len() <= 6 && hex.chars().all(|c| c.is_ascii_hexdigit())
+            })
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32)
+            .with_context(|| {
+                format!(
+                    "Invalid unicode escape {escape:?} in {value:?}: expected \\uXXXX or \\u{{XXXXX}} with a valid code point (write \\\\u for a literal \\u)"
+                )
+            })?;
+        decoded.push(c);
+        rest = &after[consumed..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+impl From<&str> for Replacement {
+    fn from(to: &str) -> Self {
+        Self {
+            to: to.to_string(),
+            enabled: true,
+            anchor: None,
+            note: None,
+            when_script: None,
+            case_insensitive: false,
+        }
+    }
+}
+
+// ログ用に置換ルールを"foo→bar (note: 説明)"の形式で表す
+fn describe_rule(original: &str, replacement: &Replacement) -> String {
+    match &replacement.note {
+        Some(note) => format!("{original}→{} (note: {note})", replacement.to),
+        None => format!("{original}→{}", replacement.to),
+    }
+}
+
+/// 置換前の文字列から置換ルールへの対応。記述された順に上から適用される
+pub type Replacements = IndexMap<String, Replacement>;
+
+#[derive(Debug, Default)]
+pub(crate) struct RegexReplacements(pub(crate) Vec<(Regex, String)>);
+
+impl<'de> serde::Deserialize<'de> for RegexReplacements {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let patterns = IndexMap::<String, String>::deserialize(deserializer)?;
+        let compiled = patterns
+            .into_iter()
+            .filter_map(|(pattern, replacement)| match Regex::new(&pattern) {
+                Ok(re) => Some((re, replacement)),
+                Err(e) => {
+                    warn!("Skipping invalid regex replacement {pattern:?}: {e}");
+                    None
+                }
+            })
+            .collect();
+        Ok(Self(compiled))
+    }
+}
+
+/// 全角・半角変換の対象から除外する文字
+pub type Exclusions = Vec<char>;
+
+fn deserialize_exclusions<'de, D>(deserializer: D) -> std::result::Result<Exclusions, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum ExclusionsShape {
+        List(Vec<String>),
+        // 旧形式: [exclusions] テーブル内の exclusions キー
+        Legacy(HashMap<String, Vec<String>>),
+    }
+
+    let entries = match <ExclusionsShape as serde::Deserialize>::deserialize(deserializer)? {
+        ExclusionsShape::List(entries) => entries,
+        ExclusionsShape::Legacy(mut table) => {
+            warn!(
+                "[exclusions] table is deprecated; use a top-level `exclusions = [...]` array instead"
+            );
+            table.remove("exclusions").unwrap_or_default()
+        }
+    };
+    Ok(entries
+        .iter()
+        .flat_map(|entry| expand_char_range(entry, "exclusion"))
+        .collect())
+}
+
+fn deserialize_invisible_chars<'de, D>(deserializer: D) -> std::result::Result<Vec<char>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let entries = <Vec<String> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(entries
+        .iter()
+        .flat_map(|entry| expand_char_range(entry, "invisible character"))
+        .collect())
+}
+
+// 除外リストなどの1要素を文字に展開する。"０-９"のような範囲指定も受け付ける
+fn expand_char_range(entry: &str, kind: &str) -> Vec<char> {
+    let chars: Vec<char> = entry.chars().collect();
+    match chars.as_slice() {
+        [c] => vec![*c],
+        [start, '-', end] if start <= end => (*start..=*end).collect(),
+        _ => {
+            warn!(
+                "Skipping invalid {kind} {entry:?}: expected a character or a range like \"０-９\""
+            );
+            Vec::new()
+        }
+    }
+}
+
+// exclusion_categoriesに指定できるUnicodeの一般カテゴリ（大分類と小分類）
+const GENERAL_CATEGORIES: &[&str] = &[
+    "L", "Lu", "Ll", "Lt", "Lm", "Lo", "M", "Mn", "Mc", "Me", "N", "Nd", "Nl", "No", "P", "Pc",
+    "Pd", "Ps", "Pe", "Pi", "Pf", "Po", "S", "Sm", "Sc", "Sk", "So", "Z", "Zs", "Zl", "Zp", "C",
+    "Cc", "Cf", "Cs", "Co", "Cn",
+];
+
+// 一般カテゴリによる除外。指定されたカテゴリを\p{..}の文字クラスにまとめた正規表現で判定する
+#[derive(Debug, Default)]
+pub(crate) struct ExclusionCategories(pub(crate) Option<Regex>);
+
+impl ExclusionCategories {
+    pub(crate) fn from_codes<S: AsRef<str>>(codes: &[S]) -> Self {
+        let classes: String = codes
+            .iter()
+            .map(AsRef::as_ref)
+            .filter(|code| {
+                let supported = GENERAL_CATEGORIES.contains(code);
+                if !supported {
+                    warn!("Skipping unknown exclusion category {code:?}: expected a Unicode general category like \"P\" or \"Nd\"");
+                }
+                supported
+            })
+            .map(|code| format!("\\p{{{code}}}"))
+            .collect();
+        if classes.is_empty() {
+            return Self(None);
+        }
+        Self(Some(
+            Regex::new(&format!("^[{classes}]$")).expect("general category classes are valid"),
+        ))
+    }
+
+    // textに含まれる文字のうち、指定されたカテゴリの文字を除外リストに加える
+    pub(crate) fn extend_exclusions(&self, text: &str, exclusion_list: &mut HashSet<char>) {
+        let Some(re) = &self.0 else {
+            return;
+        };
+        let mut buf = [0; 4];
+        for c in text.chars() {
+            if !exclusion_list.contains(&c) && re.is_match(c.encode_utf8(&mut buf)) {
+                exclusion_list.insert(c);
+            }
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ExclusionCategories {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let codes = Vec::<String>::deserialize(deserializer)?;
+        Ok(Self::from_codes(&codes))
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct Profile {
+    #[serde(default)]
+    replacements: Replacements,
+    #[serde(
+        default,
+        rename = "replacement",
+        deserialize_with = "deserialize_replacement_list"
+    )]
+    replacement_list: Vec<(String, Replacement)>,
+    #[serde(default, deserialize_with = "deserialize_exclusions")]
+    exclusions: Exclusions,
+}
+
+/// 設定ファイル（config.toml）の内容
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct AppConfig {
+    pub(crate) app: AppSettings,
+    #[serde(default)]
+    pub replacements: Replacements,
+    // [[replacement]]形式の置換ルール。[replacements]の後に記述順で適用する
+    #[serde(
+        default,
+        rename = "replacement",
+        deserialize_with = "deserialize_replacement_list"
+    )]
+    pub(crate) replacement_list: Vec<(String, Replacement)>,
+    #[serde(default)]
+    pub(crate) regex_replacements: RegexReplacements,
+    #[serde(default, deserialize_with = "deserialize_exclusions")]
+    pub exclusions: Exclusions,
+    #[serde(default)]
+    pub(crate) exclusion_categories: ExclusionCategories,
+    #[serde(default)]
+    pub(crate) profiles: IndexMap<String, Profile>,
+    #[serde(default)]
+    pub(crate) hotkeys: HotkeySettings,
+    pub(crate) history: Option<HistorySettings>,
+    #[serde(default)]
+    pub(crate) diff: DiffSettings,
+    #[serde(default)]
+    pub(crate) focus: FocusSettings,
+    #[serde(default)]
+    pub(crate) source: SourceSettings,
+    #[serde(default)]
+    pub(crate) punctuation: PunctuationMap,
+    #[serde(default)]
+    pub(crate) range: RangeSettings,
+    #[serde(default)]
+    pub(crate) logging: LoggingSettings,
+    #[serde(default)]
+    pub(crate) metrics: MetricsSettings,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct LoggingSettings {
+    // 指定した場合は標準エラー出力に加えてこのファイルにもログを追記する
+    pub(crate) file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct MetricsSettings {
+    // 指定した場合は127.0.0.1のこのポートで集計値を公開する（metrics featureが必要）
+    pub(crate) port: Option<u16>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct RangeSettings {
+    // 整形する行の範囲。指定しない場合はすべての行を整形する
+    #[serde(default)]
+    pub(crate) lines: Option<LineRange>,
+}
+
+// "2"、"2-4"、"2-"（2行目以降）の形式で指定する1始まりの行範囲
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct LineRange {
+    pub(crate) start: usize,
+    pub(crate) end: Option<usize>,
+}
+
+impl TryFrom<String> for LineRange {
+    type Error = String;
+
+    fn try_from(spec: String) -> std::result::Result<Self, Self::Error> {
+        let parse = |s: &str| match s.trim().parse::<usize>() {
+            Ok(line) if line > 0 => Ok(line),
+            _ => Err(format!(
+                "Invalid line range {spec:?}: expected \"N\" or \"N-M\""
+            )),
+        };
+        let range = match spec.split_once('-') {
+            None => {
+                let line = parse(&spec)?;
+                Self {
+                    start: line,
+                    end: Some(line),
+                }
+            }
+            Some((start, end)) if end.trim().is_empty() => Self {
+                start: parse(start)?,
+                end: None,
+            },
+            Some((start, end)) => Self {
+                start: parse(start)?,
+                end: Some(parse(end)?),
+            },
+        };
+        if range.end.is_some_and(|end| end < range.start) {
+            return Err(format!("Invalid line range {spec:?}: end is before start"));
+        }
+        Ok(range)
+    }
+}
+
+// convert_punctuationで変換する約物と変換後の文字列。[punctuation]を記述した場合はその内容だけを使う
+#[derive(Debug, serde::Deserialize)]
+#[serde(transparent)]
+pub(crate) struct PunctuationMap(pub(crate) IndexMap<char, String>);
+
+impl Default for PunctuationMap {
+    fn default() -> Self {
+        Self(
+            [
+                ('、', ","),
+                ('。', "."),
+                ('「', "\""),
+                ('」', "\""),
+                ('『', "\""),
+                ('』', "\""),
+                ('【', "["),
+                ('】', "]"),
+            ]
+            .into_iter()
+            .map(|(from, to)| (from, to.to_string()))
+            .collect(),
+        )
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct DiffSettings {
+    pub(crate) color: bool,
+    pub(crate) added_color: AnsiColor,
+    pub(crate) removed_color: AnsiColor,
+    pub(crate) granularity: DiffGranularity,
+}
+
+impl Default for DiffSettings {
+    fn default() -> Self {
+        Self {
+            color: true,
+            added_color: AnsiColor("32".to_string()),
+            removed_color: AnsiColor("31".to_string()),
+            granularity: DiffGranularity::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DiffGranularity {
+    #[default]
+    Char,
+    Word,
+    Line,
+}
+
+impl DiffGranularity {
+    // Changeset::newに渡す区切り文字
+    pub(crate) fn separator(self) -> &'static str {
+        match self {
+            Self::Char => "",
+            Self::Word => " ",
+            Self::Line => "\n",
+        }
+    }
+}
+
+// ANSIエスケープシーケンスのSGRパラメータ（例: "32", "1;33", "38;5;208"）
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AnsiColor(pub(crate) String);
+
+impl<'de> serde::Deserialize<'de> for AnsiColor {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum ColorShape {
+            Code(u8),
+            Name(String),
+        }
+
+        let code = match <ColorShape as serde::Deserialize>::deserialize(deserializer)? {
+            ColorShape::Code(code) => code.to_string(),
+            ColorShape::Name(name) => match name.to_lowercase().as_str() {
+                "black" => "30".to_string(),
+                "red" => "31".to_string(),
+                "green" => "32".to_string(),
+                "yellow" => "33".to_string(),
+                "blue" => "34".to_string(),
+                "magenta" => "35".to_string(),
+                "cyan" => "36".to_string(),
+                "white" => "37".to_string(),
+                code if !code.is_empty()
+                    && code.chars().all(|c| c.is_ascii_digit() || c == ';') =>
+                {
+                    code.to_string()
+                }
+                _ => {
+                    return Err(serde::de::Error::custom(format!(
+                        "unknown color {name:?}: expected a color name or ANSI code"
+                    )))
+                }
+            },
+        };
+        Ok(Self(code))
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct HistorySettings {
+    pub(crate) file: PathBuf,
+    #[serde(default = "default_history_max_entries")]
+    pub(crate) max_entries: usize,
+}
+
+fn default_history_max_entries() -> usize {
+    1000
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct HotkeySettings {
+    // 整形の一時停止・再開を切り替えるホットキー（例: "ctrl+alt+p"）
+    pub(crate) toggle: Option<String>,
+    // 現在の内容を整形した場合の差分を、クリップボードを変更せずに表示するホットキー
+    pub(crate) preview: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct FocusSettings {
+    // フォーカス中のウィンドウのクラス名・タイトルにこれらの文字列が含まれていれば整形しない
+    #[serde(default)]
+    pub(crate) skip: Vec<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct SourceSettings {
+    // コピー元のアプリケーション名にこれらの文字列が含まれていれば整形しない
+    #[serde(default)]
+    pub(crate) skip: Vec<String>,
+}
+
+impl AppConfig {
+    /// TOML形式の設定を読み込み、`active_profile`が指定されていればそのプロファイルを適用する
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let table = toml::from_str(text).context("Failed to parse config.toml")?;
+        Self::from_table(table).context("Failed to parse config.toml")
+    }
+
+    /// JSON形式の設定を読み込む。構造はTOML形式と同じ
+    pub fn from_json(text: &str) -> Result<Self> {
+        let table = serde_json::from_str(text).context("Failed to parse JSON config")?;
+        Self::from_table(table).context("Failed to parse JSON config")
+    }
+
+    // 置換ルールと除外リストの\uXXXXを文字に変換してから設定として読み込む
+    fn from_table(mut table: toml::Table) -> Result<Self> {
+        decode_config_escapes(&mut table)?;
+        toml::Value::Table(table)
+            .try_into::<Self>()?
+            .resolve_active_profile()
+    }
+
+    // active_profileが指定されていれば、そのプロファイルの置換ルールと除外リストを使う
+    fn resolve_active_profile(mut self) -> Result<Self> {
+        if let Some(name) = &self.app.active_profile {
+            let profile = self
+                .profiles
+                .get(name)
+                .with_context(|| format!("Profile not found: {name}"))?;
+            self.replacements = profile.replacements.clone();
+            self.replacement_list = profile.replacement_list.clone();
+            self.exclusions = profile.exclusions.clone();
+            info!("Using profile: {name}");
+        }
+        Ok(self)
+    }
+
+    // [replacements]と[[replacement]]の置換ルールを適用順に並べる
+    pub(crate) fn replacement_rules(&self) -> impl Iterator<Item = (&str, &Replacement)> {
+        self.replacements
+            .iter()
+            .chain(self.replacement_list.iter().map(|(from, to)| (from, to)))
+            .map(|(from, replacement)| (from.as_str(), replacement))
+    }
+}
+
+// この回数連続で再読み込みに失敗したらエラーとして通知する
+const RELOAD_FAILURE_ERROR_THRESHOLD: u32 = 3;
+
+// 起動してからの設定ファイルの再読み込み回数
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct ReloadTotals {
+    pub(crate) successes: u64,
+    pub(crate) failures: u64,
+}
+
+// 再読み込みの前後の置換ルールと除外リストの差分。同じ置換前の文字列のルールが複数ある場合は記述順に対応させる
+#[derive(Debug, Default, PartialEq)]
+struct ConfigDiff {
+    added_rules: Vec<String>,
+    removed_rules: Vec<String>,
+    changed_rules: Vec<String>,
+    added_exclusions: Vec<char>,
+    removed_exclusions: Vec<char>,
+}
+
+impl ConfigDiff {
+    fn between(old: &AppConfig, new: &AppConfig) -> Self {
+        let group = |config: &AppConfig| {
+            let mut rules: IndexMap<String, Vec<Replacement>> = IndexMap::new();
+            for (original, replacement) in config.replacement_rules() {
+                rules
+                    .entry(original.to_string())
+                    .or_default()
+                    .push(replacement.clone());
+            }
+            rules
+        };
+        let old_rules = group(old);
+        let new_rules = group(new);
+        let mut diff = ConfigDiff::default();
+        for (original, new_list) in &new_rules {
+            let old_list = old_rules.get(original).map_or(&[][..], Vec::as_slice);
+            for (i, replacement) in new_list.iter().enumerate() {
+                match old_list.get(i) {
+                    None => diff.added_rules.push(original.clone()),
+                    Some(previous) if previous != replacement => {
+                        diff.changed_rules.push(original.clone())
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        for (original, old_list) in &old_rules {
+            let new_len = new_rules.get(original).map_or(0, Vec::len);
+            for _ in new_len..old_list.len() {
+                diff.removed_rules.push(original.clone());
+            }
+        }
+        let old_exclusions: HashSet<char> = old.exclusions.iter().copied().collect();
+        let new_exclusions: HashSet<char> = new.exclusions.iter().copied().collect();
+        diff.added_exclusions = new
+            .exclusions
+            .iter()
+            .copied()
+            .filter(|c| !old_exclusions.contains(c))
+            .collect();
+        diff.removed_exclusions = old
+            .exclusions
+            .iter()
+            .copied()
+            .filter(|c| !new_exclusions.contains(c))
+            .collect();
+        diff
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == ConfigDiff::default()
+    }
+
+    // "+2 rules, -1 rule, 1 changed, +1 exclusion"のような要約
+    fn summary(&self) -> String {
+        if self.is_empty() {
+            return "no rule or exclusion changes".to_string();
+        }
+        let plural = |count: usize, noun: &str| {
+            if count == 1 {
+                format!("{count} {noun}")
+            } else {
+                format!("{count} {noun}s")
+            }
+        };
+        let mut parts = Vec::new();
+        if !self.added_rules.is_empty() {
+            parts.push(format!("+{}", plural(self.added_rules.len(), "rule")));
+        }
+        if !self.removed_rules.is_empty() {
+            parts.push(format!("-{}", plural(self.removed_rules.len(), "rule")));
+        }
+        if !self.changed_rules.is_empty() {
+            parts.push(format!("{} changed", self.changed_rules.len()));
+        }
+        if !self.added_exclusions.is_empty() {
+            parts.push(format!(
+                "+{}",
+                plural(self.added_exclusions.len(), "exclusion")
+            ));
+        }
+        if !self.removed_exclusions.is_empty() {
+            parts.push(format!(
+                "-{}",
+                plural(self.removed_exclusions.len(), "exclusion")
+            ));
+        }
+        parts.join(", ")
+    }
+
+    fn log_details(&self) {
+        for original in &self.added_rules {
+            debug!("Added replacement rule {original:?}");
+        }
+        for original in &self.removed_rules {
+            debug!("Removed replacement rule {original:?}");
+        }
+        for original in &self.changed_rules {
+            debug!("Changed replacement rule {original:?}");
+        }
+        for c in &self.added_exclusions {
+            debug!("Added exclusion {c:?}");
+        }
+        for c in &self.removed_exclusions {
+            debug!("Removed exclusion {c:?}");
+        }
+    }
+}
+
+/// 設定ファイルの作成・読み込み・再読み込みを管理する
+pub struct ConfigManager {
+    config_path: PathBuf,
+    config: AppConfig,
+    loaded_at: Instant,
+    pub(crate) reload_failures: u32,
+    pub(crate) reload_totals: ReloadTotals,
+    // 環境変数から読み込んだ設定。ファイルがないため監視・再読み込みしない
+    inline: bool,
+}
+
+// 拡張子が.jsonの設定ファイルはJSON形式、それ以外はTOML形式として扱う
+pub(crate) fn is_json_config(config_path: &Path) -> bool {
+    config_path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+// 設定ファイルを読み込み、includeで指定されたファイルを順にマージしたテーブルを返す
+// stackには読み込み中のファイルを保持し、循環したincludeを検出する
+fn load_config_table(config_path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Table> {
+    let canonical = config_path
+        .canonicalize()
+        .with_context(|| format!("Failed to read config file {}", config_path.display()))?;
+    if let Some(position) = stack.iter().position(|path| path == &canonical) {
+        let cycle: Vec<String> = stack[position..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|path| path.display().to_string())
+            .collect();
+        anyhow::bail!("Cyclic include: {}", cycle.join(" -> "));
+    }
+
+    let text = fs::read_to_string(config_path)?;
+    let mut table: toml::Table = if is_json_config(config_path) {
+        serde_json::from_str(&text).context("Failed to parse JSON config")?
+    } else {
+        toml::from_str(&text).context("Failed to parse config.toml")?
+    };
+    let Some(includes) = table.remove("include") else {
+        return Ok(table);
+    };
+    let includes: Vec<PathBuf> = includes
+        .try_into()
+        .context("`include` must be an array of file paths")?;
+
+    // 相対パスはincludeを記述したファイルのディレクトリを基準にする
+    let base_dir = config_path.parent().unwrap_or(Path::new(""));
+    let mut merged = toml::Table::new();
+    stack.push(canonical);
+    for include in includes {
+        let include_path = base_dir.join(include);
+        let included = load_config_table(&include_path, stack)
+            .with_context(|| format!("Failed to include {}", include_path.display()))?;
+        merge_config_tables(&mut merged, included);
+    }
+    stack.pop();
+    // 記述したファイル自身の設定はincludeしたファイルより優先する
+    merge_config_tables(&mut merged, table);
+    Ok(merged)
+}
+
+// テーブルはキーごとに再帰的にマージし、それ以外の値は後から読み込んだものを優先する
+fn merge_config_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_config_tables(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// デフォルトの設定をJSON形式に変換する（コメントは失われる）
+fn default_config_json() -> Result<String> {
+    toml_config_to_json(DEFAULT_CONFIG)
+}
+
+fn toml_config_to_json(text: &str) -> Result<String> {
+    let value: toml::Value = toml::from_str(text).context("Failed to parse config.toml")?;
+    serde_json::to_string_pretty(&value).context("Failed to convert default config to JSON")
+}
+
+// --init: 設定ファイルのテンプレートを書き込む。既存のファイルはforceの場合のみ上書きする
+pub(crate) fn init_config(config_path: &Path, force: bool) -> Result<()> {
+    if config_path.exists() && !force {
+        anyhow::bail!(
+            "Config file already exists: {} (use --force to overwrite)",
+            config_path.display()
+        );
+    }
+    if let Some(config_dir) = config_path.parent() {
+        if !config_dir.as_os_str().is_empty() {
+            fs::create_dir_all(config_dir).with_context(|| {
+                format!(
+                    "Failed to create config directory: {}",
+                    config_dir.display()
+                )
+            })?;
+        }
+    }
+    // JSONにはコメントを書けないため、設定値だけを書き込む
+    let template = if is_json_config(config_path) {
+        toml_config_to_json(CONFIG_TEMPLATE)?
+    } else {
+        CONFIG_TEMPLATE.to_string()
+    };
+    fs::write(config_path, template)
+        .with_context(|| format!("Failed to write {}", config_path.display()))
+}
+
+impl ConfigManager {
+    /// 設定ファイルを読み込む。パスを省略した場合はデフォルトの場所を使い、ファイルがなければ作成する。
+    /// パスを省略し、環境変数`CLIPBOARD_FORMATTER_CONFIG`が設定されている場合はその内容を設定として使う
+    pub fn new(config_path: Option<PathBuf>) -> Result<Self> {
+        let config_path = match config_path {
+            Some(config_path) => config_path,
+            None => {
+                if let Ok(text) = env::var(CONFIG_ENV_VAR) {
+                    return Self::from_inline(&text);
+                }
+                Self::get_config_path_static()?
+            }
+        };
+        Self::create_default_config(&config_path)?;
+        let config = Self::load_config(&config_path)?;
+        Ok(Self::with_config(config_path, config))
+    }
+
+    pub(crate) fn with_config(config_path: PathBuf, config: AppConfig) -> Self {
+        Self {
+            config_path,
+            config,
+            loaded_at: Instant::now(),
+            reload_failures: 0,
+            reload_totals: ReloadTotals::default(),
+            inline: false,
+        }
+    }
+
+    fn from_inline(text: &str) -> Result<Self> {
+        let config = AppConfig::from_toml(text)
+            .with_context(|| format!("Failed to parse config from {CONFIG_ENV_VAR}"))?;
+        info!("Using config from {CONFIG_ENV_VAR}; reloading is disabled");
+        Ok(Self {
+            inline: true,
+            ..Self::with_config(PathBuf::new(), config)
+        })
+    }
+
+    pub(crate) fn is_inline(&self) -> bool {
+        self.inline
+    }
+
+    // --configで指定されたパス、なければデフォルトの場所
+    pub(crate) fn resolve_config_path(config_path: Option<PathBuf>) -> Result<PathBuf> {
+        match config_path {
+            Some(config_path) => Ok(config_path),
+            None => Self::get_config_path_static(),
+        }
+    }
+
+    fn get_config_path_static() -> Result<PathBuf> {
+        let config_dir = if let Some(config_dir) = env::var_os("XDG_CONFIG_HOME") {
+            PathBuf::from(config_dir)
+        } else {
+            dirs::config_dir().context("Failed to get config directory")?
+        };
+        Ok(config_dir
+            .join("clipboard-formatter")
+            .join(CONFIG_FILE_NAME))
+    }
+
+    fn create_default_config(config_path: &Path) -> Result<()> {
+        let config_dir = config_path
+            .parent()
+            .with_context(|| format!("Invalid config file path: {}", config_path.display()))?;
+        if !config_dir.as_os_str().is_empty() && !config_dir.is_dir() {
+            fs::create_dir_all(config_dir).with_context(|| {
+                format!(
+                    "Failed to create config directory: {}",
+                    config_dir.display()
+                )
+            })?;
+        }
+        if !config_path.exists() {
+            let default_config = if is_json_config(config_path) {
+                default_config_json()?
+            } else {
+                DEFAULT_CONFIG.to_string()
+            };
+            fs::write(config_path, default_config).context("Failed to create default config")?;
+            info!("Created default config: {}", config_path.display());
+        }
+        Ok(())
+    }
+
+    fn load_config(config_path: &Path) -> Result<AppConfig> {
+        let table = load_config_table(config_path, &mut Vec::new())?;
+        let config = AppConfig::from_table(table).context("Failed to parse config.toml")?;
+        let active_rules = config
+            .replacement_rules()
+            .filter(|(_, r)| r.enabled)
+            .count();
+        debug!(
+            "{active_rules} of {} replacement rules are active",
+            config.replacement_rules().count()
+        );
+        for (original, replacement) in config.replacement_rules() {
+            debug!("Loaded rule {}", describe_rule(original, replacement));
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn check_config(config_path: &Path) -> Result<String> {
+        let config = Self::load_config(config_path)?;
+
+        // 実行時は不正な正規表現をスキップするため、ここで改めて全パターンを検証する
+        let table = load_config_table(config_path, &mut Vec::new())?;
+        let patterns: Vec<String> = table
+            .get("regex_replacements")
+            .and_then(|v| v.as_table())
+            .map(|patterns| patterns.keys().cloned().collect())
+            .unwrap_or_default();
+        for pattern in patterns {
+            Regex::new(&pattern)
+                .with_context(|| format!("Invalid regex replacement pattern {pattern:?}"))?;
+        }
+
+        let mut summary = format!(
+            "Config OK: {} ({} replacement rules, {} regex replacement rules, {} exclusions)",
+            config_path.display(),
+            config.replacement_rules().count(),
+            config.regex_replacements.0.len(),
+            config.exclusions.len()
+        );
+        // 説明が付いたルールは一覧として表示する
+        for (original, replacement) in config.replacement_rules() {
+            if replacement.note.is_some() {
+                summary.push_str(&format!("\n  {}", describe_rule(original, replacement)));
+            }
+        }
+        Ok(summary)
+    }
+
+    /// 設定ファイルを再読み込みする。失敗した場合は直前の設定が維持される
+    pub fn reload_config(&mut self) -> Result<()> {
+        if self.inline {
+            return Ok(());
+        }
+        match Self::load_config(&self.config_path) {
+            Ok(new_config) => {
+                let diff = ConfigDiff::between(&self.config, &new_config);
+                self.config = new_config;
+                self.loaded_at = Instant::now();
+                self.reload_failures = 0;
+                self.reload_totals.successes += 1;
+                info!("Reloaded config.toml ({})", diff.summary());
+                diff.log_details();
+                Ok(())
+            }
+            Err(e) => {
+                self.reload_failures += 1;
+                self.reload_totals.failures += 1;
+                if self.reload_failures >= RELOAD_FAILURE_ERROR_THRESHOLD {
+                    // {e:#}でtomlのパースエラー（行番号・列番号を含む）まで表示する
+                    error!(
+                        "Failed to reload config.toml {} times in a row: {e:#}",
+                        self.reload_failures
+                    );
+                } else {
+                    warn!("Failed to reload config.toml: {e}");
+                }
+                info!(
+                    "Keeping the last valid config loaded {}s ago",
+                    self.loaded_at.elapsed().as_secs()
+                );
+                Err(e)
+            }
+        }
+    }
+
+    pub fn get_config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    pub fn get_config_path(&self) -> &PathBuf {
+        &self.config_path
+    }
+}
+
+// --verboseで表示する、include・プロファイルを反映した後の設定。
+// 空白や不可視文字の違いが分かるよう、文字列は引用符付きのエスケープ表記にする
+pub(crate) fn describe_resolved_config(config: &AppConfig, source: &str) -> String {
+    let mut lines = vec![format!("Resolved config from {source}")];
+    lines.push(format!(
+        "  active profile: {}",
+        config.app.active_profile.as_deref().unwrap_or("(none)")
+    ));
+    let disabled = config
+        .replacement_rules()
+        .filter(|(_, replacement)| !replacement.enabled)
+        .count();
+    lines.push(format!(
+        "  replacement rules: {} ({disabled} disabled)",
+        config.replacement_rules().count()
+    ));
+    for (original, replacement) in config.replacement_rules() {
+        let mut options = Vec::new();
+        if !replacement.enabled {
+            options.push("disabled".to_string());
+        }
+        if let Some(anchor) = replacement.anchor {
+            options.push(format!("anchor: {anchor:?}"));
+        }
+        if replacement.case_insensitive {
+            options.push("case insensitive".to_string());
+        }
+        if let Some(script) = replacement.when_script {
+            options.push(format!("when: {script:?}"));
+        }
+        if let Some(note) = &replacement.note {
+            options.push(format!("note: {note}"));
+        }
+        let options = if options.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", options.join(", "))
+        };
+        lines.push(format!("    {original:?} → {:?}{options}", replacement.to));
+    }
+    lines.push(format!(
+        "  regex replacement rules: {}",
+        config.regex_replacements.0.len()
+    ));
+    for (re, replacement) in &config.regex_replacements.0 {
+        lines.push(format!("    {:?} → {replacement:?}", re.as_str()));
+    }
+    let exclusions: String = config.exclusions.iter().collect();
+    lines.push(format!(
+        "  exclusions: {} {exclusions:?}",
+        config.exclusions.len()
+    ));
+    if let Some(re) = &config.exclusion_categories.0 {
+        lines.push(format!("  exclusion categories: {}", re.as_str()));
+    }
+    lines.push(format!("  app: {:#?}", config.app));
+    lines.push(format!("  range: {:?}", config.range));
+    lines.push(format!("  hotkeys: {:?}", config.hotkeys));
+    lines.push(format!("  focus: {:?}", config.focus));
+    lines.push(format!("  source: {:?}", config.source));
+    lines.push(format!("  diff: {:?}", config.diff));
+    lines.push(format!("  history: {:?}", config.history));
+    lines.push(format!("  logging: {:?}", config.logging));
+    lines.push(format!("  metrics: {:?}", config.metrics));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::format::format_text;
+    use crate::run::ConfigWatcher;
+    use crate::test_support::{ConfigBuilder, ENV_LOCK, REPLACEMENT_LIST_CONFIG};
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_create_default_config() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        // 一時ディレクトリを作成
+        let temp_dir = tempdir().unwrap();
+        let temp_path = temp_dir.path().to_path_buf();
+
+        // 一時ディレクトリをXDG_CONFIG_HOMEに設定
+        env::set_var("XDG_CONFIG_HOME", &temp_path);
+
+        // ConfigManagerを作成
+        let _config_manager = ConfigManager::new(None).unwrap();
+
+        // 設定ファイルが正しい場所に作成されたかを確認
+        let config_path = temp_path.join("clipboard-formatter").join("config.toml");
+
+        assert!(config_path.exists(), "config.tomlが存在しません");
+
+        // 環境変数のクリーンアップ
+        env::remove_var("XDG_CONFIG_HOME");
+    }
+
+    #[test]
+    fn test_resolve_config_path() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        let temp_dir = tempdir().unwrap();
+        env::set_var("XDG_CONFIG_HOME", temp_dir.path());
+
+        // --print-config-pathはXDG_CONFIG_HOMEを反映したパスを表示する
+        assert_eq!(
+            ConfigManager::resolve_config_path(None).unwrap(),
+            temp_dir
+                .path()
+                .join("clipboard-formatter")
+                .join("config.toml")
+        );
+        // --configの指定が優先される
+        assert_eq!(
+            ConfigManager::resolve_config_path(Some(PathBuf::from("work.toml"))).unwrap(),
+            PathBuf::from("work.toml")
+        );
+
+        env::remove_var("XDG_CONFIG_HOME");
+    }
+
+    #[test]
+    fn test_create_default_config_at_explicit_path() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("profiles").join("work.toml");
+
+        // 指定したパスに設定ファイルが作成され、読み込まれる
+        let config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+
+        assert!(config_path.exists(), "work.tomlが存在しません");
+        assert_eq!(config_manager.get_config_path(), &config_path);
+        assert_eq!(config_manager.get_config().app.clipboard_poll_interval, 300);
+    }
+
+    #[test]
+    fn test_init_config() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("new").join("config.toml");
+
+        // 存在しないディレクトリも作成してテンプレートを書き込む
+        init_config(&config_path, false).unwrap();
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), CONFIG_TEMPLATE);
+        ConfigManager::load_config(&config_path).unwrap();
+
+        // 既存のファイルは--forceがなければ上書きしない
+        fs::write(&config_path, "# 編集済み").unwrap();
+        let err = init_config(&config_path, false).unwrap_err();
+        assert!(err.to_string().contains("--force"), "{err}");
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), "# 編集済み");
+
+        init_config(&config_path, true).unwrap();
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), CONFIG_TEMPLATE);
+
+        // JSONの場合はコメントを除いた設定値を書き込む
+        let json_path = temp_dir.path().join("config.json");
+        init_config(&json_path, false).unwrap();
+        ConfigManager::load_config(&json_path).unwrap();
+    }
+
+    #[test]
+    fn test_config_template_matches_default_config() {
+        // テンプレートの設定値はデフォルトの設定と同じ
+        let template: toml::Value = toml::from_str(CONFIG_TEMPLATE).unwrap();
+        let default: toml::Value = toml::from_str(DEFAULT_CONFIG).unwrap();
+        assert_eq!(template, default);
+    }
+
+    #[test]
+    fn test_load_json_config_matches_toml() {
+        let temp_dir = tempdir().unwrap();
+        let toml_path = temp_dir.path().join("config.toml");
+        let json_path = temp_dir.path().join("config.json");
+        fs::write(&toml_path, DEFAULT_CONFIG).unwrap();
+        fs::write(
+            &json_path,
+            r#"{
+                "exclusions": ["　", "！", "？", "〜", "～"],
+                "app": {
+                    "clipboard_poll_interval": 300,
+                    "config_reload_interval": 5000,
+                    "remove_duplicate_previous_lines": false,
+                    "convert_katakana": false,
+                    "direction": "to_half"
+                },
+                "replacements": {
+                    "，": ", ",
+                    "．": ". ",
+                    "CRLF": "。",
+                    "頚": "頸"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        // 拡張子が.jsonの場合はJSONとして読み込み、同じ内容のTOMLと同じ設定になる
+        let toml_config = ConfigManager::load_config(&toml_path).unwrap();
+        let json_config = ConfigManager::load_config(&json_path).unwrap();
+        assert_eq!(format!("{json_config:?}"), format!("{toml_config:?}"));
+        assert!(ConfigManager::check_config(&json_path)
+            .unwrap()
+            .contains("4 replacement rules"));
+    }
+
+    #[test]
+    fn test_create_default_json_config() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.json");
+
+        // .jsonのパスを指定した場合はデフォルトの設定をJSON形式で作成する
+        let config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let text = fs::read_to_string(&config_path).unwrap();
+        assert!(
+            serde_json::from_str::<serde_json::Value>(&text).is_ok(),
+            "{text}"
+        );
+        assert_eq!(config_manager.get_config().replacements.len(), 4);
+    }
+
+    #[test]
+    fn test_create_default_config_uncreatable_directory() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("not-a-directory");
+        fs::write(&file_path, "").unwrap();
+
+        // 親ディレクトリが作成できない場合はわかりやすいエラーを返す
+        let result = ConfigManager::new(Some(file_path.join("config.toml")));
+        let error = format!("{:#}", result.err().unwrap());
+        assert!(
+            error.contains("Failed to create config directory"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_check_config_valid() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, DEFAULT_CONFIG).unwrap();
+
+        let summary = ConfigManager::check_config(&config_path).unwrap();
+        assert!(summary.starts_with("Config OK"), "{summary}");
+        assert!(summary.contains("4 replacement rules"), "{summary}");
+        assert!(summary.contains("0 regex replacement rules"), "{summary}");
+        assert!(summary.contains("5 exclusions"), "{summary}");
+    }
+
+    #[test]
+    fn test_check_config_reports_notes() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+
+            [replacements]
+            "teh" = { to = "the", note = "fix vendor typo" }
+            "，" = ", "
+            "#,
+        )
+        .unwrap();
+
+        let summary = ConfigManager::check_config(&config_path).unwrap();
+        assert!(summary.contains("2 replacement rules"), "{summary}");
+        assert!(
+            summary.ends_with("\n  teh→the (note: fix vendor typo)"),
+            "{summary}"
+        );
+        // 説明のないルールは一覧に表示しない
+        assert!(!summary.contains("，→"), "{summary}");
+    }
+
+    #[test]
+    fn test_check_config_invalid_regex() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!("{DEFAULT_CONFIG}\n\"(unclosed\" = \"x\"\n"),
+        )
+        .unwrap();
+
+        // 実行時はスキップされる不正なパターンもエラーとして報告する
+        let error = format!(
+            "{:#}",
+            ConfigManager::check_config(&config_path).unwrap_err()
+        );
+        assert!(error.contains("(unclosed"), "{error}");
+    }
+
+    #[test]
+    fn test_check_config_parse_error() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, "[app]\nclipboard_poll_interval = \"fast\"\n").unwrap();
+
+        assert!(ConfigManager::check_config(&config_path).is_err());
+    }
+
+    #[test]
+    fn test_check_config_missing_file() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("missing.toml");
+
+        // 設定ファイルを新規作成せずにエラーを返す
+        assert!(ConfigManager::check_config(&config_path).is_err());
+        assert!(!config_path.exists());
+    }
+
+    // Tests for include
+    #[test]
+    fn test_load_config_include_override() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("base.toml"), DEFAULT_CONFIG).unwrap();
+        fs::write(
+            temp_dir.path().join("local.toml"),
+            r#"
+            [app]
+            trim = true
+
+            [replacements]
+            "，" = "、"
+            "teh" = "the"
+            "#,
+        )
+        .unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "include = [\"base.toml\", \"local.toml\"]\n\n[app]\nclipboard_poll_interval = 1000\n",
+        )
+        .unwrap();
+
+        let config = ConfigManager::load_config(&config_path).unwrap();
+        // 後から読み込んだファイルの置換ルールが優先され、新しいキーは追加される
+        assert_eq!(config.replacements["，"].to, "、");
+        assert_eq!(config.replacements["teh"].to, "the");
+        // 基本の設定ファイルのルールと順序は維持される
+        let keys: Vec<&str> = config.replacements.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["，", "．", "CRLF", "頚", "teh"]);
+        assert!(config.app.trim);
+        // 記述したファイル自身の設定が最も優先される
+        assert_eq!(config.app.clipboard_poll_interval, 1000);
+        assert_eq!(config.app.config_reload_interval, 5000);
+    }
+
+    #[test]
+    fn test_load_config_cyclic_include() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, "include = [\"other.toml\"]\n").unwrap();
+        fs::write(
+            temp_dir.path().join("other.toml"),
+            "include = [\"config.toml\"]\n",
+        )
+        .unwrap();
+
+        let error = format!(
+            "{:#}",
+            ConfigManager::load_config(&config_path).unwrap_err()
+        );
+        assert!(error.contains("Cyclic include"), "{error}");
+        assert!(error.contains("other.toml"), "{error}");
+    }
+
+    #[test]
+    fn test_load_config_missing_include() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, "include = [\"missing.toml\"]\n").unwrap();
+
+        let error = format!(
+            "{:#}",
+            ConfigManager::load_config(&config_path).unwrap_err()
+        );
+        assert!(error.contains("missing.toml"), "{error}");
+    }
+
+    #[test]
+    fn test_parse_logging_settings() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+
+            [logging]
+            file = "/tmp/clipboard-formatter.log"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.logging.file,
+            Some(PathBuf::from("/tmp/clipboard-formatter.log"))
+        );
+        assert!(AppConfig::default().logging.file.is_none());
+    }
+
+    #[test]
+    fn test_parse_direction() {
+        let settings: AppSettings = toml::from_str(
+            r#"
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+            direction = "to_full"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.direction, Direction::ToFull);
+
+        let settings: AppSettings = toml::from_str(
+            r#"
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.direction, Direction::ToHalf);
+    }
+
+    #[test]
+    fn test_parse_exclusion_ranges() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            exclusions = ["０-９", "！", "-", "９-０", "ab"]
+
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+            "#,
+        )
+        .unwrap();
+        // 範囲は展開され、逆順の範囲や2文字以上の文字列は読み飛ばされる
+        let mut expected: Vec<char> = ('０'..='９').collect();
+        expected.extend(['！', '-']);
+        assert_eq!(config.exclusions, expected);
+    }
+
+    #[test]
+    fn test_exclusion_categories_skip_unknown_codes() {
+        // 未対応のカテゴリは読み飛ばし、すべて未対応の場合は除外しない
+        assert!(ExclusionCategories::from_codes(&["Greek", "X"]).0.is_none());
+        let categories = ExclusionCategories::from_codes(&["Greek", "Nd"]);
+        let mut exclusion_list = HashSet::new();
+        categories.extend_exclusions("１a！", &mut exclusion_list);
+        assert_eq!(exclusion_list, HashSet::from(['１']));
+    }
+
+    // Tests for exclusions config shape
+    #[test]
+    fn test_parse_exclusions_list() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            exclusions = ["！", "？"]
+
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.exclusions, vec!['！', '？']);
+    }
+
+    #[test]
+    fn test_parse_exclusions_legacy_table() {
+        // 旧形式の [exclusions] テーブルも読み込める
+        let config: AppConfig = toml::from_str(
+            r#"
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+
+            [exclusions]
+            exclusions = ["！", "？"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.exclusions, vec!['！', '？']);
+    }
+
+    #[test]
+    fn test_parse_exclusions_missing() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+            "#,
+        )
+        .unwrap();
+        assert!(config.exclusions.is_empty());
+    }
+
+    #[test]
+    fn test_parse_default_config() {
+        let config: AppConfig = toml::from_str(DEFAULT_CONFIG).unwrap();
+        assert_eq!(config.exclusions, vec!['　', '！', '？', '〜', '～']);
+    }
+
+    #[test]
+    fn test_wave_dash_config() {
+        let config = AppConfig::from_toml(
+            &DEFAULT_CONFIG.replace("wave_dash = \"keep\"", "wave_dash = \"fullwidth_tilde\""),
+        )
+        .unwrap();
+        assert_eq!(config.app.wave_dash, WaveDash::FullwidthTilde);
+    }
+
+    #[test]
+    fn test_invisible_chars_config() {
+        // 削除する文字は範囲やコードポイントで指定できる
+        let config = AppConfig::from_toml(&DEFAULT_CONFIG.replace(
+            "strip_invisible = false",
+            "strip_invisible = true\ninvisible_chars = ['\\u200B-\\u200D', \"\\u0007\"]",
+        ))
+        .unwrap();
+        assert!(config.app.strip_invisible);
+        assert_eq!(
+            config.app.invisible_chars,
+            ['\u{200B}', '\u{200C}', '\u{200D}', '\u{7}']
+        );
+        assert_eq!(
+            format_text("a\u{200D}b\u{FEFF}\u{7}", &config)
+                .unwrap()
+                .text,
+            "ab\u{FEFF}"
+        );
+        // 指定しない場合のデフォルトにはタブ・改行・ゼロ幅接合子を含めない
+        let default_chars = AppConfig::default().app.invisible_chars;
+        assert!(default_chars.contains(&'\u{200B}') && default_chars.contains(&'\u{FEFF}'));
+        for c in ['\t', '\n', '\r', ' ', '\u{200D}'] {
+            assert!(!default_chars.contains(&c), "{c:?}");
+        }
+    }
+
+    #[test]
+    fn test_parse_replacements_preserves_order() {
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "z" = "1"
+            "a" = "2"
+            "m" = "3"
+            "#,
+        )
+        .unwrap();
+        let keys: Vec<&str> = replacements.keys().map(String::as_str).collect();
+        assert_eq!(keys, vec!["z", "a", "m"]);
+    }
+
+    // Tests for inline config
+    #[test]
+    fn test_inline_config() {
+        let mut config_manager = ConfigManager::from_inline(PROFILES_CONFIG).unwrap();
+        assert!(config_manager.is_inline());
+        assert_eq!(config_manager.get_config_path(), &PathBuf::new());
+        assert_eq!(
+            config_manager.get_config().app.active_profile.as_deref(),
+            Some("japanese")
+        );
+        assert_eq!(
+            format_text("ｆｏｏ，", config_manager.get_config())
+                .unwrap()
+                .text,
+            "foo、"
+        );
+
+        // 再読み込みと監視は行わない
+        config_manager.reload_config().unwrap();
+        let mut config_watcher = ConfigWatcher::default();
+        assert!(!config_watcher.watch(&config_manager).unwrap());
+        assert!(config_watcher.watcher.is_none());
+    }
+
+    #[test]
+    fn test_inline_config_invalid() {
+        assert!(ConfigManager::from_inline("[app").is_err());
+    }
+
+    // Tests for reload failures
+    #[test]
+    fn test_malformed_reload_keeps_previous_config() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, PROFILES_CONFIG).unwrap();
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+
+        // 書き込み途中の壊れた設定ファイル
+        fs::write(&config_path, "[app\nclipboard_poll_interval = ").unwrap();
+        for failures in 1..=RELOAD_FAILURE_ERROR_THRESHOLD {
+            assert!(config_manager.reload_config().is_err());
+            assert_eq!(config_manager.reload_failures, failures);
+        }
+
+        // 直前の正しい設定で整形を続けられる
+        assert_eq!(
+            config_manager.get_config().app.active_profile.as_deref(),
+            Some("japanese")
+        );
+        assert_eq!(
+            format_text("，", config_manager.get_config()).unwrap().text,
+            "、"
+        );
+
+        // 正しい設定に戻すと失敗回数がリセットされる
+        fs::write(&config_path, PROFILES_CONFIG).unwrap();
+        config_manager.reload_config().unwrap();
+        assert_eq!(config_manager.reload_failures, 0);
+        // 累計の回数はリセットされない
+        assert_eq!(
+            config_manager.reload_totals,
+            ReloadTotals {
+                successes: 1,
+                failures: RELOAD_FAILURE_ERROR_THRESHOLD as u64,
+            }
+        );
+    }
+
+    // Tests for config diff on reload
+    #[test]
+    fn test_config_diff_summary() {
+        let old = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                ("，".to_string(), ", ".into()),
+                ("．".to_string(), ". ".into()),
+                ("頚".to_string(), "頸".into()),
+            ]))
+            .exclusions(&['！', '？'])
+            .build();
+        let new = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                ("，".to_string(), "、".into()),
+                ("頚".to_string(), "頸".into()),
+                ("CRLF".to_string(), "。".into()),
+                ("TODO".to_string(), "ToDo".into()),
+            ]))
+            .exclusions(&['！', '〜'])
+            .build();
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.added_rules, ["CRLF", "TODO"]);
+        assert_eq!(diff.removed_rules, ["．"]);
+        assert_eq!(diff.changed_rules, ["，"]);
+        assert_eq!(diff.added_exclusions, ['〜']);
+        assert_eq!(diff.removed_exclusions, ['？']);
+        assert_eq!(
+            diff.summary(),
+            "+2 rules, -1 rule, 1 changed, +1 exclusion, -1 exclusion"
+        );
+
+        // 同じ設定の場合は差分なし
+        let diff = ConfigDiff::between(&new, &new);
+        assert!(diff.is_empty());
+        assert_eq!(diff.summary(), "no rule or exclusion changes");
+    }
+
+    #[test]
+    fn test_config_diff_counts_disabled_and_duplicate_rules() {
+        // 有効・無効の切り替えは変更として扱い、[[replacement]]の同じ置換前の文字列は記述順に対応させる
+        let old = AppConfig::from_toml(&DEFAULT_CONFIG.replace(
+            "\"頚\" = \"頸\"",
+            "\"頚\" = \"頸\"\n\n[[replacement]]\nfrom = \"a\"\nto = \"b\"",
+        ))
+        .unwrap();
+        let new = AppConfig::from_toml(&DEFAULT_CONFIG.replace(
+            "\"頚\" = \"頸\"",
+            "\"頚\" = { to = \"頸\", enabled = false }\n\n[[replacement]]\nfrom = \"a\"\nto = \"b\"\n\n[[replacement]]\nfrom = \"a\"\nto = \"c\"",
+        ))
+        .unwrap();
+        assert_eq!(
+            ConfigDiff::between(&old, &new).summary(),
+            "+1 rule, 1 changed"
+        );
+    }
+
+    #[test]
+    fn test_parse_line_range() {
+        let parse = |spec: &str| LineRange::try_from(spec.to_string());
+        assert_eq!(
+            parse("2"),
+            Ok(LineRange {
+                start: 2,
+                end: Some(2)
+            })
+        );
+        assert_eq!(
+            parse("2-3"),
+            Ok(LineRange {
+                start: 2,
+                end: Some(3)
+            })
+        );
+        assert_eq!(
+            parse("2-"),
+            Ok(LineRange {
+                start: 2,
+                end: None
+            })
+        );
+        for invalid in ["0", "3-2", "a-b", "", "-2"] {
+            assert!(parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    // Tests for profiles
+    const PROFILES_CONFIG: &str = r#"
+        [app]
+        clipboard_poll_interval = 300
+        config_reload_interval = 5000
+        active_profile = "japanese"
+
+        [replacements]
+        "foo" = "bar"
+
+        [profiles.japanese]
+        exclusions = ["！"]
+
+        [profiles.japanese.replacements]
+        "，" = "、"
+
+        [profiles.code.replacements]
+        "，" = ","
+    "#;
+
+    fn profile_config(active_profile: Option<&str>) -> Result<AppConfig> {
+        let mut config: AppConfig = toml::from_str(PROFILES_CONFIG).unwrap();
+        config.app.active_profile = active_profile.map(str::to_string);
+        config.resolve_active_profile()
+    }
+
+    #[test]
+    fn test_describe_resolved_config() {
+        let mut config = profile_config(Some("japanese")).unwrap();
+        config.replacement_list.push((
+            " ".to_string(),
+            Replacement {
+                enabled: false,
+                note: Some("全角スペース".to_string()),
+                ..Replacement::from("　")
+            },
+        ));
+        let description = describe_resolved_config(&config, "/tmp/config.toml");
+
+        assert!(description.starts_with("Resolved config from /tmp/config.toml"));
+        assert!(
+            description.contains("  active profile: japanese"),
+            "{description}"
+        );
+        // プロファイルの置換ルールと除外リストが反映されている
+        assert!(
+            description.contains("  replacement rules: 2 (1 disabled)"),
+            "{description}"
+        );
+        assert!(
+            description.contains("    \"，\" → \"、\"\n"),
+            "{description}"
+        );
+        assert!(
+            description.contains(r#"    " " → "\u{3000}" (disabled, note: 全角スペース)"#),
+            "{description}"
+        );
+        assert!(!description.contains("\"foo\""), "{description}");
+        assert!(
+            description.contains("  exclusions: 1 \"！\""),
+            "{description}"
+        );
+        assert!(
+            description.contains("clipboard_poll_interval: 300"),
+            "{description}"
+        );
+        assert!(description.contains("direction: ToHalf"), "{description}");
+
+        let description = describe_resolved_config(&AppConfig::default(), "default");
+        assert!(
+            description.contains("  active profile: (none)"),
+            "{description}"
+        );
+        assert!(
+            description.contains("  regex replacement rules: 0"),
+            "{description}"
+        );
+    }
+
+    #[test]
+    fn test_profiles_produce_different_outputs() {
+        let input = "foo，１！";
+
+        let japanese = profile_config(Some("japanese")).unwrap();
+        assert_eq!(format_text(input, &japanese).unwrap().text, "foo、1！");
+
+        let code = profile_config(Some("code")).unwrap();
+        assert_eq!(format_text(input, &code).unwrap().text, "foo,1!");
+
+        // プロファイル未指定の場合はトップレベルのルールを使う
+        let default = profile_config(None).unwrap();
+        assert_eq!(format_text(input, &default).unwrap().text, "bar,1!");
+    }
+
+    #[test]
+    fn test_unknown_profile_is_an_error() {
+        assert!(profile_config(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_reload_switches_active_profile() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, PROFILES_CONFIG).unwrap();
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        assert_eq!(
+            format_text("，", config_manager.get_config()).unwrap().text,
+            "、"
+        );
+
+        // active_profileを書き換えて再読み込みするとルールが切り替わる
+        fs::write(
+            &config_path,
+            PROFILES_CONFIG.replace(
+                r#"active_profile = "japanese""#,
+                r#"active_profile = "code""#,
+            ),
+        )
+        .unwrap();
+        config_manager.reload_config().unwrap();
+        assert_eq!(
+            format_text("，", config_manager.get_config()).unwrap().text,
+            ","
+        );
+    }
+
+    // Tests for replacement toggles
+    #[test]
+    fn test_parse_replacement_entries() {
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "foo" = "bar"
+            "baz" = { to = "qux" }
+            "quux" = { to = "corge", enabled = false }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            replacements["foo"],
+            Replacement {
+                to: "bar".to_string(),
+                enabled: true,
+                anchor: None,
+                note: None,
+                when_script: None,
+                case_insensitive: false,
+            }
+        );
+        assert_eq!(
+            replacements["baz"],
+            Replacement {
+                to: "qux".to_string(),
+                enabled: true,
+                anchor: None,
+                note: None,
+                when_script: None,
+                case_insensitive: false,
+            }
+        );
+        assert_eq!(
+            replacements["quux"],
+            Replacement {
+                to: "corge".to_string(),
+                enabled: false,
+                anchor: None,
+                note: None,
+                when_script: None,
+                case_insensitive: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_replacement_list() {
+        let config = AppConfig::from_toml(REPLACEMENT_LIST_CONFIG).unwrap();
+        let rules: Vec<(&str, &str, bool)> = config
+            .replacement_rules()
+            .map(|(from, r)| (from, r.to.as_str(), r.enabled))
+            .collect();
+        // [replacements]の後に[[replacement]]が記述順に並び、同じfromも残る
+        assert_eq!(
+            rules,
+            vec![
+                ("ｘ", "x", true),
+                ("x", "xy", true),
+                ("y", "z", false),
+                ("x", "w", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_anchored_replacement() {
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "> " = { to = "", anchor = "line_start" }
+            "。" = { to = ".", anchor = "line_end" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(replacements["> "].anchor, Some(Anchor::LineStart));
+        assert_eq!(replacements["。"].anchor, Some(Anchor::LineEnd));
+        assert!(
+            toml::from_str::<Replacements>(r#""a" = { to = "b", anchor = "middle" }"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_decode_unicode_escapes() {
+        assert_eq!(decode_unicode_escapes(r"\uFF21").unwrap(), "Ａ");
+        assert_eq!(
+            decode_unicode_escapes(r"x\uff21\u3000y").unwrap(),
+            "xＡ\u{3000}y"
+        );
+        assert_eq!(decode_unicode_escapes(r"\u{1F600}").unwrap(), "😀");
+        // \u以外のバックスラッシュはそのまま
+        assert_eq!(decode_unicode_escapes(r"C:\temp\n").unwrap(), r"C:\temp\n");
+        // \\uは\uという文字列になる
+        assert_eq!(decode_unicode_escapes(r"\\uFF21").unwrap(), r"\uFF21");
+        assert_eq!(
+            decode_unicode_escapes(r"\\u{1F600}\uFF21").unwrap(),
+            r"\u{1F600}Ａ"
+        );
+        // 16進数も{も続かない\uはエスケープとみなさず、そのまま残す
+        for literal in [r"\usepackage", r"\uZZZZ", r"x\u"] {
+            assert_eq!(decode_unicode_escapes(literal).unwrap(), literal);
+        }
+        assert_eq!(
+            decode_unicode_escapes(r"\usepackage{\uFF21}").unwrap(),
+            r"\usepackage{Ａ}"
+        );
+        // 桁数が足りない、16進数以外が混ざる、閉じていない、サロゲートや範囲外のコードポイントは不正
+        for invalid in [
+            r"\uF",
+            r"\uFF2",
+            r"\uFF2G",
+            r"\u{}",
+            r"\u{FF21",
+            r"\u{FF2G}",
+            r"\uD800",
+            r"\u{110000}",
+        ] {
+            let err = decode_unicode_escapes(invalid).unwrap_err();
+            assert!(err.to_string().contains("Invalid unicode escape"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_config_unicode_escapes() {
+        // TOMLのリテラル文字列（'...'）に書いた\uXXXXも読み込み時に文字に変換する
+        let config = AppConfig::from_toml(
+            r#"
+            exclusions = ['\uFF21', '\uFF10-\uFF19']
+
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+            '\uFF21\uFF22' = 'ab'
+            '\u00A0' = { to = '\u0020', note = "NBSP" }
+
+            [[replacement]]
+            from = 'x'
+            to = '\u2192'
+            "#,
+        )
+        .unwrap();
+        assert!(config.exclusions.contains(&'Ａ'));
+        assert!(config.exclusions.contains(&'５'));
+        assert_eq!(config.replacements["ＡＢ"].to, "ab");
+        assert_eq!(config.replacements["\u{A0}"].to, " ");
+        assert_eq!(config.replacement_list[0].1.to, "→");
+
+        // JSONでも同じ
+        let config = AppConfig::from_json(
+            r#"{
+                "exclusions": ["\\uFF21"],
+                "app": { "clipboard_poll_interval": 300, "config_reload_interval": 5000 },
+                "replacements": { "\\uFF21": "A" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.exclusions, ['Ａ']);
+        assert_eq!(config.replacements["Ａ"].to, "A");
+
+        // 16進数が続かない\uはそのまま残し、\\uは\uという文字列になる
+        let config = AppConfig::from_toml(
+            r#"
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+            '\usepackage' = 'x'
+            '\\u3000' = '\u3000'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.replacements[r"\usepackage"].to, "x");
+        assert_eq!(config.replacements[r"\u3000"].to, "\u{3000}");
+
+        // 不正なエスケープは置換ルールを示してエラーにする
+        for (rule, escape) in [
+            (r"'\uFF2G' = 'x'", r"\\uFF2G"),
+            (r"'\u{FF21' = 'x'", r"\\u{FF21"),
+            (r"'\uD800' = 'x'", r"\\uD800"),
+        ] {
+            let err = AppConfig::from_toml(&format!(
+                "[app]\nclipboard_poll_interval = 300\nconfig_reload_interval = 5000\n\n[replacements]\n{rule}\n"
+            ))
+            .unwrap_err();
+            let message = format!("{err:#}");
+            assert!(message.contains("Invalid unicode escape"), "{message}");
+            assert!(message.contains(escape), "{message}");
+        }
+    }
+
+    #[test]
+    fn test_replacement_expand_env() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        env::set_var("CLIPBOARD_FORMATTER_TEST_USER", "alice");
+        env::remove_var("CLIPBOARD_FORMATTER_TEST_UNSET");
+
+        let config = AppConfig::from_toml(
+            r#"
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+            "{user}" = { to = "$CLIPBOARD_FORMATTER_TEST_USER", expand_env = true }
+            "{home}" = { to = "${CLIPBOARD_FORMATTER_TEST_USER}_home $$5", expand_env = true }
+            "{unset}" = { to = "[$CLIPBOARD_FORMATTER_TEST_UNSET]", expand_env = true }
+            "{literal}" = "$CLIPBOARD_FORMATTER_TEST_USER"
+
+            [[replacement]]
+            from = "{list}"
+            to = "$CLIPBOARD_FORMATTER_TEST_USER"
+            expand_env = true
+            "#,
+        )
+        .unwrap();
+        env::remove_var("CLIPBOARD_FORMATTER_TEST_USER");
+
+        // 読み込んだ時点の値に展開される
+        assert_eq!(config.replacements["{user}"].to, "alice");
+        assert_eq!(config.replacements["{home}"].to, "alice_home $5");
+        // 設定されていない変数はそのまま残す
+        assert_eq!(
+            config.replacements["{unset}"].to,
+            "[$CLIPBOARD_FORMATTER_TEST_UNSET]"
+        );
+        // expand_envを指定しなければ展開しない
+        assert_eq!(
+            config.replacements["{literal}"].to,
+            "$CLIPBOARD_FORMATTER_TEST_USER"
+        );
+        assert_eq!(config.replacement_list[0].1.to, "alice");
+    }
+
+    #[test]
+    fn test_replacement_interpret_escapes() {
+        let config = AppConfig::from_toml(
+            r#"
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+            "。" = { to = "。\\n", interpret_escapes = true }
+            "|" = { to = '\t', interpret_escapes = true }
+            "＼" = { to = '\\ \x', interpret_escapes = true }
+            "{literal}" = "\\n"
+
+            [[replacement]]
+            from = "<br>"
+            to = '\n'
+            interpret_escapes = true
+            "#,
+        )
+        .unwrap();
+
+        // 設定ファイル上の"\\n"は改行になる
+        assert_eq!(config.replacements["。"].to, "。\n");
+        assert_eq!(config.replacements["|"].to, "\t");
+        // \\は\になり、対応していないエスケープシーケンスはそのまま残す
+        assert_eq!(config.replacements["＼"].to, "\\ \\x");
+        // interpret_escapesを指定しなければ解釈しない
+        assert_eq!(config.replacements["{literal}"].to, "\\n");
+        assert_eq!(
+            format_text("一文目。二文目<br>a|b", &config).unwrap().text,
+            "一文目。\n二文目\na\tb"
+        );
+    }
+
+    #[test]
+    fn test_interpret_escape_sequences() {
+        assert_eq!(interpret_escape_sequences(r"a\nb\tc"), "a\nb\tc");
+        // \\nは\とnになる
+        assert_eq!(interpret_escape_sequences(r"\\n"), r"\n");
+        assert_eq!(interpret_escape_sequences(r"\r\"), r"\r\");
+        assert_eq!(interpret_escape_sequences("改行なし"), "改行なし");
+    }
+
+    #[test]
+    fn test_invalid_regex_replacement_is_skipped() {
+        // 不正なパターンはスキップされ、他のルールは有効なまま
+        let regex_replacements: RegexReplacements = toml::from_str(
+            r#"
+            "(unclosed" = "x"
+            "a+" = "a"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(regex_replacements.0.len(), 1);
+        let formatted = format_text(
+            "(unclosed aaa",
+            &AppConfig {
+                regex_replacements,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .text;
+        assert_eq!(formatted, "(unclosed a");
+    }
+
+    #[test]
+    fn test_config_without_regex_replacements() {
+        // [regex_replacements]がない既存の設定ファイルも読み込める
+        let config: AppConfig = toml::from_str(
+            r#"
+            exclusions = ["　"]
+
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [replacements]
+            "，" = ", "
+            "#,
+        )
+        .unwrap();
+        assert!(config.regex_replacements.0.is_empty());
+    }
+
+    #[test]
+    fn test_diff_invalid_color() {
+        assert!(toml::from_str::<DiffSettings>(r#"added_color = "pink""#).is_err());
+        assert!(toml::from_str::<DiffSettings>(r#"added_color = "32m""#).is_err());
+    }
+
+    #[test]
+    fn test_format_on_paste_config() {
+        assert_eq!(AppConfig::default().app.format_on, FormatOn::Copy);
+        let config = AppConfig::from_toml(
+            &DEFAULT_CONFIG.replace("format_on = \"copy\"", "format_on = \"paste\""),
+        )
+        .unwrap();
+        assert_eq!(config.app.format_on, FormatOn::Paste);
+        assert!(AppConfig::from_toml(
+            &DEFAULT_CONFIG.replace("format_on = \"copy\"", "format_on = \"select\"")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_change_detection_config() {
+        let config = AppConfig::from_toml(
+            &DEFAULT_CONFIG.replace("change_detection = \"hash\"", "change_detection = \"full\""),
+        )
+        .unwrap();
+        assert_eq!(config.app.change_detection, ChangeDetection::Full);
+        assert_eq!(
+            AppConfig::default().app.change_detection,
+            ChangeDetection::Hash
+        );
+    }
+
+    #[test]
+    fn test_source_config() {
+        let config = AppConfig::from_toml(
+            r#"
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+
+            [source]
+            skip = ["keepassxc"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.source.skip, ["keepassxc"]);
+        assert!(AppConfig::default().source.skip.is_empty());
+    }
+
+    #[test]
+    fn test_focus_config() {
+        let config = AppConfig::from_toml(
+            r#"
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+            remove_duplicate_previous_lines = false
+            convert_katakana = false
+            direction = "to_half"
+
+            [focus]
+            skip = ["KeePassXC"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.focus.skip, vec!["KeePassXC".to_string()]);
+        // [focus]がない場合は空
+        assert!(AppConfig::default().focus.skip.is_empty());
+    }
+
+    #[test]
+    fn test_parse_stash_original_to() {
+        let parse = |target: &str| {
+            toml::from_str::<AppSettings>(&format!(
+                "clipboard_poll_interval = 300\nconfig_reload_interval = 5000\nstash_original_to = {target:?}\n"
+            ))
+            .unwrap()
+            .stash_original_to
+        };
+        assert_eq!(parse("primary"), Some(StashTarget::Primary));
+        assert_eq!(
+            parse("/tmp/original.txt"),
+            Some(StashTarget::File(PathBuf::from("/tmp/original.txt")))
+        );
+        assert_eq!(AppSettings::default().stash_original_to, None);
+    }
+
+    #[test]
+    fn test_heartbeat_file_config() {
+        let config = AppConfig::from_toml(
+            &DEFAULT_CONFIG.replace("# heartbeat_file = ", "heartbeat_file = "),
+        )
+        .unwrap();
+        assert_eq!(
+            config.app.heartbeat_file,
+            Some(PathBuf::from(
+                "/run/user/1000/clipboard-formatter.heartbeat"
+            ))
+        );
+        assert_eq!(AppConfig::default().app.heartbeat_file, None);
+    }
+
+    #[test]
+    fn test_parse_clipboard_mode() {
+        let settings: AppSettings = toml::from_str(
+            r#"
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+            clipboard_mode = "event"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.clipboard_mode, ClipboardMode::Event);
+        assert_eq!(AppSettings::default().clipboard_mode, ClipboardMode::Poll);
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        let settings: AppSettings = toml::from_str(
+            r#"
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+            selection = "primary"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.selection, Selection::Primary);
+        assert_eq!(settings.selection.atom_name(), "PRIMARY");
+        assert_eq!(AppSettings::default().selection, Selection::Clipboard);
+        assert_eq!(Selection::Clipboard.atom_name(), "CLIPBOARD");
+
+        let error = toml::from_str::<AppSettings>(
+            r#"
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+            selection = "secondary"
+            "#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("secondary"), "{error}");
+    }
+}
@@ -0,0 +1,3404 @@
+//! 設定による文字列の整形と差分の表示
+
+use crate::config::{
+    Anchor, AppConfig, AppSettings, DiffGranularity, DiffSettings, Direction, LineEndings,
+    PunctuationMap, Script, WaveDash,
+};
+use anyhow::{Context, Result};
+use difference::{Changeset, Difference};
+use log::{debug, warn};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use unicode_normalization::UnicodeNormalization;
+
+pub(crate) const FULL_WIDTH_PATTERN: &str = r"[！-～]";
+pub(crate) const HALF_WIDTH_PATTERN: &str = r"[!-~]";
+
+static FULL_WIDTH_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(FULL_WIDTH_PATTERN).expect("Failed to create regex pattern"));
+static HALF_WIDTH_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(HALF_WIDTH_PATTERN).expect("Failed to create regex pattern"));
+
+pub(crate) fn remove_duplicate_previous_lines(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+
+    if lines.len() < 4 {
+        return text.to_string();
+    }
+
+    let first_three_lines = &lines[..3];
+
+    let previous_line_indices: Vec<usize> = first_three_lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            if line.contains("前回") {
+                Some(i)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if previous_line_indices.len() < 2 {
+        return text.to_string();
+    }
+
+    // 2番目に出現する「前回」を含む行を削除
+    let mut result_lines = lines.clone();
+    let second_index = previous_line_indices[1];
+    result_lines.remove(second_index);
+
+    result_lines.join("\n")
+}
+
+// 全角カタカナ -> 半角カタカナ（濁点・半濁点は分解してから変換する）
+const FULL_WIDTH_KATAKANA: &str =
+    "ァアィイゥウェエォオカキクケコサシスセソタチッツテトナニヌネノハヒフヘホマミムメモャヤュユョヨラリルレロワヲンー・゛゜\u{3099}\u{309A}";
+const HALF_WIDTH_KATAKANA: &str = "ｧｱｨｲｩｳｪｴｫｵｶｷｸｹｺｻｼｽｾｿﾀﾁｯﾂﾃﾄﾅﾆﾇﾈﾉﾊﾋﾌﾍﾎﾏﾐﾑﾒﾓｬﾔｭﾕｮﾖﾗﾘﾙﾚﾛﾜｦﾝｰ･ﾞﾟﾞﾟ";
+
+fn to_half_width_katakana(c: char) -> Option<char> {
+    FULL_WIDTH_KATAKANA
+        .chars()
+        .position(|full| full == c)
+        .and_then(|i| HALF_WIDTH_KATAKANA.chars().nth(i))
+}
+
+// 半角で表した文字が、数字・英字・記号のうち変換が有効な範囲に含まれるか
+fn is_range_enabled(half_width_char: char, app: &AppSettings) -> bool {
+    if half_width_char.is_ascii_digit() {
+        app.convert_digits
+    } else if half_width_char.is_ascii_alphabetic() {
+        app.convert_latin
+    } else {
+        app.convert_symbols
+    }
+}
+
+pub(crate) fn convert_punctuation(
+    text: &str,
+    punctuation: &PunctuationMap,
+    exclusion_list: &HashSet<char>,
+) -> String {
+    let mut converted = String::with_capacity(text.len());
+    for c in text.chars() {
+        match punctuation.0.get(&c) {
+            Some(to) if !exclusion_list.contains(&c) => converted.push_str(to),
+            _ => converted.push(c),
+        }
+    }
+    converted
+}
+
+pub(crate) fn convert_katakana(text: &str, exclusion_list: &HashSet<char>) -> String {
+    let mut converted = String::with_capacity(text.len());
+    for c in text.chars() {
+        if exclusion_list.contains(&c) {
+            converted.push(c);
+            continue;
+        }
+        if let Some(half_width_char) = to_half_width_katakana(c) {
+            converted.push(half_width_char);
+            continue;
+        }
+        if !('ァ'..='ヺ').contains(&c) {
+            converted.push(c);
+            continue;
+        }
+        // ガ -> カ + U+3099 のように分解し、それぞれ半角に変換できる場合のみ置き換える
+        let mut decomposed = Vec::new();
+        unicode_normalization::char::decompose_canonical(c, |d| decomposed.push(d));
+        let half_width: Option<Vec<char>> = decomposed
+            .iter()
+            .map(|d| to_half_width_katakana(*d))
+            .collect();
+        match half_width {
+            Some(chars) if decomposed.len() > 1 => converted.extend(chars),
+            _ => converted.push(c),
+        }
+    }
+    converted
+}
+
+fn strip_invisible_chars(
+    text: &str,
+    invisible_chars: &[char],
+    exclusion_list: &HashSet<char>,
+) -> String {
+    text.chars()
+        .filter(|c| !invisible_chars.contains(c) || exclusion_list.contains(c))
+        .collect()
+}
+
+// 波ダッシュと全角チルダを指定した文字に統一する。除外リストの文字はそのまま残す
+fn normalize_wave_dash(text: &str, target: WaveDash, exclusion_list: &HashSet<char>) -> String {
+    let target_char = match target {
+        WaveDash::Keep => return text.to_string(),
+        WaveDash::FullwidthTilde => FULLWIDTH_TILDE,
+        WaveDash::AsciiTilde => '~',
+    };
+    text.chars()
+        .map(|c| {
+            if matches!(c, WAVE_DASH | FULLWIDTH_TILDE) && !exclusion_list.contains(&c) {
+                target_char
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+// 除外対象の文字はそのまま残し、それ以外の部分をNFKC正規化する
+fn normalize_nfkc(text: &str, exclusion_list: &HashSet<char>) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut segment = String::new();
+    for c in text.chars() {
+        if exclusion_list.contains(&c) {
+            normalized.extend(segment.nfkc());
+            segment.clear();
+            normalized.push(c);
+        } else {
+            segment.push(c);
+        }
+    }
+    normalized.extend(segment.nfkc());
+    normalized
+}
+
+// 記号の割合がこれ以上ならコードとみなす。文章でも使う句読点や引用符は数えない
+const CODE_SYMBOLS: &str = "{}[]()<>=;&|$#*/\\_`^~%@+";
+const CODE_SYMBOL_DENSITY: f64 = 0.15;
+// 記号の割合で判定する最小の文字数（空白を除く）。短い内容は割合が偏りやすい
+const CODE_MIN_CHARS: usize = 20;
+// 空白を含まずこの長さ以上の英数字と記号の並びは、base64などのデータとみなす
+const CODE_BLOB_MIN_LEN: usize = 40;
+
+// 内容がコード・JSON・base64などに見えるかを簡単な規則で推定する。
+// 推定のため、文章をコードと判定したり、コードを見逃したりすることがある
+pub(crate) fn looks_like_code(text: &str) -> bool {
+    let text = text.trim();
+    if text.starts_with("#!") {
+        return true;
+    }
+    // JSONの値やコードのブロック全体: 括弧で始まって対応する括弧で終わる
+    if matches!(
+        (text.chars().next(), text.chars().last()),
+        (Some('{'), Some('}')) | (Some('['), Some(']'))
+    ) && brackets_balanced(text)
+    {
+        return true;
+    }
+    if text.len() >= CODE_BLOB_MIN_LEN
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+/=_-".contains(c))
+    {
+        return true;
+    }
+    // 複数行のうち半分以上が;や{、}で終わる
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect();
+    if lines.len() >= 2
+        && lines
+            .iter()
+            .filter(|line| line.ends_with([';', '{', '}']))
+            .count()
+            * 2
+            >= lines.len()
+    {
+        return true;
+    }
+    let (chars, symbols) = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .fold((0usize, 0usize), |(chars, symbols), c| {
+            (chars + 1, symbols + usize::from(CODE_SYMBOLS.contains(c)))
+        });
+    chars >= CODE_MIN_CHARS && symbols as f64 / chars as f64 >= CODE_SYMBOL_DENSITY
+}
+
+// ()、[]、{}の対応が取れているか。ダブルクォートで囲まれた文字列の中の括弧は数えない
+fn brackets_balanced(text: &str) -> bool {
+    let mut stack = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' | '[' | '{' => stack.push(c),
+            ')' | ']' | '}' => {
+                let expected = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                if stack.pop() != Some(expected) {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+    stack.is_empty() && !in_string
+}
+
+// 各行の行頭または行末に一致した場合だけ1回置換する。置換した行数も返す。
+// 保護した範囲の間を整形する場合、textの先頭と末尾は実際の行頭・行末とは限らないため、
+// starts_lineとends_lineがfalseならその位置では一致させない
+fn replace_anchored(
+    text: &str,
+    original: &str,
+    to: &str,
+    anchor: Anchor,
+    case_insensitive: bool,
+    starts_line: bool,
+    ends_line: bool,
+) -> (String, usize) {
+    // ASCIIの大文字・小文字を区別しない場合もバイト数は変わらないため、同じ長さの部分を比べる
+    let matches = |part: &str| {
+        if case_insensitive {
+            part.eq_ignore_ascii_case(original)
+        } else {
+            part == original
+        }
+    };
+    let mut count = 0;
+    let replaced = text
+        .split_inclusive('\n')
+        .enumerate()
+        .map(|(index, line)| {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let line_ending = &line[content.len()..];
+            let stripped = match anchor {
+                Anchor::LineStart if index == 0 && !starts_line => None,
+                // 改行で終わらないのは最後の行だけ
+                Anchor::LineEnd if !line.ends_with('\n') && !ends_line => None,
+                Anchor::LineStart => content
+                    .get(..original.len())
+                    .filter(|prefix| matches(prefix))
+                    .map(|_| format!("{to}{}", &content[original.len()..])),
+                Anchor::LineEnd => content
+                    .len()
+                    .checked_sub(original.len())
+                    .and_then(|start| content.get(start..))
+                    .filter(|suffix| matches(suffix))
+                    .map(|suffix| format!("{}{to}", &content[..content.len() - suffix.len()])),
+            };
+            match stripped {
+                Some(content) if !original.is_empty() => {
+                    count += 1;
+                    format!("{content}{line_ending}")
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect();
+    (replaced, count)
+}
+
+// 前後の保護した範囲を含めた文字列に正規表現を適用し、textの中に収まる一致だけを置換する。
+// 保護した範囲の隣は内容の先頭・末尾や行頭・行末ではないため、^や$はそこで一致しない。
+// 保護した範囲にかかる一致は置換しない。置換した数も返す
+fn replace_regex_between(
+    re: &Regex,
+    replacement: &str,
+    before: &str,
+    text: &str,
+    after: &str,
+) -> (String, usize) {
+    if before.is_empty() && after.is_empty() {
+        let count = re.find_iter(text).count();
+        return (re.replace_all(text, replacement).into_owned(), count);
+    }
+    let haystack = format!("{before}{text}{after}");
+    let (start, end) = (before.len(), before.len() + text.len());
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = start;
+    let mut count = 0;
+    for caps in re.captures_iter(&haystack) {
+        let matched = caps.get(0).expect("capture group 0 is always present");
+        if matched.start() < start || matched.end() > end {
+            continue;
+        }
+        replaced.push_str(&haystack[last..matched.start()]);
+        caps.expand(replacement, &mut replaced);
+        last = matched.end();
+        count += 1;
+    }
+    replaced.push_str(&haystack[last..end]);
+    (replaced, count)
+}
+
+// ASCIIの大文字・小文字を区別せずに置換する。Unicodeの大文字・小文字（Ａとａなど）は区別する
+fn replace_case_insensitive(text: &str, original: &str, to: &str) -> Result<(String, usize)> {
+    let re = Regex::new(&format!("(?i-u:{})", regex::escape(original)))
+        .with_context(|| format!("Failed to build case-insensitive pattern for {original:?}"))?;
+    let count = re.find_iter(text).count();
+    Ok((
+        re.replace_all(text, regex::NoExpand(to)).into_owned(),
+        count,
+    ))
+}
+
+// CRLF・CRを1つの改行として扱い、指定された改行コードに揃える
+fn normalize_line_endings(text: &str, line_endings: LineEndings) -> String {
+    if line_endings == LineEndings::Keep {
+        return text.to_string();
+    }
+    let lf = text.replace("\r\n", "\n").replace('\r', "\n");
+    match line_endings {
+        LineEndings::Crlf => lf.replace('\n', "\r\n"),
+        _ => lf,
+    }
+}
+
+// 各行の末尾の空白を削除する。改行コードと全角スペースによる字下げはそのまま残す
+fn trim_trailing_whitespace_per_line(text: &str) -> String {
+    text.split_inclusive('\n')
+        .map(|line| {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let line_ending = &line[content.len()..];
+            let trimmed = content.trim_end_matches(|c: char| c.is_ascii_whitespace());
+            format!("{trimmed}{line_ending}")
+        })
+        .collect()
+}
+
+/// 整形結果
+#[derive(Debug, Default, PartialEq)]
+pub struct FormatOutcome {
+    /// 整形後の文字列
+    pub text: String,
+    /// 実際に置換が発生したルールのキー（正規表現ルールはパターン）
+    pub applied_rules: Vec<String>,
+    /// 置換ルールによって置換された箇所の数
+    pub replaced_count: usize,
+    /// 変更が発生した変換処理の名前（"全角→半角"など）
+    pub applied_conversions: Vec<String>,
+}
+
+impl FormatOutcome {
+    fn record_rule(&mut self, rule: &str, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.replaced_count += count;
+        if !self.applied_rules.iter().any(|r| r == rule) {
+            self.applied_rules.push(rule.to_string());
+        }
+    }
+
+    // 全角・半角変換などの変換処理で変更が発生した場合に記録する
+    fn record_conversion(&mut self, conversion: &str, before: &str, after: &str) {
+        if before != after && !self.applied_conversions.iter().any(|c| c == conversion) {
+            self.applied_conversions.push(conversion.to_string());
+        }
+    }
+
+    fn merge(&mut self, other: FormatOutcome) {
+        self.text = other.text;
+        self.replaced_count += other.replaced_count;
+        for rule in other.applied_rules {
+            if !self.applied_rules.contains(&rule) {
+                self.applied_rules.push(rule);
+            }
+        }
+        for conversion in other.applied_conversions {
+            if !self.applied_conversions.contains(&conversion) {
+                self.applied_conversions.push(conversion);
+            }
+        }
+    }
+}
+
+// 内部の整形結果。変わらないことが分かっている場合は、入力の文字列を複製せずにUnchangedを返し、
+// 公開APIの戻り値にするときに初めて複製する
+#[derive(Debug)]
+pub(crate) enum Formatted {
+    Unchanged,
+    Changed(FormatOutcome),
+}
+
+impl Formatted {
+    // 整形途中の文字列と記録から結果を作る。文字列が入力を借用したままならUnchanged
+    fn finish(mut outcome: FormatOutcome, current: Cow<str>) -> Self {
+        match current {
+            Cow::Borrowed(_) => Formatted::Unchanged,
+            Cow::Owned(text) => {
+                outcome.text = text;
+                Formatted::Changed(outcome)
+            }
+        }
+    }
+
+    // 変更があった場合だけ記録に加え、整形途中の文字列を結果で置き換える
+    fn apply_to<'t>(self, outcome: &mut FormatOutcome, current: &mut Cow<'t, str>) {
+        if let Formatted::Changed(formatted) = self {
+            outcome.merge(formatted);
+            *current = Cow::Owned(std::mem::take(&mut outcome.text));
+        }
+    }
+
+    fn into_outcome(self, text: &str) -> FormatOutcome {
+        match self {
+            Formatted::Unchanged => FormatOutcome {
+                text: text.to_string(),
+                ..Default::default()
+            },
+            Formatted::Changed(outcome) => outcome,
+        }
+    }
+
+    fn into_cow(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Formatted::Unchanged => Cow::Borrowed(text),
+            Formatted::Changed(outcome) => Cow::Owned(outcome.text),
+        }
+    }
+}
+
+const IDEOGRAPHIC_SPACE: char = '\u{3000}';
+const WAVE_DASH: char = '\u{301C}';
+const FULLWIDTH_TILDE: char = '\u{FF5E}';
+// 全角英数字・記号（U+FF01〜U+FF5E）と半角（U+0021〜U+007E）のコードポイントの差
+const FULL_WIDTH_OFFSET: u32 = 0xfee0;
+
+// U+FF01〜U+FF5E以外は差を引いても対応する半角文字にならないため変換しない
+fn to_half_width(c: char) -> Option<char> {
+    match c {
+        '！'..='～' => char::from_u32(c as u32 - FULL_WIDTH_OFFSET),
+        _ => None,
+    }
+}
+
+fn to_full_width(c: char) -> Option<char> {
+    match c {
+        '!'..='~' => char::from_u32(c as u32 + FULL_WIDTH_OFFSET),
+        _ => None,
+    }
+}
+
+// 整形する範囲と、整形せずにそのまま残す範囲
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+    Format(&'a str),
+    Preserved(&'a str),
+}
+
+// 保護する文字列と、区切り文字で囲まれた範囲（区切り文字を含む）の位置を調べ、
+// 整形する範囲と保護する範囲に分ける。同じ位置から複数の文字列が一致する場合は最も長いものを優先する。
+// 対になる閉じ区切り文字がない区切り文字は通常の文字として扱う
+fn split_preserved<'a>(
+    text: &'a str,
+    preserve: &[String],
+    delimiters: &[String],
+) -> Vec<Segment<'a>> {
+    let mut tokens: Vec<&str> = preserve
+        .iter()
+        .map(String::as_str)
+        .filter(|token| !token.is_empty())
+        .collect();
+    let mut delimiters: Vec<&str> = delimiters
+        .iter()
+        .map(String::as_str)
+        .filter(|delimiter| !delimiter.is_empty())
+        .collect();
+    if tokens.is_empty() && delimiters.is_empty() {
+        return vec![Segment::Format(text)];
+    }
+    tokens.sort_by_key(|token| std::cmp::Reverse(token.len()));
+    // "```"と"`"のように一方が他方を含む場合に、長い区切り文字を優先する
+    delimiters.sort_by_key(|delimiter| std::cmp::Reverse(delimiter.len()));
+
+    let mut segments = Vec::new();
+    let mut gap_start = 0;
+    let mut pos = 0;
+    while let Some(c) = text[pos..].chars().next() {
+        let rest = &text[pos..];
+        let span_len = match delimiters.iter().find(|d| rest.starts_with(**d)) {
+            Some(delimiter) => match rest[delimiter.len()..].find(delimiter) {
+                Some(end) => Some(delimiter.len() * 2 + end),
+                None => {
+                    pos += delimiter.len();
+                    continue;
+                }
+            },
+            None => tokens
+                .iter()
+                .find(|token| rest.starts_with(**token))
+                .map(|token| token.len()),
+        };
+        match span_len {
+            Some(len) => {
+                if gap_start < pos {
+                    segments.push(Segment::Format(&text[gap_start..pos]));
+                }
+                segments.push(Segment::Preserved(&rest[..len]));
+                pos += len;
+                gap_start = pos;
+            }
+            None => pos += c.len_utf8(),
+        }
+    }
+    if gap_start < text.len() {
+        segments.push(Segment::Format(&text[gap_start..]));
+    }
+    segments
+}
+
+/// 置換ルール、全角・半角変換などの設定に従って文字列を1回整形する
+///
+/// `[range]`の`lines`が指定されている場合は、その範囲の行だけを整形し、それ以外の行はそのまま残す
+pub fn format_text(text: &str, config: &AppConfig) -> Result<FormatOutcome> {
+    Ok(format_pass(text, config, &builtin_steps())?.into_outcome(text))
+}
+
+/// [`format_text`]と同じ整形を行い、整形後の文字列だけを返す
+///
+/// 整形しても変わらない場合は入力を複製せずに[`Cow::Borrowed`]で返す
+pub fn format_text_cow<'t>(text: &'t str, config: &AppConfig) -> Result<Cow<'t, str>> {
+    Ok(format_pass(text, config, &builtin_steps())?.into_cow(text))
+}
+
+fn format_pass(text: &str, config: &AppConfig, steps: &[FormatStep]) -> Result<Formatted> {
+    let Some(range) = config.range.lines else {
+        return format_whole_text(text, config, steps);
+    };
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let start = (range.start - 1).min(lines.len());
+    let end = range.end.unwrap_or(lines.len()).min(lines.len());
+    // 範囲内の行はまとめて整形し、複数行にわたる置換ルールも適用できるようにする。
+    // 末尾の改行（CRLFの場合はCRも含む）はtrimなどで削除されて次の行と繋がらないよう、
+    // 整形の対象から外して元の改行コードのまま戻す
+    let selected = lines[start..end].concat();
+    let newline = ["\r\n", "\n", "\r"]
+        .into_iter()
+        .find(|newline| selected.ends_with(newline))
+        .unwrap_or("");
+    let selected = &selected[..selected.len() - newline.len()];
+    let Formatted::Changed(mut outcome) = format_whole_text(selected, config, steps)? else {
+        return Ok(Formatted::Unchanged);
+    };
+    outcome.text = [
+        &lines[..start].concat(),
+        &outcome.text,
+        newline,
+        &lines[end..].concat(),
+    ]
+    .concat();
+    Ok(Formatted::Changed(outcome))
+}
+
+// 文字（記号・数字・空白以外）のうち、かな・漢字がこの割合以上なら日本語の文章とみなす
+const JAPANESE_SCRIPT_THRESHOLD: f64 = 0.3;
+
+// 内容の主な文字種を判定する。文字を含まない場合はNone
+fn detect_script(text: &str) -> Option<Script> {
+    let (letters, japanese) = text
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .fold((0usize, 0usize), |(letters, japanese), c| {
+            (letters + 1, japanese + usize::from(is_japanese_char(c)))
+        });
+    if letters == 0 {
+        return None;
+    }
+    if japanese as f64 / letters as f64 >= JAPANESE_SCRIPT_THRESHOLD {
+        Some(Script::Japanese)
+    } else {
+        Some(Script::Latin)
+    }
+}
+
+// ひらがな・カタカナ（半角を含む）・漢字。全角英字は含めない
+fn is_japanese_char(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'
+        | '\u{31F0}'..='\u{31FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF66}'..='\u{FF9F}'
+        | '\u{3005}'
+    )
+}
+
+// 正規表現に一致した部分を1文字ずつ変換する。現在のパターンは1文字にしか一致しないが、
+// 複数の文字に一致した場合も2文字目以降（結合文字など）を落とさずにそれぞれ変換する
+fn convert_matched_chars(matched: &str, convert: impl Fn(char) -> char) -> String {
+    let mut chars = matched.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => convert(c).to_string(),
+        _ => {
+            debug!("Width conversion matched {matched:?}, which is not a single character");
+            matched.chars().map(convert).collect()
+        }
+    }
+}
+
+// 置換ルールの対象や変換する文字がなく、整形しても変わらないことが簡単な走査で分かればtrue。
+// falseの場合も変わらないことはある（正規表現に一致しても置換結果が同じ場合など）
+fn can_skip_formatting(text: &str, config: &AppConfig) -> bool {
+    let app = &config.app;
+    let has_rule_candidate = config.replacement_rules().any(|(original, replacement)| {
+        replacement.enabled
+            && (original.is_empty()
+                || if replacement.case_insensitive {
+                    contains_ignore_ascii_case(text, original)
+                } else {
+                    text.contains(original)
+                })
+    });
+    if has_rule_candidate
+        || config
+            .regex_replacements
+            .0
+            .iter()
+            .any(|(re, _)| re.is_match(text))
+    {
+        return false;
+    }
+    if app.line_endings != LineEndings::Keep && text.contains(['\r', '\n']) {
+        return false;
+    }
+    if app.trim_trailing_lines
+        || app.trim && text.trim_matches(|c: char| c.is_ascii_whitespace()).len() != text.len()
+    {
+        return false;
+    }
+    // NFKC正規化とカタカナの変換はASCII以外の文字にしか影響しない
+    if (app.nfkc || app.convert_katakana) && !text.is_ascii() {
+        return false;
+    }
+    if app.strip_invisible && text.contains(app.invisible_chars.as_slice()) {
+        return false;
+    }
+    if app.wave_dash != WaveDash::Keep && text.contains([WAVE_DASH, FULLWIDTH_TILDE]) {
+        return false;
+    }
+    // 除外リストなどで変換しない文字も候補として扱い、その場合は通常どおり整形する
+    !text.chars().any(|c| {
+        let width_candidate = match app.direction {
+            Direction::ToHalf => to_half_width(c).is_some(),
+            Direction::ToFull => to_full_width(c).is_some(),
+        };
+        width_candidate
+            || app.convert_ideographic_space && c == IDEOGRAPHIC_SPACE
+            || app.convert_punctuation && config.punctuation.0.contains_key(&c)
+    })
+}
+
+fn contains_ignore_ascii_case(text: &str, pattern: &str) -> bool {
+    text.as_bytes()
+        .windows(pattern.len())
+        .any(|window| window.eq_ignore_ascii_case(pattern.as_bytes()))
+}
+
+fn format_whole_text(text: &str, config: &AppConfig, steps: &[FormatStep]) -> Result<Formatted> {
+    // よくある「変更なし」の場合は、置換や正規表現による変換の文字列を作らずに返す。
+    // 組み込みのステップの間に独自の変換があれば、その変換で変わるかは分からない
+    let has_custom_step = steps
+        .iter()
+        .any(|step| matches!(step, FormatStep::Custom { .. }));
+    if !has_custom_step && can_skip_formatting(text, config) {
+        return Ok(Formatted::Unchanged);
+    }
+    apply_formatting(text, config, steps).map(Formatted::Changed)
+}
+
+fn apply_formatting(text: &str, config: &AppConfig, steps: &[FormatStep]) -> Result<FormatOutcome> {
+    // 文字ごとに除外対象か調べるため、除外リストは集合にしてから使う。
+    // 一般カテゴリによる除外は、内容に含まれる文字のうち該当するものを集合に加える
+    let mut exclusion_set: HashSet<char> = config.exclusions.iter().copied().collect();
+    config
+        .exclusion_categories
+        .extend_exclusions(text, &mut exclusion_set);
+    let script = detect_script(text);
+    let segments = split_preserved(text, &config.app.preserve, &config.app.literal_delimiters);
+    let mut outcome = FormatOutcome::default();
+    let mut formatted = String::with_capacity(text.len());
+    // 保護する範囲はそのまま残し、その間の範囲だけを整形する。
+    // 前後の空白の削除は、内容全体の先頭と末尾にあたる範囲だけに行う
+    let preserved_at = |index: Option<usize>| match index.and_then(|index| segments.get(index)) {
+        Some(Segment::Preserved(preserved)) => *preserved,
+        _ => "",
+    };
+    for (index, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Preserved(preserved) => formatted.push_str(preserved),
+            Segment::Format(part) => {
+                let segment = SegmentFormatter {
+                    config,
+                    exclusion_set: &mut exclusion_set,
+                    script,
+                    at_start: index == 0,
+                    at_end: index + 1 == segments.len(),
+                    before: preserved_at(index.checked_sub(1)),
+                    after: preserved_at(Some(index + 1)),
+                    outcome: FormatOutcome::default(),
+                };
+                let part_outcome = segment.format(part, steps)?;
+                formatted.push_str(&part_outcome.text);
+                outcome.merge(part_outcome);
+            }
+        }
+    }
+    outcome.text = formatted;
+    Ok(outcome)
+}
+
+// 保護する範囲の間の1つの範囲を、ステップの順に整形する
+struct SegmentFormatter<'a> {
+    config: &'a AppConfig,
+    exclusion_set: &'a mut HashSet<char>,
+    script: Option<Script>,
+    at_start: bool,
+    at_end: bool,
+    // 前後に隣接する保護した範囲。正規表現の^、$、\bなどを内容全体と同じように判定するために使う
+    before: &'a str,
+    after: &'a str,
+    outcome: FormatOutcome,
+}
+
+impl SegmentFormatter<'_> {
+    fn format(mut self, text: &str, steps: &[FormatStep]) -> Result<FormatOutcome> {
+        let mut formatted_content = text.to_string();
+        for step in steps {
+            formatted_content = match step {
+                FormatStep::Builtin(step) => self.apply_builtin(*step, formatted_content)?,
+                FormatStep::Custom { name, transform } => {
+                    let transformed = transform(&formatted_content);
+                    self.outcome
+                        .record_conversion(name, &formatted_content, &transformed);
+                    transformed
+                }
+            };
+        }
+        self.outcome.text = formatted_content;
+        Ok(self.outcome)
+    }
+
+    fn apply_builtin(&mut self, step: BuiltinStep, formatted_content: String) -> Result<String> {
+        let config = self.config;
+        let outcome = &mut self.outcome;
+        let converted = match step {
+            BuiltinStep::StripInvisible if config.app.strip_invisible => {
+                let stripped = strip_invisible_chars(
+                    &formatted_content,
+                    &config.app.invisible_chars,
+                    self.exclusion_set,
+                );
+                outcome.record_conversion("不可視文字の削除", &formatted_content, &stripped);
+                stripped
+            }
+            BuiltinStep::WaveDash if config.app.wave_dash != WaveDash::Keep => {
+                let normalized = normalize_wave_dash(
+                    &formatted_content,
+                    config.app.wave_dash,
+                    self.exclusion_set,
+                );
+                outcome.record_conversion("波ダッシュの統一", &formatted_content, &normalized);
+                normalized
+            }
+            BuiltinStep::Replacements => {
+                let mut formatted_content = formatted_content;
+                for (original, replacement) in config.replacement_rules() {
+                    if !replacement.enabled {
+                        continue;
+                    }
+                    if replacement
+                        .when_script
+                        .is_some_and(|when_script| self.script != Some(when_script))
+                    {
+                        continue;
+                    }
+                    match replacement.anchor {
+                        Some(anchor) => {
+                            let (replaced, count) = replace_anchored(
+                                &formatted_content,
+                                original,
+                                &replacement.to,
+                                anchor,
+                                replacement.case_insensitive,
+                                self.before.is_empty() || self.before.ends_with('\n'),
+                                self.after.is_empty() || self.after.starts_with(['\r', '\n']),
+                            );
+                            outcome.record_rule(original, count);
+                            formatted_content = replaced;
+                        }
+                        None if replacement.case_insensitive && !original.is_empty() => {
+                            let (replaced, count) = replace_case_insensitive(
+                                &formatted_content,
+                                original,
+                                &replacement.to,
+                            )?;
+                            outcome.record_rule(original, count);
+                            formatted_content = replaced;
+                        }
+                        None => {
+                            outcome
+                                .record_rule(original, formatted_content.matches(original).count());
+                            formatted_content =
+                                formatted_content.replace(original, &replacement.to);
+                        }
+                    }
+                }
+                formatted_content
+            }
+            BuiltinStep::RegexReplacements => {
+                let mut formatted_content = formatted_content;
+                for (re, replacement) in config.regex_replacements.0.iter() {
+                    let (replaced, count) = replace_regex_between(
+                        re,
+                        replacement,
+                        self.before,
+                        &formatted_content,
+                        self.after,
+                    );
+                    // 一致しても置換結果が変わらない場合（"a+" = "a"に対する"a"など）は適用されたとみなさない
+                    if replaced != formatted_content {
+                        outcome.record_rule(re.as_str(), count);
+                    }
+                    formatted_content = replaced;
+                }
+                formatted_content
+            }
+            BuiltinStep::LineEndings => {
+                normalize_line_endings(&formatted_content, config.app.line_endings)
+            }
+            BuiltinStep::Nfkc => {
+                // 置換ルールで加わった文字もカテゴリによる除外の対象にする
+                config
+                    .exclusion_categories
+                    .extend_exclusions(&formatted_content, self.exclusion_set);
+                if !config.app.nfkc {
+                    return Ok(formatted_content);
+                }
+                let normalized = normalize_nfkc(&formatted_content, self.exclusion_set);
+                outcome.record_conversion("NFKC", &formatted_content, &normalized);
+                config
+                    .exclusion_categories
+                    .extend_exclusions(&normalized, self.exclusion_set);
+                normalized
+            }
+            BuiltinStep::Width => {
+                // 前のステップ（独自の変換を含む）で加わった文字もカテゴリによる除外の対象にする
+                config
+                    .exclusion_categories
+                    .extend_exclusions(&formatted_content, self.exclusion_set);
+                let exclusion_list = &*self.exclusion_set;
+                let converted = match config.app.direction {
+                    Direction::ToHalf => FULL_WIDTH_REGEX
+                        .replace_all(&formatted_content, |caps: &regex::Captures| {
+                            convert_matched_chars(&caps[0], |c| {
+                                let half_width_char = to_half_width(c).unwrap_or(c);
+                                if exclusion_list.contains(&c)
+                                    || !is_range_enabled(half_width_char, &config.app)
+                                {
+                                    c
+                                } else {
+                                    half_width_char
+                                }
+                            })
+                        })
+                        .to_string(),
+                    Direction::ToFull => HALF_WIDTH_REGEX
+                        .replace_all(&formatted_content, |caps: &regex::Captures| {
+                            convert_matched_chars(&caps[0], |c| {
+                                let full_width_char = to_full_width(c).unwrap_or(c);
+                                // 除外リストは全角・半角どちらの表記でも指定できる
+                                if exclusion_list.contains(&c)
+                                    || exclusion_list.contains(&full_width_char)
+                                    || !is_range_enabled(c, &config.app)
+                                {
+                                    c
+                                } else {
+                                    full_width_char
+                                }
+                            })
+                        })
+                        .to_string(),
+                };
+                let direction_step = match config.app.direction {
+                    Direction::ToHalf => "全角→半角",
+                    Direction::ToFull => "半角→全角",
+                };
+                outcome.record_conversion(direction_step, &formatted_content, &converted);
+                converted
+            }
+            // 全角スペース(U+3000)は[！-～]の範囲外のため個別に変換する
+            BuiltinStep::IdeographicSpace
+                if config.app.convert_ideographic_space
+                    && config.app.direction == Direction::ToHalf
+                    && !self.exclusion_set.contains(&IDEOGRAPHIC_SPACE) =>
+            {
+                let converted = formatted_content.replace(IDEOGRAPHIC_SPACE, " ");
+                outcome.record_conversion("全角スペース→半角", &formatted_content, &converted);
+                converted
+            }
+            BuiltinStep::Punctuation if config.app.convert_punctuation => {
+                let converted = convert_punctuation(
+                    &formatted_content,
+                    &config.punctuation,
+                    self.exclusion_set,
+                );
+                outcome.record_conversion("句読点→半角", &formatted_content, &converted);
+                converted
+            }
+            BuiltinStep::Katakana if config.app.convert_katakana => {
+                let converted = convert_katakana(&formatted_content, self.exclusion_set);
+                outcome.record_conversion("カタカナ→半角", &formatted_content, &converted);
+                converted
+            }
+            // 空白の削除は変換後に行い、半角に変換された空白も対象にする
+            BuiltinStep::TrimTrailingLines if config.app.trim_trailing_lines => {
+                // 保護する範囲の直前は行末ではないため、最後の改行より後ろは残す
+                let line_end = if self.at_end {
+                    formatted_content.len()
+                } else {
+                    formatted_content.rfind('\n').map_or(0, |i| i + 1)
+                };
+                format!(
+                    "{}{}",
+                    trim_trailing_whitespace_per_line(&formatted_content[..line_end]),
+                    &formatted_content[line_end..]
+                )
+            }
+            BuiltinStep::Trim if config.app.trim => {
+                let is_whitespace = |c: char| c.is_ascii_whitespace();
+                let mut trimmed = formatted_content.as_str();
+                if self.at_start {
+                    trimmed = trimmed.trim_start_matches(is_whitespace);
+                }
+                if self.at_end {
+                    trimmed = trimmed.trim_end_matches(is_whitespace);
+                }
+                trimmed.to_string()
+            }
+            // 設定で無効になっているステップ
+            _ => formatted_content,
+        };
+        Ok(converted)
+    }
+}
+
+pub(crate) fn format_clipboard_content(
+    clipboard_content: &str,
+    config: &AppConfig,
+) -> Result<FormatOutcome> {
+    Formatter::new(config).format(clipboard_content)
+}
+
+// remove_duplicate_previous_linesで内容が変わった場合に記録する変換の名前
+const DUPLICATE_PREVIOUS_LINE_STEP: &str = "前回の行の削除";
+
+// verify_idempotentの確認用に、設定による整形を繰り返さずに1回だけ行う
+fn format_single_pass(text: &str, config: &AppConfig, steps: &[FormatStep]) -> Result<Formatted> {
+    let mut outcome = FormatOutcome::default();
+    let mut current = deduplicate_previous_lines(text, config, &mut outcome);
+    format_pass(&current, config, steps)?.apply_to(&mut outcome, &mut current);
+    Ok(Formatted::finish(outcome, current))
+}
+
+// 設定による整形（置換ルール、全角・半角変換など）を結果が変わらなくなるまで繰り返す
+fn format_until_stable(text: &str, config: &AppConfig, steps: &[FormatStep]) -> Result<Formatted> {
+    let mut outcome = FormatOutcome::default();
+    let mut current = deduplicate_previous_lines(text, config, &mut outcome);
+    let max_iterations = 10;
+
+    for iteration in 1..=max_iterations {
+        let Formatted::Changed(formatted) = format_pass(&current, config, steps)? else {
+            break;
+        };
+        let stable = formatted.text == current;
+        Formatted::Changed(formatted).apply_to(&mut outcome, &mut current);
+        if stable {
+            break;
+        }
+        if iteration == max_iterations {
+            warn!("Reached maximum number of iterations");
+        }
+    }
+    Ok(Formatted::finish(outcome, current))
+}
+
+// 整形した結果にもう一度一致する置換ルール。その一致する文字列を置換後の文字列に含むルールも添える
+fn rematching_rules(formatted: &str, config: &AppConfig) -> Vec<String> {
+    let script = detect_script(formatted);
+    let mut causes = Vec::new();
+    for (original, replacement) in config.replacement_rules() {
+        if !replacement.enabled
+            || original.is_empty()
+            || replacement
+                .when_script
+                .is_some_and(|when_script| script != Some(when_script))
+        {
+            continue;
+        }
+        let matched = match replacement.anchor {
+            Some(anchor) => {
+                replace_anchored(
+                    formatted,
+                    original,
+                    &replacement.to,
+                    anchor,
+                    replacement.case_insensitive,
+                    true,
+                    true,
+                )
+                .1 > 0
+            }
+            None if replacement.case_insensitive => contains_ignore_ascii_case(formatted, original),
+            None => formatted.contains(original),
+        };
+        if !matched {
+            continue;
+        }
+        let producers: Vec<String> = config
+            .replacement_rules()
+            .filter(|(_, other)| other.enabled && other.to.contains(original))
+            .map(|(other, _)| format!("{other:?}"))
+            .collect();
+        causes.push(if producers.is_empty() {
+            format!("{original:?}")
+        } else {
+            format!("{original:?} (produced by {})", producers.join(", "))
+        });
+    }
+    for (re, replacement) in config.regex_replacements.0.iter() {
+        if re.replace_all(formatted, replacement.as_str()) != formatted {
+            causes.push(format!("{:?}", re.as_str()));
+        }
+    }
+    causes
+}
+
+// remove_duplicate_previous_linesが有効な場合に前回の行を削除する。変わらなければ入力を借用したまま返す
+fn deduplicate_previous_lines<'t>(
+    text: &'t str,
+    config: &AppConfig,
+    outcome: &mut FormatOutcome,
+) -> Cow<'t, str> {
+    if !config.app.remove_duplicate_previous_lines {
+        return Cow::Borrowed(text);
+    }
+    let deduplicated = remove_duplicate_previous_lines(text);
+    if deduplicated == text {
+        return Cow::Borrowed(text);
+    }
+    outcome.record_conversion(DUPLICATE_PREVIOUS_LINE_STEP, text, &deduplicated);
+    Cow::Owned(deduplicated)
+}
+
+/// 整形の1ステップとして登録する関数。整形途中の文字列を受け取り、変換後の文字列を返す
+pub type Transform = Box<dyn Fn(&str) -> String>;
+
+/// 設定による整形を構成する組み込みのステップ。[`Formatter::new`]はこの順に適用する
+///
+/// 設定で無効になっているステップは内容を変えない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinStep {
+    /// 不可視文字の削除（`strip_invisible`）
+    StripInvisible,
+    /// 波ダッシュと全角チルダの統一（`wave_dash`）
+    WaveDash,
+    /// 置換ルール（`[replacements]`、`[[replacement]]`、プロファイルのルール）
+    Replacements,
+    /// 正規表現による置換（`[regex_replacements]`）
+    RegexReplacements,
+    /// 改行コードの統一（`line_endings`）
+    LineEndings,
+    /// NFKC正規化（`nfkc`）
+    Nfkc,
+    /// 全角・半角変換（`direction`）
+    Width,
+    /// 全角スペースの変換（`convert_ideographic_space`）
+    IdeographicSpace,
+    /// 句読点の変換（`convert_punctuation`）
+    Punctuation,
+    /// カタカナの変換（`convert_katakana`）
+    Katakana,
+    /// 行末の空白の削除（`trim_trailing_lines`）
+    TrimTrailingLines,
+    /// 前後の空白の削除（`trim`）
+    Trim,
+}
+
+impl BuiltinStep {
+    /// 組み込みのステップを適用する順に並べたもの
+    pub const ALL: [BuiltinStep; 12] = [
+        BuiltinStep::StripInvisible,
+        BuiltinStep::WaveDash,
+        BuiltinStep::Replacements,
+        BuiltinStep::RegexReplacements,
+        BuiltinStep::LineEndings,
+        BuiltinStep::Nfkc,
+        BuiltinStep::Width,
+        BuiltinStep::IdeographicSpace,
+        BuiltinStep::Punctuation,
+        BuiltinStep::Katakana,
+        BuiltinStep::TrimTrailingLines,
+        BuiltinStep::Trim,
+    ];
+}
+
+enum FormatStep {
+    Builtin(BuiltinStep),
+    Custom { name: String, transform: Transform },
+}
+
+fn builtin_steps() -> Vec<FormatStep> {
+    BuiltinStep::ALL
+        .into_iter()
+        .map(FormatStep::Builtin)
+        .collect()
+}
+
+/// 設定による整形と、登録された変換関数を順に適用する
+///
+/// [`Formatter::new`]で作成した直後は設定による整形（[`BuiltinStep`]の各ステップ）だけを行い、
+/// 常駐アプリや`--format-string`と同じ結果になる。
+/// [`Formatter::with_transform_before`]と[`Formatter::with_transform_after`]で、
+/// 設定による整形の前後に独自の変換を追加できる。
+/// [`Formatter::with_transform_before_step`]と[`Formatter::with_transform_after_step`]では、
+/// 組み込みのステップの間に変換を追加できる。
+///
+/// ```
+/// use clipboard_formatter::{AppConfig, Formatter};
+///
+/// let config = AppConfig::from_toml(
+///     r#"
+///     [app]
+///     clipboard_poll_interval = 300
+///     config_reload_interval = 5000
+///     "#,
+/// )
+/// .unwrap();
+/// let formatter = Formatter::new(&config).with_transform_after("uppercase", |text| text.to_uppercase());
+/// assert_eq!(formatter.format("ａｂｃ").unwrap().text, "ABC");
+/// ```
+pub struct Formatter<'a> {
+    config: &'a AppConfig,
+    // 設定による整形の前に1回だけ適用する変換
+    before: Vec<FormatStep>,
+    // 組み込みのステップと、その間に追加した変換。結果が変わらなくなるまで繰り返す
+    steps: Vec<FormatStep>,
+    // 設定による整形の後に1回だけ適用する変換
+    after: Vec<FormatStep>,
+}
+
+impl<'a> Formatter<'a> {
+    /// 設定による整形だけを行うFormatterを作成する
+    pub fn new(config: &'a AppConfig) -> Self {
+        Self {
+            config,
+            before: Vec::new(),
+            steps: builtin_steps(),
+            after: Vec::new(),
+        }
+    }
+
+    /// 登録済みのすべてのステップより前に変換を追加する
+    ///
+    /// `name`は変換で内容が変わった場合に[`FormatOutcome::applied_conversions`]に記録される
+    pub fn with_transform_before(
+        mut self,
+        name: &str,
+        transform: impl Fn(&str) -> String + 'static,
+    ) -> Self {
+        self.before.insert(
+            0,
+            FormatStep::Custom {
+                name: name.to_string(),
+                transform: Box::new(transform),
+            },
+        );
+        self
+    }
+
+    /// 登録済みのすべてのステップの後に変換を追加する
+    pub fn with_transform_after(
+        mut self,
+        name: &str,
+        transform: impl Fn(&str) -> String + 'static,
+    ) -> Self {
+        self.after.push(FormatStep::Custom {
+            name: name.to_string(),
+            transform: Box::new(transform),
+        });
+        self
+    }
+
+    /// 組み込みのステップ`step`の直前に変換を追加する
+    ///
+    /// 追加した変換は組み込みのステップと同じく、保護する範囲（`preserve`）や`[range]`の外側には適用されず、
+    /// 設定による整形とともに結果が変わらなくなるまで繰り返し適用される
+    pub fn with_transform_before_step(
+        mut self,
+        step: BuiltinStep,
+        name: &str,
+        transform: impl Fn(&str) -> String + 'static,
+    ) -> Self {
+        let index = self.builtin_position(step);
+        self.steps.insert(
+            index,
+            FormatStep::Custom {
+                name: name.to_string(),
+                transform: Box::new(transform),
+            },
+        );
+        self
+    }
+
+    /// 組み込みのステップ`step`の直後に変換を追加する
+    ///
+    /// 同じ位置に複数の変換を追加した場合は、追加した順に適用される
+    pub fn with_transform_after_step(
+        mut self,
+        step: BuiltinStep,
+        name: &str,
+        transform: impl Fn(&str) -> String + 'static,
+    ) -> Self {
+        // 次の組み込みのステップの直前、つまり同じ位置に追加済みの変換の後ろに追加する
+        let start = (self.builtin_position(step) + 1).min(self.steps.len());
+        let index = self.steps[start..]
+            .iter()
+            .position(|s| matches!(s, FormatStep::Builtin(_)))
+            .map_or(self.steps.len(), |offset| start + offset);
+        self.steps.insert(
+            index,
+            FormatStep::Custom {
+                name: name.to_string(),
+                transform: Box::new(transform),
+            },
+        );
+        self
+    }
+
+    // 組み込みのステップはすべてnewで登録され、取り除かれることはない
+    fn builtin_position(&self, step: BuiltinStep) -> usize {
+        self.steps
+            .iter()
+            .position(|s| matches!(s, FormatStep::Builtin(builtin) if *builtin == step))
+            .unwrap_or(self.steps.len())
+    }
+
+    /// 登録された順にステップを適用して整形する
+    ///
+    /// 組み込みのステップとその間に追加した変換は、設定による整形として結果が変わらなくなるまで繰り返し、
+    /// その前後に追加した変換はそれぞれ1回だけ適用する
+    pub fn format(&self, text: &str) -> Result<FormatOutcome> {
+        Ok(self.apply_steps(text, false)?.into_outcome(text))
+    }
+
+    // 1回分の整形の結果をもう一度整形して変わる場合は、もう一度一致するルールを含む警告を返す。
+    // 繰り返し整形すれば収束する場合も、ルールの順序によって1回で整形しきれないことが分かる
+    pub(crate) fn idempotency_warning(&self, text: &str) -> Option<String> {
+        let first = self.apply_steps(text, true).ok()?.into_outcome(text);
+        let Formatted::Changed(second) = self.apply_steps(&first.text, true).ok()? else {
+            return None;
+        };
+        if second.text == first.text {
+            return None;
+        }
+        let rules = rematching_rules(&first.text, self.config);
+        let causes = if rules.is_empty() {
+            format!("conversions: {}", second.applied_conversions.join(", "))
+        } else {
+            format!("rules: {}", rules.join(", "))
+        };
+        Some(format!(
+            "Formatting is not idempotent: formatting the result again changes it ({causes})\n{}",
+            highlight_diff(&first.text, &second.text, &self.config.diff)
+        ))
+    }
+
+    fn apply_steps(&self, text: &str, single_pass: bool) -> Result<Formatted> {
+        let mut outcome = FormatOutcome::default();
+        let mut current = Cow::Borrowed(text);
+        apply_custom_steps(&self.before, &mut outcome, &mut current);
+        let formatted = if single_pass {
+            format_single_pass(&current, self.config, &self.steps)?
+        } else {
+            format_until_stable(&current, self.config, &self.steps)?
+        };
+        formatted.apply_to(&mut outcome, &mut current);
+        apply_custom_steps(&self.after, &mut outcome, &mut current);
+        Ok(Formatted::finish(outcome, current))
+    }
+}
+
+// 設定による整形の前後に追加した変換を1回ずつ適用する
+fn apply_custom_steps(steps: &[FormatStep], outcome: &mut FormatOutcome, current: &mut Cow<str>) {
+    for step in steps {
+        if let FormatStep::Custom { name, transform } = step {
+            let transformed = transform(current);
+            outcome.record_conversion(name, current, &transformed);
+            *current = Cow::Owned(transformed);
+        }
+    }
+}
+
+/// 差分の追加・削除・変更なしの区間の数と文字数
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize)]
+pub struct DiffStats {
+    /// 追加された区間の数
+    pub added_segments: usize,
+    /// 削除された区間の数
+    pub removed_segments: usize,
+    /// 変更のない区間の数
+    pub same_segments: usize,
+    /// 追加された文字数
+    pub added_chars: usize,
+    /// 削除された文字数
+    pub removed_chars: usize,
+    /// 変更のない文字数
+    pub same_chars: usize,
+}
+
+impl DiffStats {
+    /// 追加された文字数と削除された文字数の合計
+    pub fn changed_chars(&self) -> usize {
+        self.added_chars + self.removed_chars
+    }
+
+    // 区間は差分を取った単位で数える。文字数に区切り文字は含まない
+    fn from_changeset(changeset: &Changeset) -> Self {
+        let mut stats = Self::default();
+        for change in &changeset.diffs {
+            let (segments, chars, s) = match change {
+                Difference::Same(s) => (&mut stats.same_segments, &mut stats.same_chars, s),
+                Difference::Add(s) => (&mut stats.added_segments, &mut stats.added_chars, s),
+                Difference::Rem(s) => (&mut stats.removed_segments, &mut stats.removed_chars, s),
+            };
+            *segments += 1;
+            *chars += s.chars().count();
+        }
+        stats
+    }
+}
+
+fn diff_changeset(original: &str, formatted: &str, granularity: DiffGranularity) -> Changeset {
+    Changeset::new(original, formatted, granularity.separator())
+}
+
+/// 整形前後の文字列の文字単位の差分を取り、区間の数と文字数を数える
+///
+/// 色付きの差分の文字列を解析し直さずに、変更の量を知りたい場合に使う
+///
+/// ```
+/// let stats = clipboard_formatter::diff_stats("ＡbＣ", "AbC");
+/// assert_eq!(stats.same_segments, 1);
+/// assert_eq!(stats.changed_chars(), 4);
+/// ```
+pub fn diff_stats(original: &str, formatted: &str) -> DiffStats {
+    DiffStats::from_changeset(&diff_changeset(original, formatted, DiffGranularity::Char))
+}
+
+pub(crate) fn format_summary_json(
+    original: &str,
+    outcome: &FormatOutcome,
+    diff: &DiffSettings,
+) -> serde_json::Value {
+    let plain = DiffSettings {
+        color: false,
+        granularity: diff.granularity,
+        ..Default::default()
+    };
+    let (diff_text, stats) = highlight_diff_with_stats(original, &outcome.text, &plain);
+    serde_json::json!({
+        "changed_chars": stats.changed_chars(),
+        "diff": stats,
+        "diff_text": diff_text,
+        "applied_rules": outcome.applied_rules,
+        "replaced_count": outcome.replaced_count,
+        "bytes_before": original.len(),
+        "bytes_after": outcome.text.len(),
+    })
+}
+
+pub(crate) fn highlight_diff(original: &str, formatted: &str, diff: &DiffSettings) -> String {
+    highlight_changeset(&diff_changeset(original, formatted, diff.granularity), diff)
+}
+
+// 差分の表示と集計を1回の差分から求める。集計の区間はdiff.granularityの単位で数える
+fn highlight_diff_with_stats(
+    original: &str,
+    formatted: &str,
+    diff: &DiffSettings,
+) -> (String, DiffStats) {
+    let changeset = diff_changeset(original, formatted, diff.granularity);
+    (
+        highlight_changeset(&changeset, diff),
+        DiffStats::from_changeset(&changeset),
+    )
+}
+
+fn highlight_changeset(changeset: &Changeset, diff: &DiffSettings) -> String {
+    let mut pieces = Vec::with_capacity(changeset.diffs.len());
+    for change in &changeset.diffs {
+        let piece = match change {
+            Difference::Same(s) => s.clone(),
+            // 色を使わない場合はgit diff --word-diffと同じ記法で表す
+            Difference::Add(s) if !diff.color => format!("{{+{s}+}}"),
+            Difference::Rem(s) if !diff.color => format!("[-{s}-]"),
+            Difference::Add(s) => format!("\x1b[{}m{s}\x1b[0m", diff.added_color.0),
+            Difference::Rem(s) => format!("\x1b[{}m{s}\x1b[0m", diff.removed_color.0),
+        };
+        pieces.push(piece);
+    }
+    pieces.join(diff.granularity.separator())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        AnsiColor, ExclusionCategories, LineRange, RegexReplacements, Replacement, Replacements,
+        DEFAULT_CONFIG,
+    };
+    use crate::test_support::{ConfigBuilder, REPLACEMENT_LIST_CONFIG};
+    use indexmap::IndexMap;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    // Test for format_text
+    #[test]
+    fn test_format_text_with_replacements_exclusions() {
+        // 置換リスト
+        let replacements = IndexMap::from([
+            ("foo".to_string(), "bar".into()),
+            ("baz".to_string(), "qux".into()),
+        ]);
+
+        // 除外リスト
+        let exclusion_list = vec!['！', '？']; // 例: 全角の「！」「？」を除外
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "bar qux 1234！？"; // ！？は除外されるので変換されない
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .exclusions(&exclusion_list)
+            .build();
+        let formatted = format_text(input, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_with_replacements_without_exclusions() {
+        // 置換リスト
+        let replacements = IndexMap::from([
+            ("foo".to_string(), "bar".into()),
+            ("baz".to_string(), "qux".into()),
+        ]);
+
+        // 除外リストなし
+        let exclusion_list = vec![];
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "bar qux 1234!?"; // 全ての文字が変換される
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .exclusions(&exclusion_list)
+            .build();
+        let formatted = format_text(input, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_without_replacements_with_exclusions() {
+        // 置換リストなし
+        let replacements = Replacements::new();
+
+        // 除外リスト
+        let exclusion_list = vec!['！', '？']; // 例: 全角の「！」「？」を除外
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "foo baz 1234！？"; // ！？は除外されるので変換されない
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .exclusions(&exclusion_list)
+            .build();
+        let formatted = format_text(input, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_without_replacements_exclusions() {
+        // 置換リストなし
+        let replacements = Replacements::new();
+
+        // 除外リストなし
+        let exclusion_list = vec![];
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "foo baz 1234!?"; // 全ての文字が変換される
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .exclusions(&exclusion_list)
+            .build();
+        let formatted = format_text(input, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_with_partial_exclusions() {
+        // 置換リスト
+
+        let replacements = IndexMap::from([
+            ("foo".to_string(), "bar".into()),
+            ("baz".to_string(), "qux".into()),
+        ]);
+
+        // 部分的な除外リスト
+        let exclusion_list = vec!['！']; // 例: 全角の「！」を除外
+
+        // テストケース
+        let input = "foo baz １２３４！？";
+        let expected = "bar qux 1234！?"; // ！は変換されず、？は変換される
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .exclusions(&exclusion_list)
+            .build();
+        let formatted = format_text(input, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_repeated_calls_are_identical() {
+        // 正規表現をキャッシュしても、繰り返し呼び出した結果は変わらない
+        let replacements = IndexMap::from([("foo".to_string(), "bar".into())]);
+        let exclusion_list = vec!['！'];
+        let input = "foo baz １２３４！？";
+
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .exclusions(&exclusion_list)
+            .build();
+
+        let first = format_text(input, &config).unwrap().text;
+        for _ in 0..100 {
+            let formatted = format_text(input, &config).unwrap().text;
+            assert_eq!(formatted, first);
+        }
+        assert_eq!(first, "bar baz 1234！?");
+    }
+
+    // Tests for katakana conversion
+    #[test]
+    fn test_format_text_convert_katakana() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.convert_katakana = true)
+            .build();
+        let formatted = format_text("カタカナ", &config).unwrap().text;
+        assert_eq!(formatted, "ｶﾀｶﾅ");
+    }
+
+    #[test]
+    fn test_format_text_convert_katakana_voiced_sounds() {
+        // 濁音・半濁音は半角文字 + ﾞ/ﾟ の2文字に分解される
+        let config = ConfigBuilder::default()
+            .app(|app| app.convert_katakana = true)
+            .build();
+        let formatted = format_text("ガギグゲゴ パピプペポ ヴ", &config)
+            .unwrap()
+            .text;
+        assert_eq!(formatted, "ｶﾞｷﾞｸﾞｹﾞｺﾞ ﾊﾟﾋﾟﾌﾟﾍﾟﾎﾟ ｳﾞ");
+    }
+
+    #[test]
+    fn test_format_text_convert_katakana_long_vowel_mark() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.convert_katakana = true)
+            .build();
+        let formatted = format_text("コーヒー", &config).unwrap().text;
+        assert_eq!(formatted, "ｺｰﾋｰ");
+    }
+
+    #[test]
+    fn test_format_text_convert_katakana_mixed_with_ascii() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.convert_katakana = true)
+            .build();
+        let formatted = format_text("ＣＴでフォローアップ ２回目", &config)
+            .unwrap()
+            .text;
+        assert_eq!(formatted, "CTでﾌｫﾛｰｱｯﾌﾟ 2回目");
+    }
+
+    #[test]
+    fn test_format_text_convert_katakana_disabled() {
+        // デフォルトではカタカナは変換されない
+        let formatted = format_text("カタカナ", &AppConfig::default()).unwrap().text;
+        assert_eq!(formatted, "カタカナ");
+    }
+
+    #[test]
+    fn test_format_text_convert_katakana_with_exclusions() {
+        let mut config = ConfigBuilder::default()
+            .app(|app| app.convert_katakana = true)
+            .build();
+        config.exclusions = vec!['ー'];
+        let formatted = format_text("コーヒー", &config).unwrap().text;
+        assert_eq!(formatted, "ｺーﾋー");
+    }
+
+    // Tests for direction
+    #[test]
+    fn test_format_text_to_full() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.direction = Direction::ToFull)
+            .build();
+        let formatted = format_text("abc 123!?", &config).unwrap().text;
+        assert_eq!(formatted, "ａｂｃ １２３！？");
+    }
+
+    #[test]
+    fn test_format_text_to_full_with_exclusions() {
+        let config = ConfigBuilder::default()
+            .exclusions(&['！'])
+            .app(|app| app.direction = Direction::ToFull)
+            .build();
+        // 除外リストの全角文字に対応する半角文字は全角化されない
+        let formatted = format_text("abc!?", &config).unwrap().text;
+        assert_eq!(formatted, "ａｂｃ!？");
+    }
+
+    #[test]
+    fn test_format_text_to_full_replacements_run_first() {
+        let config = ConfigBuilder::default()
+            .replacement("foo", "bar")
+            .app(|app| app.direction = Direction::ToFull)
+            .build();
+        let formatted = format_text("foo", &config).unwrap().text;
+        assert_eq!(formatted, "ｂａｒ");
+    }
+
+    #[test]
+    fn test_format_text_round_trip() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.direction = Direction::ToFull)
+            .build();
+        let input = "Hello, World! 123 {foo} ~";
+        let widened = format_text(input, &config).unwrap().text;
+        assert_eq!(widened, "Ｈｅｌｌｏ， Ｗｏｒｌｄ！ １２３ ｛ｆｏｏ｝ ～");
+
+        let narrowed = format_text(&widened, &AppConfig::default()).unwrap().text;
+        assert_eq!(narrowed, input);
+    }
+
+    #[test]
+    fn test_format_text_with_large_exclusions() {
+        // 除外リストが大きくても、含まれる文字だけがそのまま残る
+        let mut exclusion_list: Vec<char> = ('Ａ'..='Ｚ').collect();
+        exclusion_list.extend(['！', 'ア', 'ー']);
+        let config = ConfigBuilder::default()
+            .exclusions(&exclusion_list)
+            .app(|app| {
+                app.convert_katakana = true;
+                app.nfkc = true;
+            })
+            .build();
+        let formatted = format_text("ＡＢＣａｂｃ！？アイー１２", &config).unwrap();
+        assert_eq!(formatted.text, "ＡＢＣabc！?アｲー12");
+    }
+
+    #[test]
+    fn test_format_text_with_exclusion_categories() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            exclusions = ["Ａ"]
+            exclusion_categories = ["P", "S"]
+
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+            "#,
+        )
+        .unwrap();
+        // 全角の句読点・記号はカテゴリで除外され、数字と英字は変換される。文字の除外リストとも併用できる
+        assert_eq!(
+            format_text("１２３！？（）＋＄ＡＢｃ", &config)
+                .unwrap()
+                .text,
+            "123！？（）＋＄ＡBc"
+        );
+    }
+
+    #[test]
+    fn test_format_text_with_exclusion_subcategory() {
+        // 小分類を指定した場合は該当する文字だけを除外する（Psは開き括弧、Smは数学記号）
+        let mut config = AppConfig {
+            exclusion_categories: ExclusionCategories::from_codes(&["Ps", "Sm"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_text("（１＋２）＝３！", &config).unwrap().text,
+            "（1＋2)＝3!"
+        );
+        // 半角→全角の場合も変換前の文字のカテゴリで除外する
+        config.app.direction = Direction::ToFull;
+        assert_eq!(format_text("(1+2)", &config).unwrap().text, "(１+２）");
+    }
+
+    #[test]
+    fn test_format_text_with_exclusion_range() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            exclusions = ["０-９"]
+
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+            "#,
+        )
+        .unwrap();
+        // 範囲内の文字はすべて全角のまま残り、範囲外の文字は変換される
+        assert_eq!(
+            format_text("０１２３４５６７８９ＡＺ！／：", &config)
+                .unwrap()
+                .text,
+            "０１２３４５６７８９AZ!/:"
+        );
+    }
+
+    // Tests for NFKC normalization
+    #[test]
+    fn test_format_text_nfkc() {
+        let config = ConfigBuilder::default().app(|app| app.nfkc = true).build();
+        assert_eq!(format_text("ﬁle", &config).unwrap().text, "file");
+        assert_eq!(format_text("①②③", &config).unwrap().text, "123");
+        assert_eq!(format_text("㍿", &config).unwrap().text, "株式会社");
+    }
+
+    #[test]
+    fn test_format_text_nfkc_disabled() {
+        let config = AppConfig::default();
+        assert_eq!(format_text("ﬁ①㍿", &config).unwrap().text, "ﬁ①㍿");
+    }
+
+    #[test]
+    fn test_format_text_nfkc_with_exclusions() {
+        // 除外対象の文字は正規化後も元のまま残る
+        let config = ConfigBuilder::default()
+            .exclusions(&['！', '①'])
+            .app(|app| app.nfkc = true)
+            .build();
+        assert_eq!(format_text("①ﬁ！②", &config).unwrap().text, "①fi！2");
+    }
+
+    #[test]
+    fn test_format_text_nfkc_after_replacements() {
+        // 置換ルールは正規化の前に適用される
+        let config = ConfigBuilder::default()
+            .replacement("①", "(1)")
+            .app(|app| app.nfkc = true)
+            .build();
+        assert_eq!(format_text("①②", &config).unwrap().text, "(1)2");
+    }
+
+    #[test]
+    fn test_format_text_strip_invisible() {
+        // ゼロ幅スペースとBOMは削除し、タブと改行は残す
+        let config = ConfigBuilder::default()
+            .app(|app| app.strip_invisible = true)
+            .build();
+        let outcome = format_text("\u{FEFF}foo\u{200B}bar\tbaz\r\nqux\n", &config).unwrap();
+        assert_eq!(outcome.text, "foobar\tbaz\r\nqux\n");
+        assert_eq!(outcome.applied_conversions, ["不可視文字の削除"]);
+        assert_eq!(
+            format_text("a\u{0}b\u{7F}c\u{AD}d", &config).unwrap().text,
+            "abcd"
+        );
+    }
+
+    #[test]
+    fn test_format_text_strip_invisible_disabled() {
+        let config = AppConfig::default();
+        assert_eq!(
+            format_text("a\u{200B}b", &config).unwrap().text,
+            "a\u{200B}b"
+        );
+    }
+
+    #[test]
+    fn test_format_text_strip_invisible_with_exclusions() {
+        // 除外リストの文字は削除しない
+        let config = ConfigBuilder::default()
+            .exclusions(&['\u{FEFF}'])
+            .app(|app| app.strip_invisible = true)
+            .build();
+        assert_eq!(
+            format_text("\u{FEFF}a\u{200B}b", &config).unwrap().text,
+            "\u{FEFF}ab"
+        );
+    }
+
+    #[test]
+    fn test_format_text_strip_invisible_before_replacements() {
+        // 不可視文字を削除してから置換ルールを適用する
+        let config = ConfigBuilder::default()
+            .replacement("頚", "頸")
+            .app(|app| app.strip_invisible = true)
+            .build();
+        assert_eq!(format_text("頚\u{200B}椎", &config).unwrap().text, "頸椎");
+    }
+
+    #[test]
+    fn test_format_text_wave_dash_to_fullwidth_tilde() {
+        // 全角チルダを除外リストに残すと、どちらも全角チルダになる
+        let config = ConfigBuilder::default()
+            .exclusions(&['～'])
+            .app(|app| app.wave_dash = WaveDash::FullwidthTilde)
+            .build();
+        let outcome = format_text("1〜2、3～4", &config).unwrap();
+        assert_eq!(outcome.text, "1～2、3～4");
+        assert_eq!(outcome.applied_conversions, ["波ダッシュの統一"]);
+    }
+
+    #[test]
+    fn test_format_text_wave_dash_to_ascii_tilde() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.wave_dash = WaveDash::AsciiTilde)
+            .build();
+        assert_eq!(format_text("1〜2、3～4", &config).unwrap().text, "1~2、3~4");
+        // 半角→全角の変換では、統一した半角チルダも全角になる
+        let config = ConfigBuilder::default()
+            .app(|app| {
+                app.wave_dash = WaveDash::AsciiTilde;
+                app.direction = Direction::ToFull;
+            })
+            .build();
+        assert_eq!(format_text("〜～", &config).unwrap().text, "～～");
+    }
+
+    #[test]
+    fn test_format_text_wave_dash_keep_and_exclusions() {
+        // デフォルトでは波ダッシュを変換しない
+        let config = ConfigBuilder::default()
+            .app(|app| app.wave_dash = WaveDash::Keep)
+            .build();
+        assert_eq!(format_text("1〜2", &config).unwrap().text, "1〜2");
+        // 除外リストの文字は統一しない
+        let config = ConfigBuilder::default()
+            .exclusions(&['〜', '～'])
+            .app(|app| app.wave_dash = WaveDash::AsciiTilde)
+            .build();
+        assert_eq!(format_text("〜～", &config).unwrap().text, "〜～");
+    }
+
+    #[test]
+    fn test_wave_dash_before_replacements() {
+        // 置換ルールは統一後の文字に適用される
+        let config = ConfigBuilder::default()
+            .replacement("～", "から")
+            .app(|app| app.wave_dash = WaveDash::FullwidthTilde)
+            .build();
+        assert_eq!(format_text("1〜2", &config).unwrap().text, "1から2");
+    }
+
+    // Tests for format outcome
+    #[test]
+    fn test_format_text_outcome_records_applied_rules() {
+        let replacements = IndexMap::from([
+            ("foo".to_string(), "bar".into()),
+            ("baz".to_string(), "qux".into()),
+        ]);
+        let config = ConfigBuilder::default().replacements(replacements).build();
+        let outcome = format_text("foo foo １２", &config).unwrap();
+
+        assert_eq!(outcome.text, "bar bar 12");
+        // 置換が発生しなかったルールは含まれない
+        assert_eq!(outcome.applied_rules, vec!["foo".to_string()]);
+        assert_eq!(outcome.replaced_count, 2);
+        assert_eq!(outcome.applied_conversions, vec!["全角→半角".to_string()]);
+    }
+
+    #[test]
+    fn test_format_summary_json() {
+        let replacements = IndexMap::from([("CRLF".to_string(), "。".into())]);
+        let config = ConfigBuilder::default().replacements(replacements).build();
+        let original = "所見CRLF１２";
+        let outcome = format_clipboard_content(original, &config).unwrap();
+
+        let summary = format_summary_json(original, &outcome, &config.diff);
+        assert_eq!(summary["applied_rules"], serde_json::json!(["CRLF"]));
+        assert_eq!(summary["replaced_count"], 1);
+        // "CRLF"の4文字 + "１２"の2文字が削除され、"。" + "12"の3文字が追加される
+        assert_eq!(summary["changed_chars"], 9);
+        assert_eq!(summary["diff"]["added_chars"], 3);
+        assert_eq!(summary["diff"]["removed_chars"], 6);
+        assert_eq!(summary["diff_text"], "所見[-CRLF１２-]{+。12+}");
+        assert_eq!(summary["bytes_before"], original.len());
+        assert_eq!(summary["bytes_after"], "所見。12".len());
+    }
+
+    #[test]
+    fn test_diff_stats() {
+        // "所見"が同じ、"CRLF１２"が削除、"。12"が追加
+        assert_eq!(
+            diff_stats("所見CRLF１２", "所見。12"),
+            DiffStats {
+                added_segments: 1,
+                removed_segments: 1,
+                same_segments: 1,
+                added_chars: 3,
+                removed_chars: 6,
+                same_chars: 2,
+            }
+        );
+        // 変更箇所が離れている場合は区間ごとに数える
+        let stats = diff_stats("ＡbＣ", "AbC");
+        assert_eq!(stats.added_segments, 2);
+        assert_eq!(stats.removed_segments, 2);
+        assert_eq!(stats.same_segments, 1);
+        assert_eq!(stats.changed_chars(), 4);
+        // 変更がなければ全体が1つの同じ区間
+        assert_eq!(
+            diff_stats("同じ", "同じ"),
+            DiffStats {
+                same_segments: 1,
+                same_chars: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_highlight_diff_with_stats() {
+        let diff = DiffSettings {
+            color: false,
+            granularity: DiffGranularity::Word,
+            ..Default::default()
+        };
+        // 表示と集計は同じ単位の差分から求める
+        let (text, stats) = highlight_diff_with_stats("a b c", "a x c", &diff);
+        assert_eq!(text, highlight_diff("a b c", "a x c", &diff));
+        assert_eq!(text, "a [-b-] {+x+} c");
+        assert_eq!(
+            stats,
+            DiffStats {
+                added_segments: 1,
+                removed_segments: 1,
+                same_segments: 2,
+                added_chars: 1,
+                removed_chars: 1,
+                same_chars: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_can_skip_formatting() {
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        // ASCIIだけで置換ルールの対象もない内容は整形を省略する
+        assert!(can_skip_formatting("The quick brown fox.\nline 2", &config));
+        assert!(can_skip_formatting("本日は晴れ", &config));
+        // 全角英数字や置換ルールの対象を含む場合は省略しない
+        assert!(!can_skip_formatting("ＡＢＣ", &config));
+        assert!(!can_skip_formatting("所見CRLF", &config));
+
+        let config = AppConfig {
+            app: AppSettings {
+                direction: Direction::ToFull,
+                nfkc: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(!can_skip_formatting("abc", &config));
+        assert!(!can_skip_formatting("ﬁ", &config));
+        assert!(can_skip_formatting("   ", &config));
+
+        let config = ConfigBuilder::default()
+            .replacements(IndexMap::from([(
+                "teh".to_string(),
+                Replacement {
+                    case_insensitive: true,
+                    ..Replacement::from("the")
+                },
+            )]))
+            .build();
+        assert!(!can_skip_formatting("TEH", &config));
+
+        let config = AppConfig::from_toml(
+            r#"
+            [app]
+            clipboard_poll_interval = 300
+            config_reload_interval = 5000
+            trim = true
+            line_endings = "lf"
+
+            [regex_replacements]
+            " {2,}" = " "
+            "#,
+        )
+        .unwrap();
+        assert!(can_skip_formatting("a b", &config));
+        assert!(!can_skip_formatting("a  b", &config));
+        assert!(!can_skip_formatting(" a", &config));
+        assert!(!can_skip_formatting("a\r\nb", &config));
+    }
+
+    #[test]
+    fn test_can_skip_formatting_matches_full_formatting() {
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        let inputs = [
+            "The quick brown fox jumps over the lazy dog.",
+            "本日は晴れ、明日は雨",
+            "ＡＢＣ株式会社",
+            "頚部，ＣＴ．CRLF",
+            "",
+        ];
+        // 省略する場合は、省略せずに整形した場合と同じ結果になる
+        for input in inputs {
+            let full = apply_formatting(input, &config, &builtin_steps()).unwrap();
+            if can_skip_formatting(input, &config) {
+                assert_eq!(full.text, input, "{input:?}");
+                assert_eq!(format_text(input, &config).unwrap(), full);
+            } else {
+                assert_eq!(format_text(input, &config).unwrap(), full);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unchanged_text_is_not_copied() {
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        let input = "The quick brown fox jumps over the lazy dog.";
+        assert!(matches!(
+            format_text_cow(input, &config).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        // 繰り返しの整形と独自の変換がない場合も、最後まで入力を借用したまま整形する
+        assert!(matches!(
+            Formatter::new(&config).apply_steps(input, false).unwrap(),
+            Formatted::Unchanged
+        ));
+        assert_eq!(
+            format_text_cow("ＡＢＣ", &config).unwrap(),
+            Cow::<str>::Owned("ABC".to_string())
+        );
+    }
+
+    // Tests for replacement order
+    #[test]
+    fn test_format_text_applies_replacements_in_config_order() {
+        // 前のルールの出力が次のルールの入力になる
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "a" = "b"
+            "b" = "c"
+            "#,
+        )
+        .unwrap();
+        let config = ConfigBuilder::default().replacements(replacements).build();
+        for _ in 0..20 {
+            assert_eq!(format_text("ab", &config).unwrap().text, "cc");
+        }
+
+        // 逆順に記述した場合は "b" -> "c" が先に適用される
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "b" = "c"
+            "a" = "b"
+            "#,
+        )
+        .unwrap();
+        let config = ConfigBuilder::default().replacements(replacements).build();
+        for _ in 0..20 {
+            assert_eq!(format_text("ab", &config).unwrap().text, "bc");
+        }
+    }
+
+    // Tests for full-width block boundaries
+    #[test]
+    fn test_to_half_width_boundaries() {
+        assert_eq!(to_half_width('！'), Some('!')); // U+FF01
+        assert_eq!(to_half_width('～'), Some('~')); // U+FF5E
+                                                    // 範囲外の全角形・半角形の文字は変換しない
+        assert_eq!(to_half_width('\u{ff00}'), None);
+        assert_eq!(to_half_width('｟'), None); // U+FF5F
+        assert_eq!(to_half_width('ｱ'), None);
+        assert_eq!(to_half_width('A'), None);
+    }
+
+    #[test]
+    fn test_to_full_width_boundaries() {
+        assert_eq!(to_full_width('!'), Some('！'));
+        assert_eq!(to_full_width('~'), Some('～'));
+        assert_eq!(to_full_width(' '), None);
+        assert_eq!(to_full_width('\u{7f}'), None);
+    }
+
+    #[test]
+    fn test_format_text_full_width_boundaries() {
+        let config = AppConfig::default();
+        assert_eq!(format_text("！～｟｠", &config).unwrap().text, "!~｟｠");
+    }
+
+    #[test]
+    fn test_format_text_preserves_combining_marks() {
+        let config = AppConfig::default();
+        // 全角文字に続く結合文字（アキュート・濁点）は変換せずにそのまま残す
+        assert_eq!(
+            format_text("Ａ\u{0301}Ｂ１\u{3099}ｃ", &config)
+                .unwrap()
+                .text,
+            "A\u{0301}B1\u{3099}c"
+        );
+
+        let config = ConfigBuilder::default()
+            .app(|app| app.direction = Direction::ToFull)
+            .build();
+        assert_eq!(
+            format_text("e\u{0301}1\u{20DD}", &config).unwrap().text,
+            "ｅ\u{0301}１\u{20DD}"
+        );
+    }
+
+    #[test]
+    fn test_convert_matched_chars() {
+        let to_half = |c| to_half_width(c).unwrap_or(c);
+        assert_eq!(convert_matched_chars("Ａ", to_half), "A");
+        // 複数の文字に一致した場合も2文字目以降を落とさない
+        assert_eq!(convert_matched_chars("Ａ\u{0301}Ｂ", to_half), "A\u{0301}B");
+        assert_eq!(convert_matched_chars("", to_half), "");
+    }
+
+    // Tests for preserved substrings
+    #[test]
+    fn test_preserve_protects_tokens() {
+        let config = ConfigBuilder::default()
+            .replacement("＃", "#")
+            .app(|app| app.preserve = strings(&["＃tag", "ＡＢＣ123"]))
+            .build();
+        // 保護された文字列は置換ルールや半角変換の対象にならない
+        assert_eq!(
+            format_text("＃tag ＃other ＡＢＣ123 ＡＢＣ１２３", &config)
+                .unwrap()
+                .text,
+            "＃tag #other ＡＢＣ123 ABC123"
+        );
+    }
+
+    #[test]
+    fn test_preserve_longest_match_first() {
+        let config = ConfigBuilder::default()
+            .replacement("＃", "#")
+            .app(|app| app.preserve = strings(&["ＡＢ", "ＡＢＣ"]))
+            .build();
+        assert_eq!(
+            format_text("ＡＢＣＤ ＡＢＤ", &config).unwrap().text,
+            "ＡＢＣD ＡＢD"
+        );
+
+        // 重なる場合は先に現れたものを優先し、その位置で最も長いものを保護する
+        let config = ConfigBuilder::default()
+            .replacement("＃", "#")
+            .app(|app| app.preserve = strings(&["ＢＣ", "ＡＢ"]))
+            .build();
+        assert_eq!(format_text("ＡＢＣ", &config).unwrap().text, "ＡＢC");
+    }
+
+    #[test]
+    fn test_preserve_with_multiple_passes() {
+        let config = ConfigBuilder::default()
+            .replacement("＃", "#")
+            .app(|app| app.preserve = strings(&["ＡＢＣ"]))
+            .build();
+        // 安定するまで繰り返し整形しても保護された文字列は変わらない
+        let outcome = format_clipboard_content("ＡＢＣ，ＡＢＣ", &config).unwrap();
+        assert_eq!(outcome.text, "ＡＢＣ,ＡＢＣ");
+    }
+
+    #[test]
+    fn test_preserve_keeps_private_use_chars() {
+        // 内容に含まれる私用領域の文字（Nerd Fontのアイコンなど）は保護した文字列に置き換わらない
+        let config = ConfigBuilder::default()
+            .replacement("＃", "#")
+            .app(|app| app.preserve = strings(&["ＡＢＣ"]))
+            .build();
+        assert_eq!(
+            format_text("\u{E000}ＡＢＣ\u{F8FF}１", &config)
+                .unwrap()
+                .text,
+            "\u{E000}ＡＢＣ\u{F8FF}1"
+        );
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.literal_delimiters = strings(&["`"]))
+            .build();
+        assert_eq!(
+            format_text("\u{E000} `ＡＢ` ＣＤ", &config).unwrap().text,
+            "\u{E000} `ＡＢ` CD"
+        );
+    }
+
+    #[test]
+    fn test_preserve_trims_only_outer_whitespace() {
+        // 保護した文字列の前後の空白は、内容全体の先頭・末尾や行末でなければ残す
+        let config = ConfigBuilder::default()
+            .replacement("＃", "#")
+            .app(|app| {
+                app.preserve = strings(&["ＸＸ"]);
+                app.trim = true;
+                app.trim_trailing_lines = true;
+            })
+            .build();
+        assert_eq!(
+            format_text("  ａ  ＸＸ  ｂ  \nｃ  ", &config).unwrap().text,
+            "a  ＸＸ  b\nc"
+        );
+        assert_eq!(format_text("ＸＸ  ", &config).unwrap().text, "ＸＸ");
+    }
+
+    #[test]
+    fn test_preserve_regex_anchors_see_surrounding_text() {
+        // 保護した文字列の隣は内容の先頭・末尾や行頭・行末として扱わない
+        let regex_replacements: RegexReplacements = toml::from_str(
+            r#"
+            "(?m)[ \t]+$" = ""
+            "D$" = "Q"
+            "^E" = "P"
+            "#,
+        )
+        .unwrap();
+        let mut config = AppConfig {
+            regex_replacements,
+            ..Default::default()
+        };
+        config.app.preserve = strings(&["#tag"]);
+        assert_eq!(
+            format_text("see #tag nowＡ", &config).unwrap().text,
+            "see #tag nowA"
+        );
+        assert_eq!(format_text("CD#tagEＦ", &config).unwrap().text, "CD#tagEF");
+        // 内容全体の先頭・末尾や実際の行末では従来どおり一致する
+        assert_eq!(
+            format_text("EＦ #tag  \nCD", &config).unwrap().text,
+            "PF #tag\nCQ"
+        );
+    }
+
+    // Tests for literal delimiters
+    #[test]
+    fn test_literal_delimiters_protect_balanced_spans() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.literal_delimiters = strings(&["`"]))
+            .build();
+        // 区切り文字で囲まれた範囲は区切り文字ごとそのまま残し、それ以外は整形する
+        assert_eq!(
+            format_text("ＡＢＣ，`ｌｅｔ ｘ，ｙ` ＤＥＦ `ＸＹＺ`", &config)
+                .unwrap()
+                .text,
+            "ABC, `ｌｅｔ ｘ，ｙ` DEF `ＸＹＺ`"
+        );
+        // 同じ内容の範囲が複数あっても、それぞれ元の位置に戻す
+        assert_eq!(
+            format_text("`ａ`１`ａ`", &config).unwrap().text,
+            "`ａ`1`ａ`"
+        );
+    }
+
+    #[test]
+    fn test_literal_delimiters_unbalanced() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.literal_delimiters = strings(&["`"]))
+            .build();
+        // 閉じる区切り文字がない場合は通常の文字として扱い、その後の内容も整形する
+        assert_eq!(format_text("ＡＢ `ＣＤ", &config).unwrap().text, "AB `CD");
+        assert_eq!(
+            format_text("`ＡＢ` `ＣＤ", &config).unwrap().text,
+            "`ＡＢ` `CD"
+        );
+    }
+
+    #[test]
+    fn test_literal_delimiters_prefer_longer_delimiter() {
+        // "```"で囲まれたコードブロックの中の"`"は範囲の終わりとして扱わない
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.literal_delimiters = strings(&["`", "```"]))
+            .build();
+        assert_eq!(
+            format_text("ＡＢ\n```\nｘ = `ｙ`，\n```\nＣＤ `ｚ`", &config)
+                .unwrap()
+                .text,
+            "AB\n```\nｘ = `ｙ`，\n```\nCD `ｚ`"
+        );
+    }
+
+    #[test]
+    fn test_literal_delimiters_with_preserve() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| {
+                app.literal_delimiters = strings(&["\""]);
+                app.preserve = strings(&["ＡＢＣ"]);
+            })
+            .build();
+        let outcome =
+            format_clipboard_content("ＡＢＣ \"ＤＥＦ\" ＧＨＩ，ＡＢＣ", &config).unwrap();
+        assert_eq!(outcome.text, "ＡＢＣ \"ＤＥＦ\" GHI, ＡＢＣ");
+    }
+
+    #[test]
+    fn test_literal_delimiters_regex_anchors_see_surrounding_text() {
+        // 区切り文字で囲まれた範囲の隣は行末として扱わない
+        let regex_replacements: RegexReplacements =
+            toml::from_str(r#""(?m)[ \t]+$" = """#).unwrap();
+        let mut config = AppConfig {
+            regex_replacements,
+            ..Default::default()
+        };
+        config.app.literal_delimiters = strings(&["`"]);
+        assert_eq!(
+            format_text("run `ls -l` nowＡ", &config).unwrap().text,
+            "run `ls -l` nowA"
+        );
+        assert_eq!(
+            format_text("run `ls -l`  \nｘ", &config).unwrap().text,
+            "run `ls -l`\nx"
+        );
+    }
+
+    // Tests for ideographic space conversion
+    #[test]
+    fn test_format_text_converts_only_digits() {
+        let config = ConfigBuilder::default()
+            .app(|app| {
+                app.convert_latin = false;
+                app.convert_symbols = false;
+            })
+            .build();
+        // 数字だけが半角になり、英字と記号は全角のまま残る
+        assert_eq!(
+            format_text("ＡＢＣ１２３ａｂｃ（！）", &config)
+                .unwrap()
+                .text,
+            "ＡＢＣ123ａｂｃ（！）"
+        );
+    }
+
+    #[test]
+    fn test_format_text_character_ranges() {
+        let input = "Ｘ１！";
+        let mut config = AppConfig::default();
+        assert_eq!(format_text(input, &config).unwrap().text, "X1!");
+        config.app.convert_digits = false;
+        assert_eq!(format_text(input, &config).unwrap().text, "X１!");
+        config.app.convert_latin = false;
+        assert_eq!(format_text(input, &config).unwrap().text, "Ｘ１!");
+
+        // 半角→全角でも同じ設定に従う
+        let config = ConfigBuilder::default()
+            .app(|app| {
+                app.direction = Direction::ToFull;
+                app.convert_symbols = false;
+            })
+            .build();
+        assert_eq!(format_text("X1!", &config).unwrap().text, "Ｘ１!");
+    }
+
+    #[test]
+    fn test_format_text_with_line_range() {
+        let mut config: AppConfig = toml::from_str(
+            r#"
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+
+            [range]
+            lines = "2-3"
+            "#,
+        )
+        .unwrap();
+        let input = "ＡＢＣ\n１２３\nＤＥＦ\n４５６\nＧＨＩ";
+        // 2〜3行目だけが整形され、それ以外の行はそのまま残る
+        assert_eq!(
+            format_text(input, &config).unwrap().text,
+            "ＡＢＣ\n123\nDEF\n４５６\nＧＨＩ"
+        );
+
+        // trimが有効でも範囲外の行と繋がらない
+        config.app.trim = true;
+        assert_eq!(
+            format_text("Ａ\n１ \nＢ \nＣ", &config).unwrap().text,
+            "Ａ\n1 \nB\nＣ"
+        );
+        config.app.trim = false;
+
+        // 入力より後ろの範囲は何も変更しない
+        config.range.lines = Some(LineRange {
+            start: 6,
+            end: None,
+        });
+        assert_eq!(format_text(input, &config).unwrap().text, input);
+
+        config.range.lines = None;
+        assert_eq!(
+            format_text(input, &config).unwrap().text,
+            "ABC\n123\nDEF\n456\nGHI"
+        );
+    }
+
+    #[test]
+    fn test_format_text_with_line_range_crlf() {
+        let mut config: AppConfig = toml::from_str(
+            r#"
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+            line_endings = "lf"
+
+            [range]
+            lines = "1-2"
+            "#,
+        )
+        .unwrap();
+        // 範囲の末尾のCRLFは改行コードの統一の対象にならず、空行も加わらない
+        assert_eq!(
+            format_text("ａ\r\nｂ\r\nｃ\r\n", &config).unwrap().text,
+            "a\nb\r\nｃ\r\n"
+        );
+
+        // trimが有効でも範囲の末尾の改行コードは変わらない
+        config.app.line_endings = LineEndings::Keep;
+        config.app.trim = true;
+        assert_eq!(
+            format_text("ａ \r\nｂ \r\nｃ\r\n", &config).unwrap().text,
+            "a \r\nb\r\nｃ\r\n"
+        );
+    }
+
+    #[test]
+    fn test_convert_punctuation() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.convert_punctuation = true)
+            .build();
+        // 対応表にある約物だけが変換され、それ以外の文字は変わらない
+        assert_eq!(
+            format_text("今日は、「ABC」を使う。Done, ok.・〜", &config)
+                .unwrap()
+                .text,
+            "今日は,\"ABC\"を使う.Done, ok.・〜"
+        );
+    }
+
+    #[test]
+    fn test_convert_punctuation_disabled_by_default() {
+        let config = AppConfig::default();
+        assert_eq!(format_text("あ、い。", &config).unwrap().text, "あ、い。");
+    }
+
+    #[test]
+    fn test_convert_punctuation_respects_exclusions() {
+        let config = ConfigBuilder::default()
+            .exclusions(&['。'])
+            .app(|app| app.convert_punctuation = true)
+            .build();
+        assert_eq!(format_text("あ、い。", &config).unwrap().text, "あ,い。");
+    }
+
+    #[test]
+    fn test_convert_punctuation_with_custom_table() {
+        let mut config = AppConfig::from_toml(
+            r#"
+            [app]
+            clipboard_poll_interval = 1000
+            config_reload_interval = 5000
+            remove_duplicate_previous_lines = false
+            convert_katakana = false
+            direction = "to_half"
+            convert_punctuation = true
+
+            [punctuation]
+            "、" = ", "
+            "。" = ". "
+            "#,
+        )
+        .unwrap();
+        // [punctuation]を記述した場合はデフォルトの対応表を使わない
+        assert_eq!(
+            format_text("あ、い。「う」", &config).unwrap().text,
+            "あ, い. 「う」"
+        );
+        config.app.convert_punctuation = false;
+        assert_eq!(format_text("あ、い。", &config).unwrap().text, "あ、い。");
+    }
+
+    #[test]
+    fn test_convert_ideographic_space() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.convert_ideographic_space = true)
+            .build();
+        // 全角・半角スペースが混在していても1文字ずつ変換され、空白が増えない
+        assert_eq!(
+            format_text("全角　スペース と　半角 ", &config)
+                .unwrap()
+                .text,
+            "全角 スペース と 半角 "
+        );
+        assert_eq!(format_text("Ａ　 Ｂ", &config).unwrap().text, "A  B");
+    }
+
+    #[test]
+    fn test_convert_ideographic_space_disabled_by_default() {
+        let config = AppConfig::default();
+        assert_eq!(format_text("Ａ　Ｂ", &config).unwrap().text, "A　B");
+    }
+
+    #[test]
+    fn test_convert_ideographic_space_respects_exclusions() {
+        let config = ConfigBuilder::default()
+            .exclusions(&['　'])
+            .app(|app| app.convert_ideographic_space = true)
+            .build();
+        assert_eq!(format_text("Ａ　Ｂ", &config).unwrap().text, "A　B");
+    }
+
+    // Tests for line ending normalization
+    #[test]
+    fn test_line_endings_lf() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.line_endings = LineEndings::Lf)
+            .build();
+        // CRLFは2つの改行にならず、単独のCRも改行として扱う
+        assert_eq!(
+            format_text("a\r\nb\nc\rd\r\n\r\n", &config).unwrap().text,
+            "a\nb\nc\nd\n\n"
+        );
+    }
+
+    #[test]
+    fn test_line_endings_crlf() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.line_endings = LineEndings::Crlf)
+            .build();
+        assert_eq!(
+            format_text("a\r\nb\nc\rd\r\n", &config).unwrap().text,
+            "a\r\nb\r\nc\r\nd\r\n"
+        );
+        // 繰り返し整形しても改行が増えない
+        assert_eq!(
+            format_clipboard_content("a\r\nb\n", &config).unwrap().text,
+            "a\r\nb\r\n"
+        );
+    }
+
+    #[test]
+    fn test_line_endings_keep() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.line_endings = LineEndings::Keep)
+            .build();
+        assert_eq!(
+            format_text("a\r\nb\nc\rd", &config).unwrap().text,
+            "a\r\nb\nc\rd"
+        );
+    }
+
+    // Tests for trimming
+    #[test]
+    fn test_trim_trailing_lines() {
+        let config = ConfigBuilder::default()
+            .exclusions(&['　'])
+            .app(|app| {
+                app.trim = false;
+                app.trim_trailing_lines = true;
+            })
+            .build();
+        // 行末の空白だけを削除し、行頭や行中の空白、改行コードは残す
+        assert_eq!(
+            format_text("  a  b  \r\n\tc\t\n　字下げ \n\n", &config)
+                .unwrap()
+                .text,
+            "  a  b\r\n\tc\n　字下げ\n\n"
+        );
+    }
+
+    #[test]
+    fn test_trim_surrounding_whitespace() {
+        let config = ConfigBuilder::default()
+            .exclusions(&['　'])
+            .app(|app| {
+                app.trim = true;
+                app.trim_trailing_lines = false;
+            })
+            .build();
+        assert_eq!(
+            format_text("\n\n  Ａ  \n  Ｂ  \n\n", &config).unwrap().text,
+            "A  \n  B"
+        );
+        // 全角スペースによる字下げは削除しない
+        assert_eq!(format_text("　字下げ\n", &config).unwrap().text, "　字下げ");
+    }
+
+    #[test]
+    fn test_trim_runs_after_conversion() {
+        let mut config = ConfigBuilder::default()
+            .exclusions(&['　'])
+            .app(|app| {
+                app.trim = true;
+                app.trim_trailing_lines = true;
+            })
+            .build();
+        config.exclusions.clear();
+        config.app.convert_ideographic_space = true;
+        // 半角に変換された全角スペースも削除される
+        assert_eq!(format_text("　Ａ　\nＢ　\n", &config).unwrap().text, "A\nB");
+    }
+
+    #[test]
+    fn test_replacement_note_does_not_affect_format_text() {
+        let with_note: Replacements =
+            toml::from_str(r#""teh" = { to = "the", note = "fix vendor typo" }"#).unwrap();
+        assert_eq!(with_note["teh"].note.as_deref(), Some("fix vendor typo"));
+        let with_note = ConfigBuilder::default().replacements(with_note).build();
+        let without_note = ConfigBuilder::default().replacement("teh", "the").build();
+
+        let input = "teh ＡＢＣ";
+        assert_eq!(
+            format_text(input, &with_note).unwrap(),
+            format_text(input, &without_note).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_text_replacement_list_with_duplicate_from() {
+        let config = AppConfig::from_toml(REPLACEMENT_LIST_CONFIG).unwrap();
+        // ｘ -> x -> xy -> wy の順に適用される
+        let outcome = format_text("ｘ-x", &config).unwrap();
+        assert_eq!(outcome.text, "wy-wy");
+        assert_eq!(outcome.applied_rules, vec!["ｘ", "x"]);
+        assert_eq!(outcome.replaced_count, 5);
+    }
+
+    #[test]
+    fn test_format_text_anchored_replacements() {
+        let config = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                (
+                    "> ".to_string(),
+                    Replacement {
+                        to: String::new(),
+                        enabled: true,
+                        anchor: Some(Anchor::LineStart),
+                        note: None,
+                        when_script: None,
+                        case_insensitive: false,
+                    },
+                ),
+                (
+                    "です".to_string(),
+                    Replacement {
+                        to: "だ".to_string(),
+                        enabled: true,
+                        anchor: Some(Anchor::LineEnd),
+                        note: None,
+                        when_script: None,
+                        case_insensitive: false,
+                    },
+                ),
+            ]))
+            .build();
+        // 行頭・行末に一致した場合のみ置換し、行の途中は置換しない
+        let outcome =
+            format_text("> 引用です\r\na > b です\n> > 二重引用ですです\n", &config).unwrap();
+        assert_eq!(outcome.text, "引用だ\r\na > b だ\n> 二重引用ですだ\n");
+        assert_eq!(outcome.replaced_count, 5);
+    }
+
+    #[test]
+    fn test_anchored_replacements_next_to_preserved_span() {
+        // 保護した文字列や区切り文字で囲まれた範囲の隣は行頭・行末として扱わない
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "。" = { to = "", anchor = "line_end" }
+            "> " = { to = "", anchor = "line_start" }
+            "#,
+        )
+        .unwrap();
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .app(|app| {
+                app.preserve = strings(&["#tag"]);
+                app.literal_delimiters = strings(&["`"]);
+            })
+            .build();
+        assert_eq!(
+            format_text("終わり。#tag 続き。", &config).unwrap().text,
+            "終わり。#tag 続き"
+        );
+        assert_eq!(
+            format_text("#tag> 引用\n> 引用。`x`\n`x`。", &config)
+                .unwrap()
+                .text,
+            "#tag> 引用\n引用。`x`\n`x`"
+        );
+    }
+
+    #[test]
+    fn test_detect_script() {
+        assert_eq!(detect_script("今日は晴れです"), Some(Script::Japanese));
+        assert_eq!(detect_script("Hello, world"), Some(Script::Latin));
+        // 英単語を含む日本語の文章
+        assert_eq!(
+            detect_script("Rustの所有権について"),
+            Some(Script::Japanese)
+        );
+        // 全角英字は日本語の文字として数えない
+        assert_eq!(detect_script("ＡＢＣＤＥＦ"), Some(Script::Latin));
+        assert_eq!(detect_script("123 !?"), None);
+    }
+
+    #[test]
+    fn test_format_text_when_script() {
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "," = { to = "、", when_script = "japanese" }
+            "teh" = { to = "the", when_script = "latin" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(replacements[","].when_script, Some(Script::Japanese));
+        let config = ConfigBuilder::default().replacements(replacements).build();
+
+        // 日本語の文章では日本語を条件とするルールだけを適用する
+        let outcome = format_text("今日は晴れ,明日はteh雨", &config).unwrap();
+        assert_eq!(outcome.text, "今日は晴れ、明日はteh雨");
+        assert_eq!(outcome.applied_rules, [","]);
+        // 英語の文章では適用しない
+        let outcome = format_text("Hello,teh world", &config).unwrap();
+        assert_eq!(outcome.text, "Hello,the world");
+        assert_eq!(outcome.applied_rules, ["teh"]);
+        // 文字を含まない内容では条件付きのルールを適用しない
+        assert_eq!(format_text("1,2", &config).unwrap().text, "1,2");
+    }
+
+    #[test]
+    fn test_format_text_case_insensitive_replacements() {
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "foo" = { to = "bar", case_insensitive = true }
+            "$x" = { to = "$1", case_insensitive = true }
+            "todo:" = { to = "TODO:", case_insensitive = true, anchor = "line_start" }
+            "#,
+        )
+        .unwrap();
+        assert!(replacements["foo"].case_insensitive);
+        let config = ConfigBuilder::default().replacements(replacements).build();
+
+        let outcome = format_text("FOO Foo foo fOo food", &config).unwrap();
+        assert_eq!(outcome.text, "bar bar bar bar bard");
+        assert_eq!(outcome.replaced_count, 5);
+        // 正規表現の記号はそのまま文字列として扱う
+        assert_eq!(format_text("$X", &config).unwrap().text, "$1");
+        // 行頭に限定した置換でも大文字・小文字を区別しない
+        assert_eq!(
+            format_text("Todo: a\ntodo: b todo:", &config).unwrap().text,
+            "TODO: a\nTODO: b todo:"
+        );
+    }
+
+    #[test]
+    fn test_replace_case_insensitive_ascii_only() {
+        // 全角英字などASCII以外の大文字・小文字は区別する
+        let (replaced, count) = replace_case_insensitive("Ａａ ÄÄ äb", "ａ", "x").unwrap();
+        assert_eq!(replaced, "Ａx ÄÄ äb");
+        assert_eq!(count, 1);
+        let (replaced, _) = replace_case_insensitive("ÄB äb", "äb", "x").unwrap();
+        assert_eq!(replaced, "ÄB x");
+    }
+
+    #[test]
+    fn test_format_text_skips_disabled_replacements() {
+        let replacements: Replacements = toml::from_str(
+            r#"
+            "foo" = "bar"
+            "baz" = { to = "qux", enabled = true }
+            "quux" = { to = "corge", enabled = false }
+            "#,
+        )
+        .unwrap();
+
+        // 無効化されたルールは適用されない
+        let config = ConfigBuilder::default().replacements(replacements).build();
+        let formatted = format_text("foo baz quux", &config).unwrap().text;
+        assert_eq!(formatted, "bar qux quux");
+    }
+
+    // Tests for regex replacements
+    #[test]
+    fn test_format_text_with_regex_replacements() {
+        let regex_replacements: RegexReplacements = toml::from_str(
+            r#"
+            " {2,}" = " "
+            "(?m)[ \t]+$" = ""
+            "#,
+        )
+        .unwrap();
+
+        let input = "foo   bar  \nbaz\t";
+        let expected = "foo bar\nbaz";
+        let formatted = format_text(
+            input,
+            &AppConfig {
+                regex_replacements,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_with_regex_capture_groups() {
+        let regex_replacements: RegexReplacements =
+            toml::from_str(r#""(\\d+)mm" = "$1 mm""#).unwrap();
+
+        let input = "腫瘤径は12mmです";
+        let expected = "腫瘤径は12 mmです";
+        let formatted = format_text(
+            input,
+            &AppConfig {
+                regex_replacements,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_text_regex_replacements_after_literal_replacements() {
+        // 通常の置換が先に適用され、その結果に正規表現の置換が適用される
+        let replacements = IndexMap::from([("foo".to_string(), "bar".into())]);
+        let regex_replacements: RegexReplacements = toml::from_str(r#""^bar" = "baz""#).unwrap();
+
+        let formatted = format_text(
+            "foo",
+            &AppConfig {
+                replacements,
+                regex_replacements,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .text;
+
+        assert_eq!(formatted, "baz");
+    }
+
+    // Test for highlight_diff
+    #[test]
+    fn test_diff_no_changes() {
+        let original = "This is a test.";
+        let formatted = "This is a test.";
+        let result = highlight_diff(original, formatted, &DiffSettings::default());
+        // 差分がない場合はそのままの文字列が返るはず
+        assert_eq!(result, "This is a test.");
+    }
+
+    #[test]
+    fn test_diff_with_addition() {
+        let original = "This is a test";
+        let formatted = "This is a test!";
+        let result = highlight_diff(original, formatted, &DiffSettings::default());
+        // 追加された「!」が緑色（ANSIエスケープシーケンスで囲まれている）で表示される
+        let expected = "This is a test\x1b[32m!\x1b[0m";
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_diff_with_removal() {
+        let original = "This is a test!";
+        let formatted = "This is a test";
+        let result = highlight_diff(original, formatted, &DiffSettings::default());
+        // 削除された「!」が赤色で表示される
+        let expected = "This is a test\x1b[31m!\x1b[0m";
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_diff_with_complex_changes() {
+        let original = "A string";
+        let formatted = "B string";
+        let result = highlight_diff(original, formatted, &DiffSettings::default());
+        // 変更された削除された'A'が赤色、追加された'B'が緑色で表示される
+        let expected = "\x1b[31mA\x1b[0m\x1b[32mB\x1b[0m string";
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_diff_with_configured_colors() {
+        let diff: DiffSettings = toml::from_str(
+            r#"
+            added_color = "cyan"
+            removed_color = "1;35"
+            "#,
+        )
+        .unwrap();
+        let result = highlight_diff("A string", "B string", &diff);
+        let expected = "\x1b[1;35mA\x1b[0m\x1b[36mB\x1b[0m string";
+        assert_eq!(result, expected);
+
+        let diff: DiffSettings = toml::from_str("added_color = 94").unwrap();
+        assert_eq!(diff.added_color, AnsiColor("94".to_string()));
+        assert_eq!(diff.removed_color, AnsiColor("31".to_string()));
+    }
+
+    #[test]
+    fn test_diff_without_color() {
+        let diff: DiffSettings = toml::from_str("color = false").unwrap();
+        let result = highlight_diff("A string", "B string", &diff);
+        // エスケープシーケンスを含まないプレーンテキストになる
+        assert_eq!(result, "[-A-]{+B+} string");
+        assert!(!result.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_diff_word_granularity() {
+        let original = "the quick brown fox";
+        let formatted = "the quick red fox";
+        let char_diff = highlight_diff(
+            original,
+            formatted,
+            &DiffSettings {
+                color: false,
+                ..Default::default()
+            },
+        );
+        let word_diff = highlight_diff(
+            original,
+            formatted,
+            &DiffSettings {
+                color: false,
+                granularity: DiffGranularity::Word,
+                ..Default::default()
+            },
+        );
+        // 単語単位では変更された単語全体が1つの削除・追加としてまとまる
+        assert_eq!(word_diff, "the quick [-brown-] {+red+} fox");
+        // 文字単位では共通する文字（"r"など）が残り、変更が細かく分かれる
+        assert_ne!(char_diff, word_diff);
+        assert!(char_diff.matches("[-").count() > 1);
+    }
+
+    #[test]
+    fn test_diff_line_granularity() {
+        let diff = DiffSettings {
+            color: false,
+            granularity: DiffGranularity::Line,
+            ..Default::default()
+        };
+        let result = highlight_diff("a\nb b\nc", "a\nb c\nc", &diff);
+        assert_eq!(result, "a\n[-b b-]\n{+b c+}\nc");
+    }
+
+    // Tests for remove_duplicate_previous_lines function
+    #[test]
+    fn test_user_defined_remove_duplicate_previous_lines() {
+        // 複数の「前回」が含まれる場合、2番目の「前回」を削除する
+        let input = "前回1\n前回2\n前回3\n前回4";
+        let expected = "前回1\n前回3\n前回4";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_user_defined_remove_duplicate_previous_lines_with_another_word() {
+        // 複数の「前回」が含まれる場合、2番目に出現する「前回」を削除する
+        let input = "前回1\n胸部CT\n前回2\n前回3\n前回4";
+        let expected = "前回1\n胸部CT\n前回3\n前回4";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_user_defined_remove_duplicate_previous_lines_with_no_duplicates() {
+        // 3行以下の場合は削除されない
+        let input = "前回1\n前回2\n前回3";
+        let expected = "前回1\n前回2\n前回3";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_user_defined_remove_duplicate_previous_lines_with_empty_lines() {
+        // 削除が適用されるのは3行目までに含まれる場合
+        let input = "前回1\n胸部CT\n\n前回CTで認められた";
+        let expected = "前回1\n胸部CT\n\n前回CTで認められた";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_four_lines_two_duplicates_in_first_three() {
+        let input =
+            "第1回目の内容\n前回の結果は良好でした\n前回の課題が残っています\n第4回目の内容";
+        let expected = "第1回目の内容\n前回の結果は良好でした\n第4回目の内容";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_five_lines_two_duplicates_in_first_three() {
+        let input = "第1回目の内容\n前回の結果は良好でした\n前回の課題が残っています\n第4回目の内容\n第5回目の内容";
+        let expected = "第1回目の内容\n前回の結果は良好でした\n第4回目の内容\n第5回目の内容";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_four_lines_one_duplicate() {
+        let input = "第1回目の内容\n前回の結果は良好でした\n第2回目の内容\n第4回目の内容";
+        let expected = "第1回目の内容\n前回の結果は良好でした\n第2回目の内容\n第4回目の内容";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_four_lines_no_duplicates() {
+        let input = "第1回目の内容\n第2回目の内容\n第3回目の内容\n第4回目の内容";
+        let expected = "第1回目の内容\n第2回目の内容\n第3回目の内容\n第4回目の内容";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_less_than_four_lines() {
+        let input = "第1回目の内容\n前回の結果は良好でした\n前回の課題が残っています";
+        let expected = "第1回目の内容\n前回の結果は良好でした\n前回の課題が残っています";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_empty_string() {
+        let input = "";
+        let expected = "";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_single_line() {
+        let input = "前回の結果";
+        let expected = "前回の結果";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_remove_duplicate_previous_lines_four_lines_first_and_third_with_previous() {
+        let input =
+            "前回の結果は良好でした\n第2回目の内容\n前回の課題が残っています\n第4回目の内容";
+        let expected = "前回の結果は良好でした\n第2回目の内容\n第4回目の内容";
+        let result = remove_duplicate_previous_lines(input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_process_clipboard_with_remove_duplicate_previous_lines_enabled() {
+        let config = AppConfig {
+            app: AppSettings {
+                remove_duplicate_previous_lines: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let input =
+            "第1回目の内容\n前回の結果は良好でした\n前回の課題が残っています\n第4回目の内容";
+        let expected = "第1回目の内容\n前回の結果は良好でした\n第4回目の内容";
+
+        let processed = remove_duplicate_previous_lines(input);
+        let formatted = format_text(&processed, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_process_clipboard_with_remove_duplicate_previous_lines_disabled() {
+        let config = AppConfig {
+            app: AppSettings {
+                remove_duplicate_previous_lines: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let input =
+            "第1回目の内容\n前回の結果は良好でした\n前回の課題が残っています\n第4回目の内容";
+        let expected =
+            "第1回目の内容\n前回の結果は良好でした\n前回の課題が残っています\n第4回目の内容";
+
+        let processed = if config.app.remove_duplicate_previous_lines {
+            remove_duplicate_previous_lines(input)
+        } else {
+            input.to_string()
+        };
+        let formatted = format_text(&processed, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_format_clipboard_content_applies_all_steps() {
+        let config = AppConfig {
+            app: AppSettings {
+                remove_duplicate_previous_lines: true,
+                ..Default::default()
+            },
+            replacements: IndexMap::from([("CRLF".to_string(), "。".into())]),
+            ..Default::default()
+        };
+
+        let input = "前回１\n前回２\n前回３\n所見CRLF";
+        let expected = "前回1\n前回3\n所見。";
+        let formatted = format_clipboard_content(input, &config).unwrap().text;
+
+        assert_eq!(formatted, expected);
+    }
+
+    // Tests for Formatter
+    #[test]
+    fn test_formatter_custom_uppercase_transform() {
+        let config = ConfigBuilder::default().replacement("，", ", ").build();
+        let formatter =
+            Formatter::new(&config).with_transform_after("大文字", |text| text.to_uppercase());
+        let outcome = formatter.format("ａｂｃ，ｄｅｆ").unwrap();
+        assert_eq!(outcome.text, "ABC, DEF");
+        assert_eq!(outcome.applied_conversions, ["全角→半角", "大文字"]);
+        assert_eq!(outcome.replaced_count, 1);
+
+        // 変換で内容が変わらなければ記録しない
+        let outcome = formatter.format("ABC").unwrap();
+        assert!(outcome.applied_conversions.is_empty());
+    }
+
+    #[test]
+    fn test_formatter_applies_steps_in_order() {
+        // 置換ルールは前に追加した変換の結果に適用され、後に追加した変換は置換後の文字列を受け取る
+        let config = ConfigBuilder::default().replacement("b", "c").build();
+        let formatter = Formatter::new(&config)
+            .with_transform_before("a→b", |text| text.replace('a', "b"))
+            .with_transform_before("前置き", |text| format!("a{text}"))
+            .with_transform_after("c→d", |text| text.replace('c', "d"))
+            .with_transform_after("後置き", |text| format!("{text}b"));
+        // "x" -> "ax" -> "bx" -> "cx" -> "dx" -> "dxb"
+        assert_eq!(formatter.format("x").unwrap().text, "dxb");
+    }
+
+    #[test]
+    fn test_formatter_transform_between_builtin_steps() {
+        // 置換ルールの後、全角・半角変換の前に追加した変換は、置換後で変換前の文字列を受け取る
+        let config = ConfigBuilder::default().replacement("ａ", "ｂ").build();
+        let formatter = Formatter::new(&config)
+            .with_transform_after_step(BuiltinStep::Replacements, "ｂ→ｃ", |text| {
+                text.replace('ｂ', "ｃ")
+            })
+            .with_transform_before_step(BuiltinStep::Width, "ｃ→ｄ", |text| {
+                text.replace('ｃ', "ｄ")
+            });
+        let outcome = formatter.format("ａx").unwrap();
+        // "ａx" -> "ｂx" -> "ｃx" -> "ｄx" -> "dx"
+        assert_eq!(outcome.text, "dx");
+        assert_eq!(outcome.applied_conversions, ["ｂ→ｃ", "ｃ→ｄ", "全角→半角"]);
+
+        // 全角・半角変換の後に追加した変換は、変換後の文字列を受け取る
+        let formatter = Formatter::new(&config).with_transform_after_step(
+            BuiltinStep::Width,
+            "ｄ→ｅ",
+            |text| text.replace('ｄ', "ｅ"),
+        );
+        assert_eq!(formatter.format("ｄ").unwrap().text, "d");
+    }
+
+    #[test]
+    fn test_formatter_transforms_at_same_position_keep_order() {
+        fn step_names(steps: &[FormatStep]) -> Vec<String> {
+            steps
+                .iter()
+                .map(|step| match step {
+                    FormatStep::Builtin(step) => format!("{step:?}"),
+                    FormatStep::Custom { name, .. } => name.clone(),
+                })
+                .collect()
+        }
+        let config = AppConfig::default();
+        let formatter = Formatter::new(&config)
+            .with_transform_before_step(BuiltinStep::Width, "1", |text| text.to_string())
+            .with_transform_after_step(BuiltinStep::Nfkc, "2", |text| text.to_string())
+            .with_transform_after_step(BuiltinStep::Nfkc, "3", |text| text.to_string())
+            .with_transform_after_step(BuiltinStep::Trim, "4", |text| text.to_string())
+            .with_transform_after("5", |text| text.to_string());
+        // 同じ位置に追加した変換は追加した順に並ぶ
+        assert_eq!(
+            step_names(&formatter.steps)[5..10],
+            ["Nfkc", "1", "2", "3", "Width"]
+        );
+        // 最後の組み込みのステップの後に追加した変換は、設定による整形に含まれる
+        assert_eq!(step_names(&formatter.steps).last().unwrap(), "4");
+        assert_eq!(step_names(&formatter.after), ["5"]);
+    }
+
+    #[test]
+    fn test_formatter_transform_between_builtin_steps_skips_preserved_spans() {
+        let config = ConfigBuilder::default()
+            .app(|app| app.preserve = vec!["ab".to_string()])
+            .build();
+        let formatter = Formatter::new(&config).with_transform_before_step(
+            BuiltinStep::Width,
+            "a→b",
+            |text| text.replace('a', "b"),
+        );
+        // 組み込みのステップの間の変換は、保護する範囲には適用されない
+        assert_eq!(formatter.format("a ab a").unwrap().text, "b ab b");
+    }
+
+    #[test]
+    fn test_formatter_without_transforms_matches_format_clipboard_content() {
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        let input = "ＡＢＣ，１２３！頚椎";
+        assert_eq!(
+            Formatter::new(&config).format(input).unwrap(),
+            format_clipboard_content(input, &config).unwrap()
+        );
+    }
+
+    // Tests for skip_urls
+    // Tests for skip_if_code
+    #[test]
+    fn test_looks_like_code() {
+        for code in [
+            r#"{"name": "ＡＢＣ", "tags": ["x", "{y}"], "n": 1}"#,
+            "[1, 2, 3]",
+            "#!/bin/sh\necho ＡＢＣ",
+            "SGVsbG8sIFdvcmxkISBUaGlzIGlzIGJhc2U2NCBlbmNvZGVkIHRleHQu",
+            "let x = 1;\nlet y = 2;\nprintln!(\"{x}\");",
+            "if (a && b) { return c[0] * (d + e); }",
+        ] {
+            assert!(looks_like_code(code), "{code:?}");
+        }
+        for prose in [
+            "今日は１２３円のコーヒーを飲みました。",
+            "Hello, world. This is (mostly) plain English text!",
+            "{未完成の括弧",
+            "[注] 詳しくは別紙を参照",
+            "短い",
+        ] {
+            assert!(!looks_like_code(prose), "{prose:?}");
+        }
+    }
+
+    #[test]
+    fn test_brackets_balanced() {
+        assert!(brackets_balanced(r#"{"a": [1, {"b": "}"}]}"#));
+        assert!(!brackets_balanced("{[}]"));
+        assert!(!brackets_balanced("{\"a\": 1"));
+        assert!(!brackets_balanced("\"unterminated"));
+    }
+
+    #[test]
+    fn test_idempotency_warning() {
+        // 適用するたびに長くなり、収束しないルールの組み合わせ
+        let config = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                ("A".to_string(), "B".into()),
+                ("B".to_string(), "AA".into()),
+            ]))
+            .build();
+        let warning = Formatter::new(&config).idempotency_warning("A").unwrap();
+        assert!(warning.contains("not idempotent"), "{warning}");
+        assert!(
+            warning.contains(r#"rules: "A" (produced by "B")"#),
+            "{warning}"
+        );
+
+        // 1回の整形で結果が変わらなくなるルールでは警告しない
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        assert_eq!(
+            Formatter::new(&config).idempotency_warning("ＡＢＣ，ｄ"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_idempotency_warning_for_rules_converging_in_two_passes() {
+        // 2回の整形で収束するが、"A"の置換結果に前のルールの"B"が一致するため1回では整形しきれない
+        let config = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                ("B".to_string(), "C".into()),
+                ("A".to_string(), "B".into()),
+            ]))
+            .build();
+        assert_eq!(format_clipboard_content("A", &config).unwrap().text, "C");
+        let warning = Formatter::new(&config).idempotency_warning("A").unwrap();
+        assert!(
+            warning.contains(r#"rules: "B" (produced by "A")"#),
+            "{warning}"
+        );
+
+        // 順序を入れ替えれば1回で整形できる
+        let config = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                ("A".to_string(), "B".into()),
+                ("B".to_string(), "C".into()),
+            ]))
+            .build();
+        assert_eq!(Formatter::new(&config).idempotency_warning("A"), None);
+    }
+
+    #[test]
+    fn test_idempotency_warning_includes_remove_duplicate_previous_lines() {
+        // 前回の行を削除した結果で、もう一度前回の行が重複する場合も警告する
+        let mut config = AppConfig::default();
+        config.app.remove_duplicate_previous_lines = true;
+        let warning = Formatter::new(&config)
+            .idempotency_warning("前回1\n前回2\n前回3\nx\ny")
+            .unwrap();
+        assert!(warning.contains("conversions: 前回の行の削除"), "{warning}");
+    }
+
+    #[test]
+    fn test_idempotency_warning_includes_custom_transforms() {
+        let config = AppConfig::default();
+        let formatter =
+            Formatter::new(&config).with_transform_after("append", |text| format!("{text}!"));
+        let warning = formatter.idempotency_warning("abc").unwrap();
+        assert!(warning.contains("conversions: append"), "{warning}");
+    }
+}
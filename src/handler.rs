@@ -0,0 +1,2440 @@
+//! クリップボードの変更を受け取って整形し、書き戻す処理
+
+use crate::clipboard::{
+    calculate_hash, retry_with_backoff, Clipboard, ClipboardError, ContentFingerprint,
+    SystemClipboard,
+};
+use crate::config::{AppConfig, DiffSettings, HistorySettings, Selection, StashTarget};
+use crate::format::{
+    format_clipboard_content, format_summary_json, highlight_diff, looks_like_code, FormatOutcome,
+    Formatter,
+};
+use crate::metrics::{FormatLatency, PollResult, PollStats};
+use crate::x11::{is_focus_blocked, is_source_blocked, FocusLookup, PasteOffer, SourceLookup};
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use regex::Regex;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const UNDO_STATE_FILE_NAME: &str = "undo.json";
+
+pub(crate) const URL_PATTERN: &str = r"^(?i)[a-z][a-z0-9+.-]*://\S+$";
+
+static URL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(URL_PATTERN).expect("Failed to create regex pattern"));
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct HandlerOptions {
+    pub(crate) dry_run: bool,
+    pub(crate) log_format: LogFormat,
+    pub(crate) selection: Selection,
+}
+
+// --undoで復元するための、直前に整形した内容
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+struct UndoState {
+    original: String,
+    // 整形後の内容のハッシュ。クリップボードがまだ整形後の内容のときだけ復元する
+    formatted_hash: u64,
+    // --undoで復元済み。常駐中のプロセスが復元した内容を再び整形しないようにする
+    #[serde(default)]
+    restored: bool,
+}
+
+// 保存済みの状態のうち、--undoで復元した内容を再び整形しないために必要な部分。
+// 変更を処理するたびに状態ファイルを読まないよう、ファイルの更新時刻が変わるまで使い回す
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct UndoStateCache {
+    modified: SystemTime,
+    // --undoで復元した内容のハッシュ。復元していなければNone
+    restored_hash: Option<u64>,
+}
+
+impl UndoStateCache {
+    fn new(state: &UndoState, modified: SystemTime) -> Self {
+        Self {
+            modified,
+            restored_hash: state.restored.then(|| calculate_hash(&state.original)),
+        }
+    }
+}
+
+fn undo_state_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_undo_state(path: &Path) -> Result<Option<UndoState>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(Some(
+            serde_json::from_str(&text).context("Failed to parse undo state")?,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to read undo state"),
+    }
+}
+
+// 整形前の内容にはパスワードなどが含まれうるため、所有者だけが読み書きできる一時ファイル
+// （Unixでは0600）に書き込んでから置き換える
+fn save_undo_state(path: &Path, state: &UndoState) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    let mut file = tempfile::NamedTempFile::new_in(dir).context("Failed to write undo state")?;
+    file.write_all(serde_json::to_string(state)?.as_bytes())
+        .context("Failed to write undo state")?;
+    file.persist(path).context("Failed to write undo state")?;
+    Ok(())
+}
+
+// 整形前の内容の保存先。設定ファイルのディレクトリは同期やバージョン管理の対象になりやすいため、
+// 状態を保存するディレクトリ（$XDG_STATE_HOME、なければキャッシュのディレクトリ）に置く
+pub(crate) fn default_undo_state_path() -> Option<PathBuf> {
+    let state_dir = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(dirs::state_dir)
+        .or_else(dirs::cache_dir)?;
+    Some(
+        state_dir
+            .join("clipboard-formatter")
+            .join(UNDO_STATE_FILE_NAME),
+    )
+}
+
+pub(crate) struct ClipboardHandler<C: Clipboard = SystemClipboard> {
+    pub(crate) ctx: C,
+    options: HandlerOptions,
+    undo_state_path: Option<PathBuf>,
+    last_change: Option<UndoState>,
+    undo_state_cache: Option<UndoStateCache>,
+    // 直前に書き込んだ内容のハッシュ。他のクリップボードマネージャーが再コピーしても整形し直さない
+    last_written: Option<ContentFingerprint>,
+    notification_limiter: NotificationLimiter,
+    notification_digest: NotificationDigest,
+    // stash_original_to = "primary"の場合の退避先
+    original_stash: Option<C>,
+    // 書き込みに連続して失敗した回数と、次に書き込みを試すまでの待ち時間の終わり
+    set_failures: u32,
+    set_backoff_until: Option<Instant>,
+    // 最後に整形した内容を書き込んだ時刻と、min_format_interval_msのために整形を見送った内容があるか
+    last_format_at: Option<Instant>,
+    pub(crate) format_pending: bool,
+    circuit_breaker: CircuitBreaker,
+    pub(crate) format_latency: FormatLatency,
+    // format_on = "paste"の場合に、整形後の内容を貼り付け用に用意するセレクションの所有者と、
+    // 貼り付けを待っている整形前の内容
+    paste_offer: Option<Box<dyn PasteOffer>>,
+    pending_paste: Option<String>,
+    // コピー元のアプリケーションを調べる。Noneの場合は直前に書き込んだ内容との比較だけを行う
+    source_lookup: Option<Box<dyn SourceLookup>>,
+    // focus.skipのためにフォーカス中のウィンドウを調べる。Noneの場合は調べない
+    focus_lookup: Option<Box<dyn FocusLookup>>,
+    // tee_stdout = trueの場合に整形結果を書き出す先
+    tee_output: Box<dyn Write>,
+}
+
+// 書き込みに失敗した場合にその場で再試行する回数と最初の待ち時間
+const SET_CONTENTS_RETRY_ATTEMPTS: u32 = 3;
+const SET_CONTENTS_RETRY_DELAY: Duration = Duration::from_millis(50);
+// 再試行しても失敗した場合は、連続した失敗回数に応じて書き込みを控える（最大MAX_SET_CONTENTS_BACKOFF）
+const SET_CONTENTS_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_SET_CONTENTS_BACKOFF: Duration = Duration::from_secs(60);
+const SET_FAILURE_ERROR_THRESHOLD: u32 = 3;
+
+impl ClipboardHandler {
+    pub(crate) fn new(options: HandlerOptions) -> Result<Self, ClipboardError> {
+        let ctx = SystemClipboard::new(options.selection)?;
+        Ok(Self::with_clipboard(ctx, options))
+    }
+}
+
+impl<C: Clipboard> ClipboardHandler<C> {
+    pub(crate) fn with_clipboard(ctx: C, options: HandlerOptions) -> Self {
+        Self {
+            ctx,
+            options,
+            undo_state_path: None,
+            last_change: None,
+            undo_state_cache: None,
+            last_written: None,
+            notification_limiter: NotificationLimiter::default(),
+            notification_digest: NotificationDigest::new(Instant::now()),
+            original_stash: None,
+            set_failures: 0,
+            set_backoff_until: None,
+            last_format_at: None,
+            format_pending: false,
+            circuit_breaker: CircuitBreaker::default(),
+            format_latency: FormatLatency::default(),
+            paste_offer: None,
+            pending_paste: None,
+            source_lookup: None,
+            focus_lookup: None,
+            tee_output: Box::new(std::io::stdout()),
+        }
+    }
+
+    // min_format_interval_msが経過するまでの残り時間。経過していればNone
+    pub(crate) fn format_delay(&self, config: &AppConfig) -> Option<Duration> {
+        let interval = Duration::from_millis(config.app.min_format_interval_ms);
+        let remaining = interval.checked_sub(self.last_format_at?.elapsed())?;
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    pub(crate) fn with_paste_offer(mut self, paste_offer: Box<dyn PasteOffer>) -> Self {
+        self.paste_offer = Some(paste_offer);
+        self
+    }
+
+    pub(crate) fn with_source_lookup(mut self, source_lookup: Box<dyn SourceLookup>) -> Self {
+        self.source_lookup = Some(source_lookup);
+        self
+    }
+
+    pub(crate) fn with_focus_lookup(mut self, focus_lookup: Box<dyn FocusLookup>) -> Self {
+        self.focus_lookup = Some(focus_lookup);
+        self
+    }
+
+    pub(crate) fn with_original_stash(mut self, stash: C) -> Self {
+        self.original_stash = Some(stash);
+        self
+    }
+
+    // 上書きする前の内容をstash_original_toに退避する。失敗しても整形は続ける
+    fn stash_original(&mut self, original: &str, config: &AppConfig) {
+        match &config.app.stash_original_to {
+            Some(StashTarget::Primary) => {
+                // PRIMARYを利用できない場合は--undoで復元できる
+                if let Some(stash) = &mut self.original_stash {
+                    if let Err(e) = stash.set_contents(original.to_string()) {
+                        warn!("Failed to stash original content to PRIMARY: {e}");
+                    }
+                }
+            }
+            Some(StashTarget::File(path)) => {
+                if let Err(e) = fs::write(path, original) {
+                    warn!(
+                        "Failed to stash original content to {}: {e}",
+                        path.display()
+                    );
+                }
+            }
+            None => {}
+        }
+    }
+
+    pub(crate) fn with_undo_state_path(mut self, path: PathBuf) -> Self {
+        self.undo_state_path = Some(path);
+        self
+    }
+
+    // 書き込みに続けて失敗した後の待ち時間の残り。待っていなければNone
+    pub(crate) fn write_backoff(&self) -> Option<Duration> {
+        let remaining = self
+            .set_backoff_until?
+            .saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    fn set_contents(&mut self, content: String) -> Result<(), ClipboardError> {
+        if let Some(remaining) = self.write_backoff() {
+            return Err(ClipboardError::WriteBackoff(format!(
+                "waiting {}ms after {} consecutive failures",
+                remaining.as_millis(),
+                self.set_failures
+            )));
+        }
+        let result = retry_with_backoff(
+            SET_CONTENTS_RETRY_ATTEMPTS,
+            SET_CONTENTS_RETRY_DELAY,
+            || self.ctx.set_contents(content.clone()),
+        );
+        match &result {
+            Ok(()) => {
+                self.set_failures = 0;
+                self.set_backoff_until = None;
+            }
+            Err(e) => {
+                self.set_failures += 1;
+                let backoff = SET_CONTENTS_BACKOFF
+                    .saturating_mul(2u32.saturating_pow(self.set_failures - 1))
+                    .min(MAX_SET_CONTENTS_BACKOFF);
+                self.set_backoff_until = Some(Instant::now() + backoff);
+                if self.set_failures >= SET_FAILURE_ERROR_THRESHOLD {
+                    error!(
+                        "Failed to write the clipboard {} times in a row: {e}; waiting {}s before the next write",
+                        self.set_failures,
+                        backoff.as_secs()
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    fn get_contents(&mut self) -> Result<String, ClipboardError> {
+        self.ctx.get_contents()
+    }
+
+    pub(crate) fn process_clipboard(&mut self, config: &AppConfig) -> Result<bool, ClipboardError> {
+        let clipboard_content = match self.get_contents() {
+            Ok(clipboard_content) => clipboard_content,
+            Err(ClipboardError::NonText(reason)) => {
+                info!("Skipped formatting: clipboard does not contain text ({reason})");
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        self.process_content(clipboard_content, config)
+    }
+
+    // 読み込み済みのクリップボードの内容を整形する。整形が必要な内容だった場合はtrueを返す
+    fn process_content(
+        &mut self,
+        clipboard_content: String,
+        config: &AppConfig,
+    ) -> Result<bool, ClipboardError> {
+        let defer_until_paste = self.paste_offer.is_some();
+        self.format_content(clipboard_content, config, defer_until_paste)
+    }
+
+    // defer_until_pasteがtrueの場合は書き込まず、整形後の内容を貼り付け用に用意してfalseを返す
+    fn format_content(
+        &mut self,
+        clipboard_content: String,
+        config: &AppConfig,
+        defer_until_paste: bool,
+    ) -> Result<bool, ClipboardError> {
+        if clipboard_content.trim().is_empty() {
+            return Ok(false);
+        }
+        if clipboard_content.len() > config.app.max_content_bytes {
+            warn!(
+                "Skipped formatting: clipboard content is {} bytes (max_content_bytes = {})",
+                clipboard_content.len(),
+                config.app.max_content_bytes
+            );
+            return Ok(false);
+        }
+        if !config.focus.skip.is_empty() {
+            if let Some(window) = self
+                .focus_lookup
+                .as_mut()
+                .and_then(|lookup| lookup.focused_window())
+            {
+                if is_focus_blocked(&window, &config.focus.skip) {
+                    debug!("Skipped formatting: focused window {window:?} is in the skip list");
+                    return Ok(false);
+                }
+            }
+        }
+        // 提供元が分からない場合は、直前に書き込んだ内容のハッシュによる確認だけを行う
+        if let Some(source) = self
+            .source_lookup
+            .as_ref()
+            .and_then(|lookup| lookup.source())
+        {
+            if is_source_blocked(&source, std::process::id(), &config.source.skip) {
+                debug!("Skipped formatting: clipboard content came from {source:?}");
+                return Ok(false);
+            }
+        }
+        // trigger_suffixが指定されていれば、その文字列で終わる内容だけを末尾を取り除いて整形する
+        let content = match config.app.trigger_suffix.as_deref() {
+            Some(suffix) if !suffix.is_empty() => {
+                match clipboard_content.strip_suffix(suffix) {
+                    Some(content) => content,
+                    None => {
+                        debug!("Skipped formatting: clipboard content does not end with trigger_suffix");
+                        return Ok(false);
+                    }
+                }
+            }
+            _ => clipboard_content.as_str(),
+        };
+        if config.app.skip_urls && URL_REGEX.is_match(content.trim()) {
+            debug!("Skipped formatting: clipboard content is a URL");
+            return Ok(false);
+        }
+        if config.app.skip_if_code && looks_like_code(content) {
+            debug!("Skipped formatting: clipboard content looks like code");
+            return Ok(false);
+        }
+        if self.is_restored_by_undo(&clipboard_content) {
+            info!("Skipped formatting: clipboard content was restored by --undo");
+            return Ok(false);
+        }
+        // 整形中のpanicは常駐を止めずにエラーとして扱う（続く場合はCircuitBreakerで停止する）
+        let started_at = Instant::now();
+        let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Formatter::new(config).format(content)
+        }))
+        .map_err(|panic| {
+            ClipboardError::Format(format!("formatting panicked: {}", panic_message(&*panic)))
+        })?
+        .map_err(|e| ClipboardError::Format(e.to_string()))?;
+        let elapsed = started_at.elapsed();
+        self.format_latency.record(elapsed);
+        debug!("Formatted {} bytes in {elapsed:.2?}", content.len());
+
+        if config.app.verify_idempotent {
+            if let Some(warning) = Formatter::new(config).idempotency_warning(content) {
+                warn!("{warning}");
+            }
+        }
+
+        let changed = clipboard_content != outcome.text;
+        if let Some(max_ratio) = config.app.max_shrink_ratio {
+            let ratio = shrink_ratio(&clipboard_content, &outcome.text);
+            if ratio > max_ratio {
+                warn!(
+                    "Refused to write formatted content: {:.0}% of the text would be removed (max_shrink_ratio = {max_ratio})\n{}",
+                    ratio * 100.0,
+                    highlight_diff(&clipboard_content, &outcome.text, &config.diff)
+                );
+                return Ok(false);
+            }
+        }
+        if changed && defer_until_paste && !self.options.dry_run {
+            if let Some(paste_offer) = &self.paste_offer {
+                // 用意できない場合は貼り付けを待たずに直接書き込む
+                match paste_offer.offer(outcome.text.clone()) {
+                    Ok(()) => {
+                        debug!("Deferred formatting until the content is pasted");
+                        self.pending_paste = Some(clipboard_content);
+                        return Ok(false);
+                    }
+                    Err(e) => warn!("Writing formatted content without waiting for a paste: {e}"),
+                }
+            }
+        }
+        if changed {
+            self.log_outcome(&clipboard_content, &outcome, config);
+            if !self.options.dry_run {
+                let record = history_record(&clipboard_content, &outcome);
+                let undo_state = UndoState {
+                    original: clipboard_content,
+                    formatted_hash: calculate_hash(&outcome.text),
+                    restored: false,
+                };
+                let written =
+                    ContentFingerprint::with_detection(&outcome.text, config.app.change_detection);
+                let message = notification_message(&outcome);
+                let replaced_count = outcome.replaced_count;
+                let tee_text = config.app.tee_stdout.then(|| outcome.text.clone());
+                self.set_contents(outcome.text)?;
+                // 書き込めた場合だけ退避する。書き込めなければ元の内容はクリップボードに残っている
+                self.stash_original(&undo_state.original, config);
+                if let Some(text) = tee_text {
+                    self.tee(&text);
+                }
+                self.last_format_at = Some(Instant::now());
+                self.last_written = Some(written);
+                self.remember_change(undo_state);
+                // notify_digest_minutesを指定した場合は、整形ごとに通知せず定期的にまとめて通知する
+                if config.app.notify_digest_minutes > 0 {
+                    self.notification_digest.record(replaced_count);
+                } else if config.app.notify_on_format
+                    && self.notification_limiter.allow(Instant::now())
+                {
+                    show_notification(message);
+                }
+                if let Some(history) = &config.history {
+                    if let Err(e) = append_history(history, &record) {
+                        warn!("Failed to write history: {e}");
+                    }
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    // 書き込んだ整形結果を改行で終わるように出力する。失敗しても整形は続ける
+    fn tee(&mut self, text: &str) {
+        let newline = if text.ends_with('\n') { "" } else { "\n" };
+        if let Err(e) =
+            write!(self.tee_output, "{text}{newline}").and_then(|()| self.tee_output.flush())
+        {
+            warn!("Failed to write formatted content to standard output: {e}");
+        }
+    }
+
+    // notify_digest_minutesの間隔が経過していれば、その間の整形をまとめて通知する
+    pub(crate) fn flush_notification_digest(&mut self, config: &AppConfig, now: Instant) {
+        let minutes = config.app.notify_digest_minutes;
+        if minutes == 0 {
+            return;
+        }
+        if let Some(message) = self
+            .notification_digest
+            .take_message(now, Duration::from_secs(minutes * 60))
+        {
+            show_notification(message);
+        }
+    }
+
+    // 現在の内容を整形した場合の差分（ログ用と色なし）を返す。クリップボードには書き込まない
+    fn preview(&mut self, config: &AppConfig) -> Result<Option<(String, String)>, ClipboardError> {
+        let content = self.get_contents()?;
+        let outcome = format_clipboard_content(&content, config)
+            .map_err(|e| ClipboardError::Format(e.to_string()))?;
+        if outcome.text == content {
+            return Ok(None);
+        }
+        let plain = DiffSettings {
+            color: false,
+            granularity: config.diff.granularity,
+            ..Default::default()
+        };
+        Ok(Some((
+            highlight_diff(&content, &outcome.text, &config.diff),
+            highlight_diff(&content, &outcome.text, &plain),
+        )))
+    }
+
+    fn remember_change(&mut self, state: UndoState) {
+        if let Some(path) = self.undo_state_path.clone() {
+            match save_undo_state(&path, &state) {
+                Ok(()) => self.cache_undo_state(&path, &state),
+                Err(e) => warn!("{e}"),
+            }
+        }
+        self.last_change = Some(state);
+    }
+
+    fn cache_undo_state(&mut self, path: &Path, state: &UndoState) {
+        self.undo_state_cache =
+            undo_state_modified(path).map(|modified| UndoStateCache::new(state, modified));
+    }
+
+    // 状態ファイルは更新時刻が変わった場合（--undoを実行した別のプロセスが書き込んだ場合など）だけ読み直す
+    fn is_restored_by_undo(&mut self, content: &str) -> bool {
+        let Some(path) = self.undo_state_path.clone() else {
+            return false;
+        };
+        let Some(modified) = undo_state_modified(&path) else {
+            self.undo_state_cache = None;
+            return false;
+        };
+        if self.undo_state_cache.map(|cache| cache.modified) != Some(modified) {
+            self.undo_state_cache = match load_undo_state(&path) {
+                Ok(Some(state)) => Some(UndoStateCache::new(&state, modified)),
+                // 読めない状態ファイルも更新されるまで読み直さない
+                _ => Some(UndoStateCache {
+                    modified,
+                    restored_hash: None,
+                }),
+            };
+        }
+        matches!(
+            self.undo_state_cache,
+            Some(UndoStateCache { restored_hash: Some(hash), .. }) if hash == calculate_hash(&content)
+        )
+    }
+
+    // 直前の整形前の内容を復元する。クリップボードが整形後から変わっていれば復元しない
+    pub(crate) fn undo(&mut self) -> Result<bool> {
+        let path = self
+            .undo_state_path
+            .clone()
+            .context("Undo state path is not set")?;
+        let Some(mut state) = load_undo_state(&path)? else {
+            info!("Nothing to undo");
+            return Ok(false);
+        };
+        let current = self.get_contents()?;
+        if state.restored || calculate_hash(&current) != state.formatted_hash {
+            info!("Nothing to undo: the clipboard has changed since the last format");
+            return Ok(false);
+        }
+        self.set_contents(state.original.clone())?;
+        state.restored = true;
+        save_undo_state(&path, &state)?;
+        self.cache_undo_state(&path, &state);
+        info!("Restored the clipboard contents before the last format");
+        Ok(true)
+    }
+
+    fn log_outcome(&self, original: &str, outcome: &FormatOutcome, config: &AppConfig) {
+        match self.options.log_format {
+            LogFormat::Text => {
+                let label = if self.options.dry_run {
+                    "Would format (dry run)"
+                } else {
+                    "Formatted"
+                };
+                info!(
+                    "{label}{}\n{}",
+                    describe_applied_rules(outcome, config),
+                    highlight_diff(original, &outcome.text, &config.diff)
+                );
+            }
+            LogFormat::Json => {
+                let mut summary = format_summary_json(original, outcome, &config.diff);
+                summary["dry_run"] = self.options.dry_run.into();
+                info!("{summary}");
+            }
+        }
+    }
+}
+
+// 整形によって減った文字数の割合。全角→半角でバイト数が減るのは数えないよう、文字数で比べる
+fn shrink_ratio(original: &str, formatted: &str) -> f64 {
+    let original_len = original.chars().count();
+    let formatted_len = formatted.chars().count();
+    if original_len == 0 || formatted_len >= original_len {
+        return 0.0;
+    }
+    (original_len - formatted_len) as f64 / original_len as f64
+}
+
+// 通知の最短間隔。連続してコピーした場合に通知が溜まらないようにする
+const NOTIFICATION_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct NotificationLimiter {
+    last_sent: Option<Instant>,
+}
+
+impl NotificationLimiter {
+    // 前回の通知からNOTIFICATION_MIN_INTERVAL以上経過していれば通知を許可する
+    fn allow(&mut self, now: Instant) -> bool {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.duration_since(last_sent) < NOTIFICATION_MIN_INTERVAL)
+        {
+            return false;
+        }
+        self.last_sent = Some(now);
+        true
+    }
+}
+
+// notify_digest_minutesの間の整形回数と置換回数。通知するたびにリセットする
+#[derive(Debug)]
+struct NotificationDigest {
+    formats: usize,
+    replacements: usize,
+    since: Instant,
+}
+
+impl NotificationDigest {
+    fn new(now: Instant) -> Self {
+        Self {
+            formats: 0,
+            replacements: 0,
+            since: now,
+        }
+    }
+
+    fn record(&mut self, replaced_count: usize) {
+        self.formats += 1;
+        self.replacements += replaced_count;
+    }
+
+    // 間隔が経過していれば集計をリセットし、1回以上整形していた場合だけ通知の本文を返す
+    fn take_message(&mut self, now: Instant, interval: Duration) -> Option<String> {
+        if now.duration_since(self.since) < interval {
+            return None;
+        }
+        let digest = std::mem::replace(self, Self::new(now));
+        (digest.formats > 0).then(|| digest_message(digest.formats, digest.replacements, interval))
+    }
+}
+
+fn digest_message(formats: usize, replacements: usize, interval: Duration) -> String {
+    let times = match formats {
+        1 => "1 time".to_string(),
+        count => format!("{count} times"),
+    };
+    let replacements = match replacements {
+        1 => "1 replacement".to_string(),
+        count => format!("{count} replacements"),
+    };
+    format!(
+        "Formatted clipboard {times}, {replacements} in the last {} min",
+        interval.as_secs() / 60
+    )
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+// 連続してこの回数だけ処理に失敗したら、CIRCUIT_BREAKER_COOLDOWNの間は整形を止める
+const CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+const CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+// 処理の失敗が続く場合に、ポーリングのたびに警告を出し続けないよう整形を一時停止する
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self, now: Instant) -> bool {
+        self.open_until.is_some_and(|until| now < until)
+    }
+
+    // 失敗を記録する。停止した場合はtrueを返す。停止が明けた後も失敗すればすぐに再び停止する
+    fn record_failure(&mut self, now: Instant) -> bool {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures < CIRCUIT_BREAKER_THRESHOLD {
+            return false;
+        }
+        self.open_until = Some(now + CIRCUIT_BREAKER_COOLDOWN);
+        true
+    }
+
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+}
+
+fn notification_message(outcome: &FormatOutcome) -> String {
+    let mut details = Vec::new();
+    match outcome.replaced_count {
+        0 => {}
+        1 => details.push("1 replacement".to_string()),
+        count => details.push(format!("{count} replacements")),
+    }
+    details.extend(outcome.applied_conversions.iter().cloned());
+    if details.is_empty() {
+        "Formatted clipboard".to_string()
+    } else {
+        format!("Formatted clipboard ({})", details.join(", "))
+    }
+}
+
+// 通知の表示を待たずに整形を続けられるよう、別スレッドで表示する
+fn show_notification(message: String) {
+    thread::spawn(move || {
+        if let Err(e) = notify_rust::Notification::new()
+            .summary("clipboard-formatter")
+            .body(&message)
+            .show()
+        {
+            debug!("Desktop notifications are not available: {e}");
+        }
+    });
+}
+
+// ログ用に適用されたルールと変換を" (rules: foo→bar, 全角→半角)"の形式で表す
+fn describe_applied_rules(outcome: &FormatOutcome, config: &AppConfig) -> String {
+    if outcome.applied_rules.is_empty() && outcome.applied_conversions.is_empty() {
+        return String::new();
+    }
+    let rules: Vec<String> = outcome
+        .applied_rules
+        .iter()
+        .map(|rule| {
+            if let Some((_, replacement)) = config
+                .replacement_rules()
+                .find(|(original, _)| original == rule)
+            {
+                return format!("{rule}→{}", replacement.to);
+            }
+            match config
+                .regex_replacements
+                .0
+                .iter()
+                .find(|(re, _)| re.as_str() == rule)
+            {
+                Some((_, replacement)) => format!("{rule}→{replacement}"),
+                None => rule.clone(),
+            }
+        })
+        .chain(outcome.applied_conversions.iter().cloned())
+        .collect();
+    format!(" (rules: {})", rules.join(", "))
+}
+
+fn history_record(original: &str, outcome: &FormatOutcome) -> serde_json::Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    serde_json::json!({
+        "timestamp": timestamp,
+        "bytes_before": original.len(),
+        "bytes_after": outcome.text.len(),
+        "applied_rules": outcome.applied_rules.len(),
+    })
+}
+
+// 1行1レコードで追記し、max_entriesを超えた分は古いものから削除する
+fn append_history(history: &HistorySettings, record: &serde_json::Value) -> Result<()> {
+    let existing = match fs::read_to_string(&history.file) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context("Failed to read history file"),
+    };
+    let record = record.to_string();
+    let mut lines: Vec<&str> = existing.lines().collect();
+    lines.push(&record);
+    let start = lines.len().saturating_sub(history.max_entries);
+    let mut text = lines[start..].join("\n");
+    text.push('\n');
+    fs::write(&history.file, text).context("Failed to write history file")
+}
+
+pub(crate) fn handle_clipboard_processing<C: Clipboard>(
+    clipboard_handler: &mut ClipboardHandler<C>,
+    config: &AppConfig,
+    previous: ContentFingerprint,
+    paused: bool,
+    stats: &mut PollStats,
+) -> ContentFingerprint {
+    let now = Instant::now();
+    if clipboard_handler.circuit_breaker.is_open(now) {
+        // 停止が明けた時点の内容を処理できるよう、識別情報は更新しない
+        clipboard_handler.format_pending = true;
+        stats.record(PollResult::Skipped);
+        return previous;
+    }
+    // 貼り付け用の内容を所有している間は、読み込みが貼り付けとして扱われるため読み込まない
+    if clipboard_handler
+        .paste_offer
+        .as_ref()
+        .is_some_and(|paste_offer| paste_offer.is_offering())
+    {
+        stats.record(PollResult::Unchanged);
+        return previous;
+    }
+    let mut failure = None;
+    // 書き込みの待ち時間による見送り。失敗にも成功にも数えない
+    let mut write_deferred = false;
+    let (fingerprint, result) = match clipboard_handler.get_contents() {
+        Ok(clipboard_content) => {
+            let current =
+                ContentFingerprint::with_detection(&clipboard_content, config.app.change_detection);
+            clipboard_handler.format_pending = false;
+            // 空のクリップボードは変更として扱わない
+            if clipboard_content.trim().is_empty() {
+                (previous, PollResult::Skipped)
+            } else if current == previous
+                || clipboard_handler.last_written.as_ref() == Some(&current)
+            {
+                (current, PollResult::Unchanged)
+            } else if paused {
+                // 一時停止中も識別情報は更新し、再開時に停止中のコピーを整形しない
+                (current, PollResult::Skipped)
+            } else if let Some(delay) = clipboard_handler.format_delay(config) {
+                // 識別情報は更新せず、間隔が経過した時点の最新の内容を整形する
+                debug!(
+                    "Deferred formatting for {}ms (min_format_interval_ms)",
+                    delay.as_millis()
+                );
+                clipboard_handler.format_pending = true;
+                (previous, PollResult::Skipped)
+            } else if let Some(delay) = clipboard_handler.write_backoff() {
+                // 識別情報は更新せず、待ち時間が過ぎた時点の最新の内容を整形して書き込む
+                debug!(
+                    "Deferred formatting for {}ms after failing to write the clipboard",
+                    delay.as_millis()
+                );
+                clipboard_handler.format_pending = true;
+                write_deferred = true;
+                (previous, PollResult::Skipped)
+            } else {
+                match clipboard_handler.process_content(clipboard_content, config) {
+                    Ok(true) => (current, PollResult::Formatted),
+                    Ok(false) => (current, PollResult::Skipped),
+                    // 書き込めなかった内容は識別情報を更新せず、待ち時間が過ぎてから書き込み直す
+                    Err(e @ ClipboardError::SetContents(_)) => {
+                        failure = Some(format!("Failed to process clipboard: {e}"));
+                        clipboard_handler.format_pending = true;
+                        (previous, PollResult::Skipped)
+                    }
+                    Err(ClipboardError::WriteBackoff(reason)) => {
+                        debug!("Deferred formatting: {reason}");
+                        clipboard_handler.format_pending = true;
+                        write_deferred = true;
+                        (previous, PollResult::Skipped)
+                    }
+                    Err(e) => {
+                        failure = Some(format!("Failed to process clipboard: {e}"));
+                        (current, PollResult::Skipped)
+                    }
+                }
+            }
+        }
+        // テキスト以外の内容は整形せずに読み飛ばし、次の変更を待つ
+        Err(ClipboardError::NonText(reason)) => {
+            debug!("Skipped formatting: clipboard does not contain text ({reason})");
+            (previous, PollResult::Skipped)
+        }
+        Err(e) => {
+            failure = Some(e.to_string());
+            (previous, PollResult::Skipped)
+        }
+    };
+    match failure {
+        Some(message) if clipboard_handler.circuit_breaker.record_failure(now) => error!(
+            "{message}; pausing formatting for {}s after {} consecutive errors",
+            CIRCUIT_BREAKER_COOLDOWN.as_secs(),
+            clipboard_handler.circuit_breaker.consecutive_failures
+        ),
+        Some(message) => warn!("{message}"),
+        None if write_deferred => {}
+        None => clipboard_handler.circuit_breaker.record_success(),
+    }
+    stats.record(result);
+    fingerprint
+}
+
+// format_on = "paste"で、用意した内容が貼り付けられていれば整形してクリップボードに書き込む
+pub(crate) fn handle_paste<C: Clipboard>(
+    clipboard_handler: &mut ClipboardHandler<C>,
+    config: &AppConfig,
+) -> bool {
+    let pasted = clipboard_handler
+        .paste_offer
+        .as_ref()
+        .is_some_and(|paste_offer| paste_offer.take_paste());
+    if !pasted {
+        return false;
+    }
+    let Some(original) = clipboard_handler.pending_paste.take() else {
+        return false;
+    };
+    debug!("Formatted content was pasted; writing it to the clipboard");
+    if let Err(e) = clipboard_handler.format_content(original, config, false) {
+        warn!("Failed to format pasted content: {e}");
+    }
+    true
+}
+
+// プレビューのホットキーを押すと立つフラグ。ループ内で確認して差分を表示する
+pub(crate) static PREVIEW_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn handle_preview_request<C: Clipboard>(
+    clipboard_handler: &mut ClipboardHandler<C>,
+    config: &AppConfig,
+    requested: &AtomicBool,
+) -> bool {
+    if !requested.swap(false, Ordering::SeqCst) {
+        return false;
+    }
+    match clipboard_handler.preview(config) {
+        Ok(Some((diff, plain_diff))) => {
+            info!("Preview (clipboard not changed):\n{diff}");
+            show_notification(format!("Preview: {plain_diff}"));
+        }
+        Ok(None) => {
+            info!("Preview: formatting would not change the clipboard");
+            show_notification("Preview: no changes".to_string());
+        }
+        Err(e) => warn!("Failed to preview formatting: {e}"),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppSettings, ChangeDetection, DiffGranularity, DEFAULT_CONFIG};
+    use crate::format::format_text;
+    use crate::test_support::{ConfigBuilder, MockClipboard, ENV_LOCK};
+    use crate::x11::{ClipboardSource, PasteOfferState};
+    use indexmap::IndexMap;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    // Tests for desktop notifications
+    #[test]
+    fn test_notification_message() {
+        let outcome = FormatOutcome {
+            replaced_count: 3,
+            applied_conversions: vec!["全角→半角".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            notification_message(&outcome),
+            "Formatted clipboard (3 replacements, 全角→半角)"
+        );
+
+        let outcome = FormatOutcome {
+            replaced_count: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            notification_message(&outcome),
+            "Formatted clipboard (1 replacement)"
+        );
+        assert_eq!(
+            notification_message(&FormatOutcome::default()),
+            "Formatted clipboard"
+        );
+    }
+
+    #[test]
+    fn test_notification_limiter() {
+        let mut limiter = NotificationLimiter::default();
+        let start = Instant::now();
+        assert!(limiter.allow(start));
+        // 最短間隔内の通知は抑制される
+        assert!(!limiter.allow(start + Duration::from_secs(1)));
+        assert!(limiter.allow(start + NOTIFICATION_MIN_INTERVAL));
+    }
+
+    #[test]
+    fn test_digest_message() {
+        let interval = Duration::from_secs(5 * 60);
+        assert_eq!(
+            digest_message(12, 40, interval),
+            "Formatted clipboard 12 times, 40 replacements in the last 5 min"
+        );
+        assert_eq!(
+            digest_message(1, 1, interval),
+            "Formatted clipboard 1 time, 1 replacement in the last 5 min"
+        );
+    }
+
+    #[test]
+    fn test_notification_digest() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(60);
+        let mut digest = NotificationDigest::new(start);
+        digest.record(3);
+        digest.record(0);
+        // 間隔が経過するまでは通知しない
+        assert_eq!(
+            digest.take_message(start + Duration::from_secs(30), interval),
+            None
+        );
+        assert_eq!(
+            digest.take_message(start + interval, interval).as_deref(),
+            Some("Formatted clipboard 2 times, 3 replacements in the last 1 min")
+        );
+        // 通知すると集計はリセットされ、整形しなかった間隔は通知しない
+        assert_eq!(digest.formats, 0);
+        assert_eq!(digest.replacements, 0);
+        assert_eq!(digest.take_message(start + interval * 2, interval), None);
+    }
+
+    #[test]
+    fn test_process_clipboard_records_notification_digest() {
+        let config = AppConfig {
+            app: AppSettings {
+                notify_digest_minutes: 5,
+                ..Default::default()
+            },
+            replacements: IndexMap::from([("Ａ".to_string(), "a".into())]),
+            ..Default::default()
+        };
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＡＢ"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.notification_digest.formats, 1);
+        assert_eq!(handler.notification_digest.replacements, 2);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut breaker = CircuitBreaker::default();
+        let start = Instant::now();
+        for _ in 1..CIRCUIT_BREAKER_THRESHOLD {
+            assert!(!breaker.record_failure(start));
+        }
+        assert!(!breaker.is_open(start));
+        // 連続した失敗が閾値に達すると停止する
+        assert!(breaker.record_failure(start));
+        assert!(breaker.is_open(start + Duration::from_secs(1)));
+        assert!(!breaker.is_open(start + CIRCUIT_BREAKER_COOLDOWN));
+        // 停止が明けた後も失敗が続けばすぐに再び停止する
+        let retry = start + CIRCUIT_BREAKER_COOLDOWN;
+        assert!(breaker.record_failure(retry));
+        assert!(breaker.is_open(retry));
+        // 成功すると失敗の回数と停止が解除される
+        breaker.record_success();
+        assert!(!breaker.is_open(retry));
+        assert!(!breaker.record_failure(retry));
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = std::panic::catch_unwind(|| panic!("invalid {}", "state")).unwrap_err();
+        assert_eq!(panic_message(&*panic), "invalid state");
+        let panic = std::panic::catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(panic_message(&*panic), "unknown panic");
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_skips_while_circuit_breaker_is_open() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢ"),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+        for _ in 0..CIRCUIT_BREAKER_THRESHOLD {
+            handler.circuit_breaker.record_failure(Instant::now());
+        }
+
+        // 停止中はクリップボードを読み込まず、識別情報も更新しない
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(hash, ContentFingerprint::default());
+        assert_eq!(handler.ctx.get_count, 0);
+        assert!(handler.format_pending);
+
+        // 停止が明けて整形に成功すると解除される
+        handler.circuit_breaker.open_until = Some(Instant::now());
+        handle_clipboard_processing(
+            &mut handler,
+            &config,
+            hash,
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(handler.ctx.contents, "AB");
+        assert_eq!(handler.circuit_breaker.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_describe_applied_rules() {
+        let mut config = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                ("foo".to_string(), "bar".into()),
+                ("baz".to_string(), "qux".into()),
+            ]))
+            .build();
+        config.regex_replacements = toml::from_str(r#""a+" = "a""#).unwrap();
+        config.app.convert_katakana = true;
+
+        // 一致したルールと変化のあった変換だけが、適用順に表示される
+        let outcome = format_clipboard_content("foo aaa １ ア", &config).unwrap();
+        assert_eq!(
+            describe_applied_rules(&outcome, &config),
+            " (rules: foo→bar, a+→a, 全角→半角, カタカナ→半角)"
+        );
+
+        let outcome = format_clipboard_content("plain", &config).unwrap();
+        assert_eq!(describe_applied_rules(&outcome, &config), "");
+    }
+
+    #[test]
+    fn test_process_clipboard_formats_contents() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ１２３"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+
+        assert_eq!(handler.ctx.contents, "ABC123");
+        assert_eq!(handler.ctx.set_count, 1);
+    }
+
+    #[test]
+    fn test_process_clipboard_unchanged_skips_set() {
+        // 整形結果が変わらない場合はクリップボードに書き込まない
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ABC123"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+
+        assert_eq!(handler.ctx.contents, "ABC123");
+        assert_eq!(handler.ctx.set_count, 0);
+    }
+
+    #[test]
+    fn test_process_clipboard_dry_run_skips_set() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ１２３"),
+            HandlerOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        );
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+
+        assert_eq!(handler.ctx.contents, "ＡＢＣ１２３");
+        assert_eq!(handler.ctx.set_count, 0);
+    }
+
+    #[test]
+    fn test_process_clipboard_empty_contents() {
+        // 空白のみの内容に対しては整形も書き込みも行わない
+        let config = AppConfig {
+            replacements: IndexMap::from([(" ".to_string(), "_".into())]),
+            ..Default::default()
+        };
+        for contents in ["", "   ", "\n\t\n"] {
+            let mut handler = ClipboardHandler::with_clipboard(
+                MockClipboard::with_contents(contents),
+                HandlerOptions::default(),
+            );
+            handler.process_clipboard(&config).unwrap();
+
+            assert_eq!(handler.ctx.contents, contents);
+            assert_eq!(handler.ctx.set_count, 0);
+        }
+    }
+
+    #[test]
+    fn test_process_clipboard_skips_oversized_contents() {
+        let config = AppConfig {
+            app: AppSettings {
+                max_content_bytes: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // 上限を超える内容はそのまま残す
+        let oversized = "ＡＢＣＤＥＦ";
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents(oversized),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, oversized);
+        assert_eq!(handler.ctx.set_count, 0);
+
+        // 上限以内の内容は整形される
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, "ABC");
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_updates_hash_for_oversized_contents() {
+        let config = AppConfig {
+            app: AppSettings {
+                max_content_bytes: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+
+        // 整形はスキップしてもハッシュは更新され、同じ内容を再確認しない
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(hash, ContentFingerprint::new("ＡＢＣ"));
+        assert_eq!(handler.ctx.set_count, 0);
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_defers_rapid_changes() {
+        let config = AppConfig {
+            app: AppSettings {
+                min_format_interval_ms: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢ"),
+            HandlerOptions::default(),
+        );
+        let mut stats = PollStats::default();
+        let mut hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut stats,
+        );
+        assert_eq!(handler.ctx.set_count, 1);
+
+        // 間隔内に続けて変わった内容は整形せず、識別情報も更新しない
+        for content in ["ＣＤ", "ＥＦ", "ＧＨ"] {
+            handler.ctx.contents = content.to_string();
+            hash = handle_clipboard_processing(&mut handler, &config, hash, false, &mut stats);
+            assert_eq!(hash, ContentFingerprint::new("ＡＢ"));
+        }
+        assert_eq!(handler.ctx.set_count, 1);
+        assert!(handler.format_pending);
+
+        // 間隔が経過すると最後の内容だけを1回整形する
+        thread::sleep(Duration::from_millis(120));
+        hash = handle_clipboard_processing(&mut handler, &config, hash, false, &mut stats);
+        assert_eq!(hash, ContentFingerprint::new("ＧＨ"));
+        assert_eq!(handler.ctx.contents, "GH");
+        assert_eq!(handler.ctx.set_count, 2);
+        assert!(!handler.format_pending);
+    }
+
+    // 貼り付けの検知を模倣するPasteOffer。テストからコピー・貼り付けを発生させる
+    #[derive(Clone, Default)]
+    struct FakePasteOffer {
+        state: Arc<Mutex<PasteOfferState>>,
+        // 内容が大きすぎる場合などに、用意できずにエラーを返す
+        refuse: bool,
+    }
+
+    impl FakePasteOffer {
+        fn offered(&self) -> Option<String> {
+            self.state.lock().unwrap().content.clone()
+        }
+
+        // 他のアプリケーションが内容を要求した
+        fn paste(&self) {
+            let mut state = self.state.lock().unwrap();
+            if state.content.is_some() {
+                state.pasted = true;
+            }
+        }
+
+        // 他のアプリケーションがコピーしてセレクションの所有者でなくなった
+        fn clear(&self) {
+            self.state.lock().unwrap().content = None;
+        }
+    }
+
+    impl PasteOffer for FakePasteOffer {
+        fn offer(&self, formatted: String) -> Result<()> {
+            if self.refuse {
+                anyhow::bail!("Formatted content is too large to offer for pasting");
+            }
+            *self.state.lock().unwrap() = PasteOfferState {
+                content: Some(formatted),
+                pasted: false,
+            };
+            Ok(())
+        }
+
+        fn take_paste(&self) -> bool {
+            std::mem::take(&mut self.state.lock().unwrap().pasted)
+        }
+
+        fn is_offering(&self) -> bool {
+            self.state.lock().unwrap().content.is_some()
+        }
+    }
+
+    #[test]
+    fn test_format_on_paste_defers_until_pasted() {
+        let config = AppConfig::default();
+        let paste_offer = FakePasteOffer::default();
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢ"),
+            HandlerOptions::default(),
+        )
+        .with_paste_offer(Box::new(paste_offer.clone()));
+        let mut stats = PollStats::default();
+
+        // コピーした時点ではクリップボードに書き込まず、整形後の内容を用意する
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut stats,
+        );
+        assert_eq!(handler.ctx.set_count, 0);
+        assert_eq!(handler.ctx.contents, "ＡＢ");
+        assert_eq!(paste_offer.offered().as_deref(), Some("AB"));
+
+        // 用意している間は読み込み自体が貼り付けになるため、クリップボードを読み込まない
+        let get_count = handler.ctx.get_count;
+        let hash = handle_clipboard_processing(&mut handler, &config, hash, false, &mut stats);
+        assert_eq!(handler.ctx.get_count, get_count);
+
+        // 貼り付けられるまでは何もしない
+        assert!(!handle_paste(&mut handler, &config));
+        assert_eq!(handler.ctx.set_count, 0);
+
+        // 貼り付けられると整形後の内容をクリップボードに書き込む
+        paste_offer.paste();
+        assert!(handle_paste(&mut handler, &config));
+        assert_eq!(handler.ctx.contents, "AB");
+        assert_eq!(handler.ctx.set_count, 1);
+        assert!(handler.pending_paste.is_none());
+        assert!(!handle_paste(&mut handler, &config));
+
+        // 他のアプリケーションが新しくコピーすると、古い内容は貼り付けの対象にならない
+        paste_offer.clear();
+        paste_offer.paste();
+        assert!(!handle_paste(&mut handler, &config));
+        handler.ctx.contents = "ＣＤ".to_string();
+        handle_clipboard_processing(&mut handler, &config, hash, false, &mut stats);
+        assert_eq!(paste_offer.offered().as_deref(), Some("CD"));
+        assert_eq!(handler.ctx.contents, "ＣＤ");
+        assert_eq!(handler.ctx.set_count, 1);
+    }
+
+    #[test]
+    fn test_format_on_paste_writes_directly_when_offer_fails() {
+        let config = AppConfig::default();
+        let paste_offer = FakePasteOffer {
+            refuse: true,
+            ..FakePasteOffer::default()
+        };
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢ"),
+            HandlerOptions::default(),
+        )
+        .with_paste_offer(Box::new(paste_offer.clone()));
+
+        // 貼り付け用の内容を用意できなければ、その場で整形して書き込む
+        handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(paste_offer.offered(), None);
+        assert_eq!(handler.ctx.contents, "AB");
+        assert_eq!(handler.ctx.set_count, 1);
+        assert!(handler.pending_paste.is_none());
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_ignores_empty_contents() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents(""),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+
+        // 空のクリップボードは変更として扱わず、直前のハッシュを維持する
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::new("前回の内容"),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(hash, ContentFingerprint::new("前回の内容"));
+        assert_eq!(handler.ctx.set_count, 0);
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_skips_non_text_contents() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard {
+                non_text: true,
+                ..MockClipboard::with_contents("ＡＢＣ")
+            },
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+        let mut stats = PollStats::default();
+
+        // テキスト以外の内容は読み飛ばし、直前のハッシュを維持する
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::new("前回の内容"),
+            false,
+            &mut stats,
+        );
+        assert_eq!(hash, ContentFingerprint::new("前回の内容"));
+        assert_eq!(handler.ctx.set_count, 0);
+        assert_eq!(stats.polls, 1);
+        assert_eq!(stats.formatted, 0);
+
+        // 同じプロバイダーのまま、テキストがコピーされれば整形する
+        handler.ctx.non_text = false;
+        handle_clipboard_processing(&mut handler, &config, hash, false, &mut stats);
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.ctx.get_count, 2);
+    }
+
+    #[test]
+    fn test_process_clipboard_skips_non_text_contents() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard {
+                non_text: true,
+                ..MockClipboard::with_contents("ＡＢＣ")
+            },
+            HandlerOptions::default(),
+        );
+        // --onceでもエラーにせず、整形しなかったものとして扱う
+        assert!(!handler.process_clipboard(&AppConfig::default()).unwrap());
+        assert_eq!(handler.ctx.set_count, 0);
+    }
+
+    #[test]
+    fn test_change_detection_strategies_detect_changes() {
+        for detection in [
+            ChangeDetection::Hash,
+            ChangeDetection::Prefix,
+            ChangeDetection::Full,
+        ] {
+            let mut config = AppConfig::default();
+            config.app.change_detection = detection;
+            let mut handler = ClipboardHandler::with_clipboard(
+                MockClipboard::with_contents("ＡＢＣ"),
+                HandlerOptions::default(),
+            );
+            let mut stats = PollStats::default();
+            let mut poll = |handler: &mut ClipboardHandler<MockClipboard>, previous| {
+                handle_clipboard_processing(handler, &config, previous, false, &mut stats)
+            };
+
+            let fingerprint = poll(&mut handler, ContentFingerprint::default());
+            assert_eq!(handler.ctx.contents, "ABC", "{detection:?}");
+            // 整形する必要のない内容に変わった場合も変更として扱い、識別情報を更新する
+            handler.ctx.contents = "abc".to_string();
+            let fingerprint = poll(&mut handler, fingerprint);
+            // 変わっていない内容は処理しない
+            let fingerprint = poll(&mut handler, fingerprint);
+            handler.ctx.contents = "ＤＥＦ".to_string();
+            poll(&mut handler, fingerprint);
+            assert_eq!(handler.ctx.contents, "DEF", "{detection:?}");
+            assert_eq!(handler.ctx.set_count, 2, "{detection:?}");
+            assert_eq!(
+                stats,
+                PollStats {
+                    polls: 4,
+                    formatted: 2,
+                    deduped: 1,
+                },
+                "{detection:?}"
+            );
+        }
+    }
+
+    // 書き込まれた内容を共有するWrite。tee_stdoutの出力の確認用
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedOutput {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tee_stdout_prints_formatted_content() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let output = SharedOutput::default();
+        handler.tee_output = Box::new(output.clone());
+        let mut config = AppConfig::default();
+        config.app.tee_stdout = true;
+
+        let fingerprint = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(output.text(), "ABC\n");
+
+        // 改行で終わる内容には改行を加えず、整形しなかった内容は出力しない
+        handler.ctx.contents = "ＤＥＦ\n".to_string();
+        let fingerprint = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            fingerprint,
+            false,
+            &mut PollStats::default(),
+        );
+        handler.ctx.contents = "ghi".to_string();
+        handle_clipboard_processing(
+            &mut handler,
+            &config,
+            fingerprint,
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(output.text(), "ABC\nDEF\n");
+    }
+
+    #[test]
+    fn test_tee_stdout_disabled_by_default() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let output = SharedOutput::default();
+        handler.tee_output = Box::new(output.clone());
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(output.text(), "");
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_skips_same_hash() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.ctx.set_count, 1);
+
+        // 同じ内容のハッシュが渡された場合は処理しない
+        handler.ctx.contents = "ＡＢＣ".to_string();
+        let next_hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            hash.clone(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(next_hash, hash);
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+        assert_eq!(handler.ctx.set_count, 1);
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_skips_own_output() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+        let mut stats = PollStats::default();
+
+        handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut stats,
+        );
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.ctx.set_count, 1);
+
+        // 直前のハッシュが異なっていても、書き込んだ内容は処理しない
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::new("前回の内容"),
+            false,
+            &mut stats,
+        );
+        assert_eq!(hash, ContentFingerprint::new("ABC"));
+        assert_eq!(handler.ctx.set_count, 1);
+        assert_eq!(stats.formatted, 1);
+        assert_eq!(stats.deduped, 1);
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_skips_formatting_while_paused() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+
+        // 一時停止中は整形しないがハッシュは更新する
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            true,
+            &mut PollStats::default(),
+        );
+        assert_eq!(hash, ContentFingerprint::new("ＡＢＣ"));
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+        assert_eq!(handler.ctx.set_count, 0);
+
+        // 再開しても停止中にコピーした内容は整形しない
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            hash,
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+
+        // 再開後の新しいコピーは整形する
+        handler.ctx.contents = "ＤＥＦ".to_string();
+        handle_clipboard_processing(
+            &mut handler,
+            &config,
+            hash,
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(handler.ctx.contents, "DEF");
+    }
+
+    // 決まった提供元を返すSourceLookup
+    struct FakeSourceLookup(&'static str);
+
+    impl SourceLookup for FakeSourceLookup {
+        fn source(&self) -> Option<ClipboardSource> {
+            Some(ClipboardSource {
+                pid: None,
+                name: Some(self.0.to_string()),
+            })
+        }
+    }
+
+    #[test]
+    fn test_process_clipboard_skips_blocked_source() {
+        let mut config = AppConfig::default();
+        config.source.skip = vec!["keepassxc".to_string()];
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        )
+        .with_source_lookup(Box::new(FakeSourceLookup("KeePassXC")));
+        assert!(!handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+
+        // 提供元を調べない場合（テストのMockClipboardなど）は通常どおり整形する
+        handler.source_lookup = None;
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "ABC");
+    }
+
+    // 決まったウィンドウを返し、調べた回数を数えるFocusLookup
+    struct FakeFocusLookup {
+        window: &'static str,
+        lookups: Arc<Mutex<usize>>,
+    }
+
+    impl FocusLookup for FakeFocusLookup {
+        fn focused_window(&mut self) -> Option<String> {
+            *self.lookups.lock().unwrap() += 1;
+            Some(self.window.to_string())
+        }
+    }
+
+    #[test]
+    fn test_process_clipboard_skips_blocked_focus() {
+        let lookups = Arc::new(Mutex::new(0));
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        )
+        .with_focus_lookup(Box::new(FakeFocusLookup {
+            window: "Alacritty Alacritty vim",
+            lookups: lookups.clone(),
+        }));
+        // focus.skipが空ならフォーカス中のウィンドウを調べない
+        let mut config = AppConfig::default();
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(*lookups.lock().unwrap(), 0);
+
+        handler.ctx.contents = "ＤＥＦ".to_string();
+        config.focus.skip = vec!["vim".to_string()];
+        assert!(!handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "ＤＥＦ");
+        assert_eq!(*lookups.lock().unwrap(), 1);
+    }
+
+    // Tests for history
+    #[test]
+    fn test_append_history_rotates_oldest_entries() {
+        let temp_dir = tempdir().unwrap();
+        let history = HistorySettings {
+            file: temp_dir.path().join("history.jsonl"),
+            max_entries: 3,
+        };
+
+        // max_entries + 1件書き込むと最も古い1件が削除される
+        for i in 0..=history.max_entries {
+            append_history(&history, &serde_json::json!({ "index": i })).unwrap();
+        }
+        let text = fs::read_to_string(&history.file).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![r#"{"index":1}"#, r#"{"index":2}"#, r#"{"index":3}"#]
+        );
+    }
+
+    #[test]
+    fn test_process_clipboard_records_history() {
+        let temp_dir = tempdir().unwrap();
+        let history_file = temp_dir.path().join("history.jsonl");
+        let config = AppConfig {
+            history: Some(HistorySettings {
+                file: history_file.clone(),
+                max_entries: 10,
+            }),
+            ..ConfigBuilder::default().replacement("，", ", ").build()
+        };
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("Ａ，Ｂ"),
+            HandlerOptions::default(),
+        );
+
+        handler.process_clipboard(&config).unwrap();
+        let text = fs::read_to_string(&history_file).unwrap();
+        let record: serde_json::Value = serde_json::from_str(text.trim()).unwrap();
+        assert_eq!(record["bytes_before"], "Ａ，Ｂ".len());
+        assert_eq!(record["bytes_after"], "A, B".len());
+        assert_eq!(record["applied_rules"], 1);
+        assert!(record["timestamp"].as_u64().unwrap() > 0);
+
+        // [history]がない場合は何も書き込まない
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("Ｃ"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&history_file).unwrap().lines().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_process_clipboard_skip_if_code() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.skip_if_code = true)
+            .build();
+        // JSONは整形せずにそのまま残す
+        let json = r#"{"price": "１２３", "note": "Ａ，Ｂ"}"#;
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents(json),
+            HandlerOptions::default(),
+        );
+        assert!(!handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, json);
+        assert_eq!(handler.ctx.set_count, 0);
+
+        // 日本語の文章は通常どおり整形する
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("価格は１２３円，税込です。"),
+            HandlerOptions::default(),
+        );
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "価格は123円, 税込です。");
+    }
+
+    #[test]
+    fn test_shrink_ratio() {
+        assert_eq!(shrink_ratio("abcd", "ab"), 0.5);
+        assert_eq!(shrink_ratio("ab", "abcd"), 0.0);
+        assert_eq!(shrink_ratio("", ""), 0.0);
+        // 全角→半角でバイト数が減っても文字数は変わらない
+        assert_eq!(shrink_ratio("ＡＢＣ", "ABC"), 0.0);
+    }
+
+    #[test]
+    fn test_process_clipboard_blocks_large_shrink() {
+        let config = ConfigBuilder::default()
+            .replacement("[削除]", "")
+            .app(|app| app.max_shrink_ratio = Some(0.3))
+            .build();
+        // 10文字中4文字が削除される（40%）
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("[削除]あいうえおか"),
+            HandlerOptions::default(),
+        );
+        assert!(!handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "[削除]あいうえおか");
+        assert_eq!(handler.ctx.set_count, 0);
+    }
+
+    #[test]
+    fn test_process_clipboard_allows_shrink_within_ratio() {
+        let config = ConfigBuilder::default()
+            .replacement("[削除]", "")
+            .app(|app| app.max_shrink_ratio = Some(0.5))
+            .build();
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("[削除]あいうえおか"),
+            HandlerOptions::default(),
+        );
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "あいうえおか");
+
+        // max_shrink_ratioが未指定なら制限しない
+        let config = ConfigBuilder::default().replacement("[削除]", "").build();
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("[削除]"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, "");
+    }
+
+    #[test]
+    fn test_process_clipboard_stashes_original_to_primary() {
+        let mut config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        config.app.stash_original_to = Some(StashTarget::Primary);
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        )
+        .with_original_stash(MockClipboard::default());
+
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.original_stash.unwrap().contents, "ＡＢＣ");
+    }
+
+    #[test]
+    fn test_process_clipboard_does_not_stash_when_write_fails() {
+        let mut config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        config.app.stash_original_to = Some(StashTarget::Primary);
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard {
+                set_failures: SET_CONTENTS_RETRY_ATTEMPTS as usize,
+                ..MockClipboard::with_contents("ＡＢＣ")
+            },
+            HandlerOptions::default(),
+        )
+        .with_original_stash(MockClipboard::with_contents("primary"));
+
+        // クリップボードを書き換えられなければ、退避先も上書きしない
+        assert!(handler.process_clipboard(&config).is_err());
+        assert!(handler.process_clipboard(&config).is_err());
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+        assert_eq!(handler.original_stash.unwrap().contents, "primary");
+    }
+
+    #[test]
+    fn test_process_clipboard_stashes_original_to_file() {
+        let temp_dir = tempdir().unwrap();
+        let stash_path = temp_dir.path().join("original.txt");
+        let mut config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        config.app.stash_original_to = Some(StashTarget::File(stash_path.clone()));
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(fs::read_to_string(&stash_path).unwrap(), "ＡＢＣ");
+
+        // 整形しなかった内容は退避しない
+        handler.ctx.contents = "abc".to_string();
+        assert!(!handler.process_clipboard(&config).unwrap());
+        assert_eq!(fs::read_to_string(&stash_path).unwrap(), "ＡＢＣ");
+    }
+
+    #[test]
+    fn test_process_clipboard_verify_idempotent_still_writes() {
+        let mut config = ConfigBuilder::default()
+            .replacements(IndexMap::from([
+                ("A".to_string(), "B".into()),
+                ("B".to_string(), "AA".into()),
+            ]))
+            .build();
+        config.app.verify_idempotent = true;
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("A"),
+            HandlerOptions::default(),
+        );
+        // 警告を出すだけで、整形結果は書き込む
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(
+            handler.ctx.contents,
+            format_clipboard_content("A", &config).unwrap().text
+        );
+    }
+
+    // Tests for preview
+    #[test]
+    fn test_preview_shows_diff_without_writing() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢ"),
+            HandlerOptions::default(),
+        );
+        let mut config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        config.diff.granularity = DiffGranularity::Line;
+
+        let (diff, plain_diff) = handler.preview(&config).unwrap().unwrap();
+        assert!(diff.contains("\x1b["), "{diff:?}");
+        assert_eq!(plain_diff, "[-ＡＢ-]\n{+AB+}");
+        // クリップボードと直前の整形の記録は変更しない
+        assert_eq!(handler.ctx.contents, "ＡＢ");
+        assert_eq!(handler.ctx.set_count, 0);
+        assert_eq!(handler.last_written, None);
+
+        handler.ctx.contents = "AB".to_string();
+        assert_eq!(handler.preview(&config).unwrap(), None);
+    }
+
+    #[test]
+    fn test_handle_preview_request_without_request() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢ"),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        // ホットキーが押されていなければクリップボードを読まない
+        assert!(!handle_preview_request(
+            &mut handler,
+            &config,
+            &AtomicBool::new(false)
+        ));
+        assert_eq!(handler.ctx.get_count, 0);
+    }
+
+    // Tests for set_contents retries
+    #[test]
+    fn test_set_contents_retries_transient_failures() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard {
+                set_failures: SET_CONTENTS_RETRY_ATTEMPTS as usize - 1,
+                ..MockClipboard::with_contents("ＡＢＣ")
+            },
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+
+        // 再試行の回数以内に成功すれば書き込まれる
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.ctx.set_count, SET_CONTENTS_RETRY_ATTEMPTS as usize);
+        assert_eq!(handler.set_failures, 0);
+        assert_eq!(handler.set_backoff_until, None);
+    }
+
+    #[test]
+    fn test_set_contents_backs_off_after_repeated_failures() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard {
+                set_failures: SET_CONTENTS_RETRY_ATTEMPTS as usize,
+                ..MockClipboard::default()
+            },
+            HandlerOptions::default(),
+        );
+
+        assert!(handler.set_contents("ABC".to_string()).is_err());
+        assert_eq!(handler.set_failures, 1);
+        assert!(handler.set_backoff_until.is_some());
+
+        // 待ち時間の間はクリップボードに書き込もうとしない
+        let set_count = handler.ctx.set_count;
+        assert!(matches!(
+            handler.set_contents("ABC".to_string()),
+            Err(ClipboardError::WriteBackoff(_))
+        ));
+        assert_eq!(handler.ctx.set_count, set_count);
+
+        // 待ち時間が過ぎれば再び書き込み、成功すると失敗回数をリセットする
+        handler.set_backoff_until = Some(Instant::now());
+        handler.set_contents("ABC".to_string()).unwrap();
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.set_failures, 0);
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_retries_failed_write_after_backoff() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard {
+                set_failures: SET_CONTENTS_RETRY_ATTEMPTS as usize,
+                ..MockClipboard::with_contents("ＡＢ")
+            },
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+
+        // 書き込めなかった内容は識別情報を更新しない
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(hash, ContentFingerprint::default());
+        assert!(handler.format_pending);
+        assert_eq!(handler.circuit_breaker.consecutive_failures, 1);
+
+        // 待ち時間の間は整形も書き込みもせず、CircuitBreakerの失敗にも数えない
+        let set_count = handler.ctx.set_count;
+        for _ in 0..CIRCUIT_BREAKER_THRESHOLD {
+            let hash = handle_clipboard_processing(
+                &mut handler,
+                &config,
+                ContentFingerprint::default(),
+                false,
+                &mut PollStats::default(),
+            );
+            assert_eq!(hash, ContentFingerprint::default());
+        }
+        assert_eq!(handler.ctx.set_count, set_count);
+        assert_eq!(handler.circuit_breaker.consecutive_failures, 1);
+        assert!(!handler.circuit_breaker.is_open(Instant::now()));
+
+        // 待ち時間が過ぎれば同じ内容を整形して書き込む
+        handler.set_backoff_until = Some(Instant::now());
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            hash,
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(hash, ContentFingerprint::new("ＡＢ"));
+        assert_eq!(handler.ctx.contents, "AB");
+        assert_eq!(handler.circuit_breaker.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_process_clipboard_with_trigger_suffix() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.trigger_suffix = Some(";;".to_string()))
+            .build();
+        // 末尾の;;を取り除いて整形する
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("Ａ，Ｂ;;"),
+            HandlerOptions::default(),
+        );
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "A, B");
+
+        // 整形する文字がなくても末尾の;;は取り除く
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("abc;;"),
+            HandlerOptions::default(),
+        );
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "abc");
+    }
+
+    #[test]
+    fn test_process_clipboard_without_trigger_suffix() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.trigger_suffix = Some(";;".to_string()))
+            .build();
+        // ;;で終わらない内容は整形しない
+        for input in ["Ａ，Ｂ", "Ａ，Ｂ;", ";;Ａ，Ｂ"] {
+            let mut handler = ClipboardHandler::with_clipboard(
+                MockClipboard::with_contents(input),
+                HandlerOptions::default(),
+            );
+            assert!(!handler.process_clipboard(&config).unwrap());
+            assert_eq!(handler.ctx.contents, input);
+            assert_eq!(handler.ctx.set_count, 0);
+        }
+
+        // trigger_suffixが未指定なら従来通り整形する
+        let config = ConfigBuilder::default().replacement("，", ", ").build();
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("Ａ，Ｂ"),
+            HandlerOptions::default(),
+        );
+        assert!(handler.process_clipboard(&config).unwrap());
+        assert_eq!(handler.ctx.contents, "A, B");
+    }
+
+    #[test]
+    fn test_process_clipboard_skips_urls() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.skip_urls = true)
+            .build();
+        for url in [
+            "https://example.com/検索?q=ＡＢＣ，ＤＥＦ",
+            "  http://example.com/ＡＢＣ\n",
+            "ftp://files.example.com/ｆｉｌｅ",
+        ] {
+            let mut handler = ClipboardHandler::with_clipboard(
+                MockClipboard::with_contents(url),
+                HandlerOptions::default(),
+            );
+            assert!(!handler.process_clipboard(&config).unwrap());
+            assert_eq!(handler.ctx.contents, url);
+            assert_eq!(handler.ctx.set_count, 0);
+        }
+    }
+
+    #[test]
+    fn test_process_clipboard_formats_text_with_skip_urls() {
+        let config = ConfigBuilder::default()
+            .replacement("，", ", ")
+            .app(|app| app.skip_urls = true)
+            .build();
+        // URLを含んでいても、内容全体がURLでなければ整形する
+        for (input, expected) in [
+            ("Ａ，Ｂ", "A, B"),
+            (
+                "参照: https://example.com/ＡＢＣ",
+                "参照: https://example.com/ABC",
+            ),
+        ] {
+            let mut handler = ClipboardHandler::with_clipboard(
+                MockClipboard::with_contents(input),
+                HandlerOptions::default(),
+            );
+            assert!(handler.process_clipboard(&config).unwrap());
+            assert_eq!(handler.ctx.contents, expected);
+        }
+
+        // skip_urlsが無効ならURLも整形する
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("https://example.com/ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+        assert_eq!(handler.ctx.contents, "https://example.com/ABC");
+    }
+
+    // Tests for undo
+    fn undo_handler(contents: &str, state_path: &Path) -> ClipboardHandler<MockClipboard> {
+        ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents(contents),
+            HandlerOptions::default(),
+        )
+        .with_undo_state_path(state_path.to_path_buf())
+    }
+
+    #[test]
+    fn test_undo_restores_original() {
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join(UNDO_STATE_FILE_NAME);
+        let config = AppConfig::default();
+
+        let mut handler = undo_handler("ＡＢＣ", &state_path);
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(
+            handler.last_change.as_ref().map(|s| s.original.as_str()),
+            Some("ＡＢＣ")
+        );
+
+        // 別のプロセスからでも状態ファイルを使って復元できる
+        let mut undo = undo_handler("ABC", &state_path);
+        assert!(undo.undo().unwrap());
+        assert_eq!(undo.ctx.contents, "ＡＢＣ");
+
+        // 復元した内容は再び整形しない
+        handler.ctx.contents = "ＡＢＣ".to_string();
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+
+        // 2回目のundoは何もしない
+        assert!(!undo.undo().unwrap());
+    }
+
+    #[test]
+    fn test_undo_state_is_reloaded_only_when_modified() {
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join(UNDO_STATE_FILE_NAME);
+        let config = AppConfig::default();
+
+        let mut handler = undo_handler("ＡＢＣ", &state_path);
+        handler.process_clipboard(&config).unwrap();
+        assert!(undo_handler("ABC", &state_path).undo().unwrap());
+        handler.ctx.contents = "ＡＢＣ".to_string();
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+
+        // 更新時刻が変わらなければ状態ファイルを読み直さない
+        let modified = fs::metadata(&state_path).unwrap().modified().unwrap();
+        fs::write(&state_path, "{").unwrap();
+        let file = fs::File::options().write(true).open(&state_path).unwrap();
+        file.set_modified(modified).unwrap();
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+
+        // 更新時刻が変われば読み直す
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        handler.process_clipboard(&config).unwrap();
+        assert_eq!(handler.ctx.contents, "ABC");
+    }
+
+    #[test]
+    fn test_undo_skips_when_clipboard_changed() {
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join(UNDO_STATE_FILE_NAME);
+
+        let mut handler = undo_handler("ＡＢＣ", &state_path);
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+
+        // 整形後に別の内容がコピーされていれば古い内容で上書きしない
+        let mut undo = undo_handler("other", &state_path);
+        assert!(!undo.undo().unwrap());
+        assert_eq!(undo.ctx.contents, "other");
+        assert_eq!(undo.ctx.set_count, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_undo_state_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join("state").join(UNDO_STATE_FILE_NAME);
+        let mut handler = undo_handler("ＡＢＣ", &state_path);
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+        let mode = fs::metadata(&state_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_default_undo_state_path() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        let temp_dir = tempdir().unwrap();
+        env::set_var("XDG_STATE_HOME", temp_dir.path());
+        assert_eq!(
+            default_undo_state_path(),
+            Some(
+                temp_dir
+                    .path()
+                    .join("clipboard-formatter")
+                    .join(UNDO_STATE_FILE_NAME)
+            )
+        );
+        env::remove_var("XDG_STATE_HOME");
+    }
+
+    #[test]
+    fn test_undo_without_state() {
+        let temp_dir = tempdir().unwrap();
+        let mut undo = undo_handler("ABC", &temp_dir.path().join(UNDO_STATE_FILE_NAME));
+        assert!(!undo.undo().unwrap());
+        assert_eq!(undo.ctx.set_count, 0);
+    }
+
+    #[test]
+    fn test_handle_clipboard_processing_reads_once_per_change() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+
+        // ハッシュ計算で読み込んだ内容をそのまま整形に使う
+        handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut PollStats::default(),
+        );
+        assert_eq!(handler.ctx.get_count, 1);
+        assert_eq!(handler.ctx.contents, "ABC");
+    }
+
+    #[test]
+    fn test_poll_stats_counts() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let config = AppConfig::default();
+        let mut stats = PollStats::default();
+
+        // 1回目は整形、2回目は自分が書き込んだ内容のため処理しない、3回目は同じハッシュのため処理しない
+        let hash = handle_clipboard_processing(
+            &mut handler,
+            &config,
+            ContentFingerprint::default(),
+            false,
+            &mut stats,
+        );
+        let hash = handle_clipboard_processing(&mut handler, &config, hash, false, &mut stats);
+        handle_clipboard_processing(&mut handler, &config, hash, false, &mut stats);
+
+        assert_eq!(
+            stats,
+            PollStats {
+                polls: 3,
+                formatted: 1,
+                deduped: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_process_clipboard_records_format_latency() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+        assert_eq!(handler.format_latency.samples.len(), 1);
+
+        // 整形しない内容（空白のみ）は記録しない
+        handler.ctx.contents = "  ".to_string();
+        handler.process_clipboard(&AppConfig::default()).unwrap();
+        assert_eq!(handler.format_latency.samples.len(), 1);
+    }
+
+    #[test]
+    fn test_clipboard_integration() {
+        if std::env::var("CI").is_ok() {
+            // CI環境ではクリップボードを操作できないのでスキップ
+            // このテストはローカル環境でのみ実行される
+            return;
+        }
+        let mut ctx = ClipboardHandler::new(HandlerOptions::default()).unwrap();
+        let original_text = "foo baz １２３４！";
+        ctx.set_contents(original_text.to_string()).unwrap();
+
+        let replacements = IndexMap::from([
+            ("foo".to_string(), "bar".into()),
+            ("baz".to_string(), "qux".into()),
+        ]);
+        let exclusion_list = vec![];
+
+        let clipboard_content = ctx.get_contents().unwrap();
+        let config = ConfigBuilder::default()
+            .replacements(replacements)
+            .exclusions(&exclusion_list)
+            .build();
+        let formatted_content = format_text(&clipboard_content, &config).unwrap().text;
+        ctx.set_contents(formatted_content.clone()).unwrap();
+
+        assert_eq!(formatted_content, "bar qux 1234!");
+        assert_eq!(ctx.get_contents().unwrap(), "bar qux 1234!");
+    }
+}
@@ -0,0 +1,37 @@
+//! クリップボードの文字列を置換ルールに従って整形するライブラリ
+//!
+//! 設定ファイルを読み込んで[`format_text`]を呼び出すと、常駐アプリと同じ整形結果を得られます。
+//!
+//! ```
+//! use clipboard_formatter::{format_text, AppConfig};
+//!
+//! let config = AppConfig::from_toml(
+//!     r#"
+//!     [app]
+//!     clipboard_poll_interval = 300
+//!     config_reload_interval = 5000
+//!
+//!     [replacements]
+//!     "，" = ", "
+//!     "#,
+//! )
+//! .unwrap();
+//! assert_eq!(format_text("Ａ，Ｂ", &config).unwrap().text, "A, B");
+//! ```
+
+mod clipboard;
+mod config;
+mod format;
+mod handler;
+mod metrics;
+mod run;
+#[cfg(test)]
+mod test_support;
+mod x11;
+
+pub use config::{Anchor, AppConfig, ConfigManager, Exclusions, Replacement, Replacements, Script};
+pub use format::{
+    diff_stats, format_text, format_text_cow, BuiltinStep, DiffStats, FormatOutcome, Formatter,
+    Transform,
+};
+pub use run::run;
@@ -0,0 +1,484 @@
+//! 動作状況の集計とPrometheus形式での公開
+
+use crate::config::ReloadTotals;
+use anyhow::Result;
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(any(feature = "metrics", test))]
+use std::time::UNIX_EPOCH;
+#[cfg(feature = "metrics")]
+use {anyhow::Context, std::io::Write, std::thread};
+
+pub(crate) const POLL_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// クリップボードの確認回数の集計。動作状況の診断用で、整形には影響しない
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PollStats {
+    pub(crate) polls: usize,
+    pub(crate) formatted: usize,
+    pub(crate) deduped: usize,
+}
+
+pub(crate) enum PollResult {
+    Formatted,
+    // ハッシュが前回と同じため処理しなかった
+    Unchanged,
+    Skipped,
+}
+
+impl PollStats {
+    pub(crate) fn record(&mut self, result: PollResult) {
+        self.polls += 1;
+        match result {
+            PollResult::Formatted => self.formatted += 1,
+            PollResult::Unchanged => self.deduped += 1,
+            PollResult::Skipped => {}
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &PollStats) {
+        self.polls += other.polls;
+        self.formatted += other.formatted;
+        self.deduped += other.deduped;
+    }
+
+    pub(crate) fn log_summary(&self, elapsed: Duration) {
+        debug!(
+            "Clipboard polls in the last {}s: {} polls, {} formatted, {} unchanged",
+            elapsed.as_secs(),
+            self.polls,
+            self.formatted,
+            self.deduped
+        );
+    }
+}
+
+// 移動平均に使う整形時間の件数
+const FORMAT_LATENCY_WINDOW: usize = 100;
+
+// 直近の整形にかかった時間。遅い置換ルールや巨大な内容の診断用で、整形には影響しない
+#[derive(Debug)]
+pub(crate) struct FormatLatency {
+    pub(crate) samples: VecDeque<Duration>,
+    capacity: usize,
+    // 前回の要約以降に記録した件数。新しい記録がなければ要約を出力しない
+    recorded_since_summary: usize,
+}
+
+impl Default for FormatLatency {
+    fn default() -> Self {
+        Self::with_capacity(FORMAT_LATENCY_WINDOW)
+    }
+}
+
+impl FormatLatency {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            recorded_since_summary: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, elapsed: Duration) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(elapsed);
+        self.recorded_since_summary += 1;
+    }
+
+    fn average(&self) -> Option<Duration> {
+        let count = u32::try_from(self.samples.len()).ok().filter(|&n| n > 0)?;
+        Some(self.samples.iter().sum::<Duration>() / count)
+    }
+
+    fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    pub(crate) fn log_summary(&mut self) {
+        if self.recorded_since_summary == 0 {
+            return;
+        }
+        self.recorded_since_summary = 0;
+        if let (Some(average), Some(max)) = (self.average(), self.max()) {
+            debug!(
+                "Format latency over the last {} formats: average {average:.2?}, max {max:.2?}",
+                self.samples.len()
+            );
+        }
+    }
+}
+
+// ハートビートファイルを更新する最短の間隔。確認のたびにディスクへ書き込まないようにする
+const HEARTBEAT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+// [app] heartbeat_fileの更新日時の更新。監視側は更新日時が古くなったら停止したと判断できる
+#[derive(Debug, Default)]
+pub(crate) struct Heartbeat {
+    last_beat: Option<Instant>,
+    // 失敗し続けている間は警告を繰り返さない
+    failing: bool,
+}
+
+impl Heartbeat {
+    pub(crate) fn beat(&mut self, path: Option<&Path>, now: Instant) {
+        let Some(path) = path else {
+            return;
+        };
+        if self
+            .last_beat
+            .is_some_and(|last| now.duration_since(last) < HEARTBEAT_MIN_INTERVAL)
+        {
+            return;
+        }
+        self.last_beat = Some(now);
+        match touch_file(path) {
+            Ok(()) => {
+                if self.failing {
+                    info!("Heartbeat file {} is being updated again", path.display());
+                }
+                self.failing = false;
+            }
+            Err(e) => {
+                if !self.failing {
+                    warn!("Failed to update heartbeat file {}: {e}", path.display());
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
+
+// ファイルがなければ作成し、内容は変えずに更新日時だけを現在時刻にする
+fn touch_file(path: &Path) -> std::io::Result<()> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.set_modified(SystemTime::now())
+}
+
+// 起動してからの集計値。[metrics] portを指定した場合にPrometheus形式で公開する
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) totals: PollStats,
+    pub(crate) reloads: ReloadTotals,
+    pub(crate) last_formatted_at: Option<SystemTime>,
+}
+
+impl Metrics {
+    pub(crate) fn record_polls(&mut self, stats: &PollStats, now: SystemTime) {
+        self.totals.merge(stats);
+        if stats.formatted > 0 {
+            self.last_formatted_at = Some(now);
+        }
+    }
+
+    #[cfg(any(feature = "metrics", test))]
+    fn render(&self) -> String {
+        let last_formatted = self
+            .last_formatted_at
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+            text.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+            for (labels, value) in samples {
+                text.push_str(&format!("{name}{labels} {value}\n"));
+            }
+        };
+        metric(
+            "clipboard_formatter_polls_total",
+            "counter",
+            "Total number of clipboard polls.",
+            &[("", self.totals.polls.to_string())],
+        );
+        metric(
+            "clipboard_formatter_formats_total",
+            "counter",
+            "Total number of times the clipboard was formatted.",
+            &[("", self.totals.formatted.to_string())],
+        );
+        metric(
+            "clipboard_formatter_config_reloads_total",
+            "counter",
+            "Total number of config reloads by result.",
+            &[
+                ("{result=\"success\"}", self.reloads.successes.to_string()),
+                ("{result=\"failure\"}", self.reloads.failures.to_string()),
+            ],
+        );
+        metric(
+            "clipboard_formatter_last_format_timestamp_seconds",
+            "gauge",
+            "Unix time of the last format, or 0 if nothing has been formatted.",
+            &[("", format!("{last_formatted:.3}"))],
+        );
+        text
+    }
+}
+
+// 集計値の更新中にパニックしても公開は続ける
+pub(crate) fn lock_metrics(metrics: &Mutex<Metrics>) -> MutexGuard<'_, Metrics> {
+    metrics.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn start_metrics_server(
+    port: u16,
+    metrics: Arc<Mutex<Metrics>>,
+) -> Result<std::net::SocketAddr> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("Failed to listen for metrics on port {port}"))?;
+    let address = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    if let Err(e) = serve_metrics(&mut stream, &metrics) {
+                        debug!("Failed to serve metrics: {e}");
+                    }
+                }
+                Err(e) => debug!("Failed to accept metrics connection: {e}"),
+            }
+        }
+    });
+    Ok(address)
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn start_metrics_server(
+    _port: u16,
+    _metrics: Arc<Mutex<Metrics>>,
+) -> Result<std::net::SocketAddr> {
+    anyhow::bail!("[metrics] port is set but this build does not include the metrics feature")
+}
+
+#[cfg(feature = "metrics")]
+fn serve_metrics(
+    stream: &mut std::net::TcpStream,
+    metrics: &Mutex<Metrics>,
+) -> std::io::Result<()> {
+    use std::io::BufRead;
+
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // リクエスト行だけを見て、/metrics以外は404を返す
+    let mut request_line = String::new();
+    std::io::BufReader::new(&*stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", lock_metrics(metrics).render())
+    } else {
+        ("404 Not Found", String::from("Not Found\n"))
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_poll_stats_record() {
+        let mut stats = PollStats::default();
+        stats.record(PollResult::Formatted);
+        stats.record(PollResult::Unchanged);
+        stats.record(PollResult::Unchanged);
+        stats.record(PollResult::Skipped);
+        assert_eq!(
+            stats,
+            PollStats {
+                polls: 4,
+                formatted: 1,
+                deduped: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_format_latency_rolling_average() {
+        let mut latency = FormatLatency::with_capacity(3);
+        assert_eq!(latency.average(), None);
+        assert_eq!(latency.max(), None);
+
+        latency.record(Duration::from_millis(10));
+        latency.record(Duration::from_millis(20));
+        assert_eq!(latency.average(), Some(Duration::from_millis(15)));
+
+        // 上限を超えると古い記録から捨てる
+        latency.record(Duration::from_millis(30));
+        latency.record(Duration::from_millis(100));
+        assert_eq!(latency.samples.len(), 3);
+        assert_eq!(latency.average(), Some(Duration::from_millis(50)));
+        assert_eq!(latency.max(), Some(Duration::from_millis(100)));
+
+        // 要約を出力すると未出力の件数をリセットするが、移動平均の記録は残す
+        latency.log_summary();
+        assert_eq!(latency.recorded_since_summary, 0);
+        assert_eq!(latency.average(), Some(Duration::from_millis(50)));
+    }
+
+    // Tests for heartbeat
+    #[test]
+    fn test_heartbeat_advances_mtime_across_iterations() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("heartbeat");
+        let mut heartbeat = Heartbeat::default();
+        let start = Instant::now();
+
+        heartbeat.beat(Some(&path), start);
+        assert!(path.exists());
+
+        // 前回の更新日時を過去に戻してから、次の繰り返しで更新されることを確認する
+        let past = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        heartbeat.beat(Some(&path), start + HEARTBEAT_MIN_INTERVAL);
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        assert!(modified > past);
+    }
+
+    #[test]
+    fn test_heartbeat_throttles_updates() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("heartbeat");
+        let mut heartbeat = Heartbeat::default();
+        let start = Instant::now();
+        heartbeat.beat(Some(&path), start);
+
+        let past = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        heartbeat.beat(Some(&path), start + Duration::from_millis(100));
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), past);
+    }
+
+    #[test]
+    fn test_heartbeat_without_path_is_noop() {
+        let mut heartbeat = Heartbeat::default();
+        heartbeat.beat(None, Instant::now());
+        assert_eq!(heartbeat.last_beat, None);
+    }
+
+    #[test]
+    fn test_heartbeat_failure_does_not_stop() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("missing").join("heartbeat");
+        let mut heartbeat = Heartbeat::default();
+        let start = Instant::now();
+        heartbeat.beat(Some(&path), start);
+        assert!(heartbeat.failing);
+
+        // ディレクトリが作成されれば次の繰り返しで再び更新する
+        fs::create_dir(temp_dir.path().join("missing")).unwrap();
+        heartbeat.beat(Some(&path), start + HEARTBEAT_MIN_INTERVAL);
+        assert!(!heartbeat.failing);
+        assert!(path.exists());
+    }
+
+    // Tests for metrics
+    #[test]
+    fn test_metrics_render() {
+        let mut metrics = Metrics {
+            reloads: ReloadTotals {
+                successes: 2,
+                failures: 1,
+            },
+            ..Default::default()
+        };
+        let mut stats = PollStats::default();
+        stats.record(PollResult::Formatted);
+        stats.record(PollResult::Unchanged);
+        metrics.record_polls(
+            &stats,
+            UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+        );
+        // 整形しなかった確認では最後に整形した時刻を更新しない
+        let mut unchanged = PollStats::default();
+        unchanged.record(PollResult::Unchanged);
+        metrics.record_polls(&unchanged, UNIX_EPOCH + Duration::from_secs(1_800_000_000));
+
+        let text = metrics.render();
+        for line in [
+            "clipboard_formatter_polls_total 3",
+            "clipboard_formatter_formats_total 1",
+            "clipboard_formatter_config_reloads_total{result=\"success\"} 2",
+            "clipboard_formatter_config_reloads_total{result=\"failure\"} 1",
+            "clipboard_formatter_last_format_timestamp_seconds 1700000000.500",
+            "# TYPE clipboard_formatter_polls_total counter",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} not in\n{text}");
+        }
+    }
+
+    #[test]
+    fn test_metrics_render_never_formatted() {
+        let text = Metrics::default().render();
+        assert!(text.contains("\nclipboard_formatter_last_format_timestamp_seconds 0.000\n"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_endpoint() {
+        use std::collections::HashMap;
+        use std::io::Read;
+
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let mut stats = PollStats::default();
+        stats.record(PollResult::Formatted);
+        lock_metrics(&metrics).record_polls(&stats, SystemTime::now());
+        let address = start_metrics_server(0, Arc::clone(&metrics)).unwrap();
+
+        let request = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = request("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        // コメント以外の行は「名前 値」の形式
+        let counters: HashMap<&str, f64> = body
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                (name, value.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(counters["clipboard_formatter_polls_total"], 1.0);
+        assert_eq!(counters["clipboard_formatter_formats_total"], 1.0);
+        assert_eq!(
+            counters["clipboard_formatter_config_reloads_total{result=\"failure\"}"],
+            0.0
+        );
+        assert!(counters["clipboard_formatter_last_format_timestamp_seconds"] > 0.0);
+
+        assert!(request("/").starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
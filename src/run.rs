@@ -0,0 +1,1840 @@
+//! コマンドライン引数の解析と常駐の実行
+
+use crate::clipboard::{
+    open_primary_stash, retry_with_backoff, Clipboard, ClipboardChangeListener, ContentFingerprint,
+    CLIPBOARD_BACKEND,
+};
+use crate::config::{
+    describe_resolved_config, init_config, is_json_config, AppConfig, ClipboardMode, ConfigManager,
+    FormatOn, Selection, StashTarget, DEFAULT_CONFIG, ENV_VAR_PATTERN,
+};
+use crate::format::{format_clipboard_content, FULL_WIDTH_PATTERN, HALF_WIDTH_PATTERN};
+use crate::handler::{
+    default_undo_state_path, handle_clipboard_processing, handle_paste, handle_preview_request,
+    ClipboardHandler, HandlerOptions, LogFormat, PREVIEW_REQUESTED, URL_PATTERN,
+};
+use crate::metrics::{
+    lock_metrics, start_metrics_server, Heartbeat, Metrics, PollStats, POLL_STATS_INTERVAL,
+};
+use crate::x11::{
+    create_change_listener, create_focus_lookup, create_paste_offer, create_source_lookup,
+};
+use anyhow::{Context, Result};
+use env_logger::Builder as EnvLoggerBuilder;
+use global_hotkey::hotkey::HotKey;
+use global_hotkey::{GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
+use log::{debug, info, warn};
+use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use regex::Regex;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub(crate) const HELP: &str = "\
+Usage: clipboard-formatter [OPTIONS]
+
+Options:
+  --config <PATH>    Use the given config file instead of the default location
+  --once, --oneshot  Format the clipboard once and exit
+  --dry-run          Log the diff without writing to the clipboard
+  --check-config     Validate the config file and exit
+  --undo             Restore the clipboard contents before the last format
+  --print-config-path
+                     Print the resolved config file path and exit
+  --init             Write a fully commented config template to the config path and exit
+  --force            Overwrite an existing config file with --init
+  --log-format=FMT   Log format for formatting results: text (default) or json
+  --watch-off        Do not watch the config file for changes
+  --doctor           Check the environment and config, print a report and exit
+  --stdin            Format standard input to standard output without using the clipboard
+  --format-string <TEXT>
+                     Format the given text, print the result and exit
+  --test-fixtures <PATH>
+                     Check input/expected pairs in a TOML or JSON file and exit
+  -q, --quiet        Do not print the version banner and only log warnings and errors
+  -v, --verbose      Log the resolved config at startup; repeat (-vv) to also enable debug logs
+  -V, --version      Print the version and exit
+  -h, --help         Print this help and exit";
+
+fn show_self_version() {
+    println!("clipboard-formatter v{}", env!("CARGO_PKG_VERSION"));
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct CliArgs {
+    config_path: Option<PathBuf>,
+    once: bool,
+    dry_run: bool,
+    check_config: bool,
+    undo: bool,
+    print_config_path: bool,
+    init: bool,
+    force: bool,
+    log_format: LogFormat,
+    watch_off: bool,
+    doctor: bool,
+    stdin: bool,
+    format_string: Option<String>,
+    test_fixtures: Option<PathBuf>,
+    quiet: bool,
+    verbose: u8,
+    version: bool,
+    help: bool,
+}
+
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<CliArgs> {
+    let mut cli_args = CliArgs::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if let Some(path) = arg.strip_prefix("--config=") {
+            cli_args.config_path = Some(PathBuf::from(path));
+            continue;
+        }
+        if let Some(text) = arg.strip_prefix("--format-string=") {
+            cli_args.format_string = Some(text.to_string());
+            continue;
+        }
+        if let Some(path) = arg.strip_prefix("--test-fixtures=") {
+            cli_args.test_fixtures = Some(PathBuf::from(path));
+            continue;
+        }
+        match arg.as_str() {
+            "--config" => {
+                let path = args.next().context("--config requires a path")?;
+                cli_args.config_path = Some(PathBuf::from(path));
+            }
+            "--once" | "--oneshot" => cli_args.once = true,
+            "--dry-run" => cli_args.dry_run = true,
+            "--check-config" => cli_args.check_config = true,
+            "--undo" => cli_args.undo = true,
+            "--print-config-path" => cli_args.print_config_path = true,
+            "--init" => cli_args.init = true,
+            "--force" => cli_args.force = true,
+            "--log-format=text" => cli_args.log_format = LogFormat::Text,
+            "--log-format=json" => cli_args.log_format = LogFormat::Json,
+            "--watch-off" => cli_args.watch_off = true,
+            "--doctor" => cli_args.doctor = true,
+            "--stdin" => cli_args.stdin = true,
+            "--format-string" => {
+                let text = args.next().context("--format-string requires a text")?;
+                cli_args.format_string = Some(text);
+            }
+            "--test-fixtures" => {
+                let path = args.next().context("--test-fixtures requires a path")?;
+                cli_args.test_fixtures = Some(PathBuf::from(path));
+            }
+            "-q" | "--quiet" => cli_args.quiet = true,
+            "-v" | "--verbose" => cli_args.verbose = cli_args.verbose.saturating_add(1),
+            "-vv" => cli_args.verbose = cli_args.verbose.saturating_add(2),
+            "-V" | "--version" => cli_args.version = true,
+            "-h" | "--help" => cli_args.help = true,
+            _ => anyhow::bail!("Unknown argument: {arg}\n\n{HELP}"),
+        }
+    }
+    if cli_args.quiet && cli_args.verbose > 0 {
+        anyhow::bail!("--quiet and --verbose cannot be used together");
+    }
+    Ok(cli_args)
+}
+
+// global-hotkeyの表記に合わせ、"win"を"super"として扱う
+fn parse_hotkey(spec: &str) -> Result<HotKey> {
+    let normalized = spec
+        .split('+')
+        .map(|part| match part.trim() {
+            part if part.eq_ignore_ascii_case("win") => "super",
+            part => part,
+        })
+        .collect::<Vec<_>>()
+        .join("+");
+    normalized
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid hotkey {spec:?}: {e}"))
+}
+
+fn toggle_formatting(paused: &AtomicBool) {
+    if paused.fetch_xor(true, Ordering::SeqCst) {
+        info!("Formatting resumed");
+    } else {
+        info!("Formatting paused");
+    }
+}
+
+type HotkeyCallbacks = Arc<Mutex<HashMap<u32, Box<dyn Fn() + Send>>>>;
+
+// マネージャーを破棄するとホットキーの登録も解除されるため、実行中は保持し続ける
+struct HotkeyRegistry {
+    manager: GlobalHotKeyManager,
+    callbacks: HotkeyCallbacks,
+}
+
+impl HotkeyRegistry {
+    fn new() -> Result<Self> {
+        check_hotkey_support()?;
+        let manager = GlobalHotKeyManager::new()
+            .map_err(|e| anyhow::anyhow!("Failed to create hotkey manager: {e}"))?;
+        let callbacks = HotkeyCallbacks::default();
+        let handlers = Arc::clone(&callbacks);
+        GlobalHotKeyEvent::set_event_handler(Some(move |event: GlobalHotKeyEvent| {
+            if event.state() != HotKeyState::Pressed {
+                return;
+            }
+            let handlers = handlers.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(on_press) = handlers.get(&event.id()) {
+                on_press();
+            }
+        }));
+        Ok(Self { manager, callbacks })
+    }
+
+    fn register(&self, hotkey: HotKey, on_press: impl Fn() + Send + 'static) -> Result<()> {
+        self.manager
+            .register(hotkey)
+            .map_err(|e| anyhow::anyhow!("Failed to grab hotkey: {e}"))?;
+        self.callbacks
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to register hotkey callback: {e}"))?
+            .insert(hotkey.id(), Box::new(on_press));
+        Ok(())
+    }
+
+    // ホットキーのイベントを受け取るために、run_loopの待機中にイベントループを回す必要があればその待機方法を返す
+    fn event_pump(&self) -> Option<Box<dyn ClipboardChangeListener>> {
+        create_hotkey_event_pump()
+    }
+}
+
+// global-hotkeyはXサーバーに接続できなくても登録に成功したように振る舞うため、先に接続を確認する
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn check_hotkey_support() -> Result<()> {
+    xcb::Connection::connect(None)
+        .map(|_| ())
+        .map_err(|e| anyhow::anyhow!("Failed to connect to X server: {e}"))
+}
+
+#[cfg(any(windows, target_os = "macos"))]
+fn check_hotkey_support() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(any(
+    windows,
+    target_os = "macos",
+    all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    )
+)))]
+fn check_hotkey_support() -> Result<()> {
+    anyhow::bail!("Global hotkeys are not supported on this platform")
+}
+
+// X11ではglobal-hotkeyが自身のスレッドでイベントを受け取るため、待機中に何もする必要はない
+#[cfg(not(any(windows, target_os = "macos")))]
+fn create_hotkey_event_pump() -> Option<Box<dyn ClipboardChangeListener>> {
+    None
+}
+
+// WindowsとmacOSでは、ホットキーを登録したスレッド（macOSではメインスレッド）でイベントループを回さないと
+// キーのイベントが届かない。run_loopはメインスレッドで待機するため、待機の間にイベントを処理する
+#[cfg(any(windows, target_os = "macos"))]
+fn create_hotkey_event_pump() -> Option<Box<dyn ClipboardChangeListener>> {
+    Some(Box::new(HotkeyEventPump))
+}
+
+// 待機の間にホットキーのイベントを処理し、ポーリングと同じく待機後は常に変更された可能性があるとみなす
+#[cfg(any(windows, target_os = "macos"))]
+struct HotkeyEventPump;
+
+#[cfg(windows)]
+impl ClipboardChangeListener for HotkeyEventPump {
+    fn wait_for_change(&self, timeout: Duration) -> bool {
+        use windows_sys::Win32::UI::WindowsAndMessaging::{
+            DispatchMessageW, MsgWaitForMultipleObjects, PeekMessageW, TranslateMessage, MSG,
+            PM_REMOVE, QS_ALLINPUT,
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            // SAFETY: msgはPeekMessageWが書き込む領域で、取り出したメッセージは同じスレッドで処理する
+            unsafe {
+                let mut msg: MSG = std::mem::zeroed();
+                while PeekMessageW(&mut msg, std::ptr::null_mut(), 0, 0, PM_REMOVE) != 0 {
+                    TranslateMessage(&msg);
+                    DispatchMessageW(&msg);
+                }
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return true;
+            }
+            let millis = u32::try_from(remaining.as_millis()).unwrap_or(u32::MAX);
+            // SAFETY: 待機するハンドルはなく、メッセージが届くか時間が経過するまで待つだけ
+            unsafe {
+                MsgWaitForMultipleObjects(0, std::ptr::null(), 0, millis, QS_ALLINPUT);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl ClipboardChangeListener for HotkeyEventPump {
+    fn wait_for_change(&self, timeout: Duration) -> bool {
+        use objc2::MainThreadMarker;
+        use objc2_app_kit::{NSApplication, NSEventMask};
+        use objc2_foundation::{NSDate, NSDefaultRunLoopMode};
+        let Some(mtm) = MainThreadMarker::new() else {
+            // メインスレッド以外ではイベントを受け取れないため、ポーリングと同じく待つだけにする
+            thread::sleep(timeout);
+            return true;
+        };
+        let app = NSApplication::sharedApplication(mtm);
+        let deadline = NSDate::dateWithTimeIntervalSinceNow(timeout.as_secs_f64());
+        // SAFETY: NSDefaultRunLoopModeはAppKitが定義する変更されない定数
+        let mode = unsafe { NSDefaultRunLoopMode };
+        while let Some(event) = app.nextEventMatchingMask_untilDate_inMode_dequeue(
+            NSEventMask::Any,
+            Some(&deadline),
+            mode,
+            true,
+        ) {
+            app.sendEvent(&event);
+        }
+        true
+    }
+}
+
+// --test-fixturesで確認する整形前後の組み合わせ
+#[derive(Debug, serde::Deserialize)]
+struct FixtureCase {
+    #[serde(default)]
+    name: Option<String>,
+    input: String,
+    expected: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Fixtures {
+    #[serde(rename = "case", default)]
+    cases: Vec<FixtureCase>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct FixtureReport {
+    passed: usize,
+    failures: Vec<String>,
+}
+
+// テストケースをクリップボードと同じ手順で整形し、期待した結果と比べる
+fn run_fixtures(fixtures_path: &Path, config: &AppConfig) -> Result<FixtureReport> {
+    let text = fs::read_to_string(fixtures_path)
+        .with_context(|| format!("Failed to read fixtures {}", fixtures_path.display()))?;
+    let fixtures: Fixtures = if is_json_config(fixtures_path) {
+        serde_json::from_str(&text).context("Failed to parse fixtures")?
+    } else {
+        toml::from_str(&text).context("Failed to parse fixtures")?
+    };
+    let mut report = FixtureReport::default();
+    for (index, case) in fixtures.cases.iter().enumerate() {
+        let name = case
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("case {}", index + 1));
+        let actual = format_clipboard_content(&case.input, config)?.text;
+        if actual == case.expected {
+            report.passed += 1;
+        } else {
+            report.failures.push(format!(
+                "{name}: input {:?}, expected {:?}, got {:?}",
+                case.input, case.expected, actual
+            ));
+        }
+    }
+    Ok(report)
+}
+
+// --doctorの確認項目1件分の結果
+struct DoctorCheck {
+    name: &'static str,
+    result: Result<String>,
+    // falseの場合は失敗しても警告として扱い、終了コードに影響しない
+    critical: bool,
+}
+
+impl DoctorCheck {
+    fn report(&self) -> String {
+        match &self.result {
+            Ok(detail) => format!("[OK]   {}: {detail}", self.name),
+            Err(e) if self.critical => format!("[FAIL] {}: {e:#}", self.name),
+            Err(e) => format!("[WARN] {}: {e:#}", self.name),
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.critical && self.result.is_err()
+    }
+}
+
+// 設定ファイル（未作成の場合は作成先のディレクトリ）に書き込めるか確認する
+fn check_config_writable(config_path: &Path) -> Result<String> {
+    if config_path.exists() {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(config_path)
+            .with_context(|| format!("Cannot write to {}", config_path.display()))?;
+        return Ok(format!("{} is writable", config_path.display()));
+    }
+    // 作成されるディレクトリのうち、既に存在する最も近い親ディレクトリで確認する
+    let existing_dir = config_path
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.as_os_str().is_empty() || dir.is_dir())
+        .with_context(|| format!("Invalid config file path: {}", config_path.display()))?;
+    let existing_dir = if existing_dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing_dir
+    };
+    tempfile::NamedTempFile::new_in(existing_dir)
+        .with_context(|| format!("Cannot create files in {}", existing_dir.display()))?;
+    Ok(format!(
+        "{} does not exist yet and can be created",
+        config_path.display()
+    ))
+}
+
+// 設定ファイル（未作成の場合はデフォルトの設定）を読み込み、ルールの数を返す
+fn check_config_rules(config_path: &Path) -> Result<String> {
+    if config_path.exists() {
+        return ConfigManager::check_config(config_path);
+    }
+    let config = AppConfig::from_toml(DEFAULT_CONFIG)?;
+    Ok(format!(
+        "default config ({} replacement rules, {} regex replacement rules, {} exclusions)",
+        config.replacement_rules().count(),
+        config.regex_replacements.0.len(),
+        config.exclusions.len()
+    ))
+}
+
+fn check_builtin_regexes() -> Result<String> {
+    let patterns = [
+        FULL_WIDTH_PATTERN,
+        HALF_WIDTH_PATTERN,
+        URL_PATTERN,
+        ENV_VAR_PATTERN,
+    ];
+    for pattern in patterns {
+        Regex::new(pattern).with_context(|| format!("Invalid built-in pattern {pattern:?}"))?;
+    }
+    Ok(format!("{} built-in patterns compiled", patterns.len()))
+}
+
+fn check_clipboard() -> Result<String> {
+    ClipboardHandler::new(HandlerOptions::default())?;
+    Ok(format!(
+        "clipboard provider created ({CLIPBOARD_BACKEND} backend)"
+    ))
+}
+
+fn doctor_checks(config_path: Option<PathBuf>) -> Vec<DoctorCheck> {
+    let config_path = match ConfigManager::resolve_config_path(config_path) {
+        Ok(config_path) => config_path,
+        Err(e) => {
+            return vec![DoctorCheck {
+                name: "config path",
+                result: Err(e),
+                critical: true,
+            }]
+        }
+    };
+    vec![
+        DoctorCheck {
+            name: "config path",
+            result: Ok(config_path.display().to_string()),
+            critical: true,
+        },
+        DoctorCheck {
+            name: "config writable",
+            // 既存の設定ファイルが読み取り専用でも整形は行えるため警告にとどめる
+            critical: !config_path.exists(),
+            result: check_config_writable(&config_path),
+        },
+        DoctorCheck {
+            name: "config rules",
+            result: check_config_rules(&config_path),
+            critical: true,
+        },
+        DoctorCheck {
+            name: "built-in regex",
+            result: check_builtin_regexes(),
+            critical: true,
+        },
+        DoctorCheck {
+            name: "clipboard",
+            result: check_clipboard(),
+            critical: true,
+        },
+    ]
+}
+
+// 標準エラー出力と、設定されていればログファイルの両方にログを書き込む。
+// ログファイルは設定を読み込んだ後に指定するため、ロガーの初期化後に差し替えられるようにしている
+#[derive(Clone, Default)]
+struct LogWriter {
+    file: Arc<Mutex<Option<fs::File>>>,
+}
+
+impl LogWriter {
+    fn set_file(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create log directory: {}", dir.display()))?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file: {}", path.display()))?;
+        *self.file.lock().unwrap_or_else(PoisonError::into_inner) = Some(file);
+        Ok(())
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        std::io::stderr().write_all(buf)?;
+        if let Some(file) = self
+            .file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()?;
+        if let Some(file) = self
+            .file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// コマンドライン引数を解釈してアプリケーションを実行する
+pub fn run() -> Result<()> {
+    let cli_args = parse_args(env::args().skip(1))?;
+    if cli_args.help {
+        println!("{HELP}");
+        return Ok(());
+    }
+    if cli_args.version {
+        show_self_version();
+        return Ok(());
+    }
+    if cli_args.print_config_path {
+        let config_path = ConfigManager::resolve_config_path(cli_args.config_path)?;
+        println!("{}", config_path.display());
+        return Ok(());
+    }
+    if cli_args.init {
+        let config_path = ConfigManager::resolve_config_path(cli_args.config_path)?;
+        init_config(&config_path, cli_args.force)?;
+        println!("Created config: {}", config_path.display());
+        return Ok(());
+    }
+
+    // バージョンは常駐する場合だけ表示し、--stdinなどの出力に混ざらないようにする
+    let one_shot = cli_args.stdin
+        || cli_args.format_string.is_some()
+        || cli_args.test_fixtures.is_some()
+        || cli_args.once
+        || cli_args.check_config
+        || cli_args.doctor
+        || cli_args.undo;
+    let default_log_level = match (cli_args.quiet, cli_args.verbose) {
+        (true, _) => "warn",
+        (false, 0 | 1) => "info",
+        (false, _) => "debug",
+    };
+    let log_writer = LogWriter::default();
+    EnvLoggerBuilder::from_env(env_logger::Env::default().default_filter_or(default_log_level))
+        .target(env_logger::Target::Pipe(Box::new(log_writer.clone())))
+        .init();
+
+    if cli_args.stdin {
+        let config_manager = ConfigManager::new(cli_args.config_path)?;
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("Failed to read standard input")?;
+        let outcome = format_clipboard_content(&input, config_manager.get_config())?;
+        std::io::stdout()
+            .write_all(outcome.text.as_bytes())
+            .context("Failed to write standard output")?;
+        return Ok(());
+    }
+
+    if let Some(text) = &cli_args.format_string {
+        let config_manager = ConfigManager::new(cli_args.config_path)?;
+        let outcome = format_clipboard_content(text, config_manager.get_config())?;
+        println!("{}", outcome.text);
+        return Ok(());
+    }
+
+    if let Some(fixtures_path) = &cli_args.test_fixtures {
+        let config_manager = ConfigManager::new(cli_args.config_path)?;
+        let report = run_fixtures(fixtures_path, config_manager.get_config())?;
+        for failure in &report.failures {
+            println!("[FAIL] {failure}");
+        }
+        println!("{} passed, {} failed", report.passed, report.failures.len());
+        if !report.failures.is_empty() {
+            anyhow::bail!("{} fixture(s) failed", report.failures.len());
+        }
+        return Ok(());
+    }
+
+    if cli_args.doctor {
+        let checks = doctor_checks(cli_args.config_path);
+        for check in &checks {
+            println!("{}", check.report());
+        }
+        let failures = checks.iter().filter(|check| check.failed()).count();
+        if failures > 0 {
+            anyhow::bail!("{failures} critical check(s) failed");
+        }
+        return Ok(());
+    }
+
+    if cli_args.check_config {
+        let config_path = ConfigManager::resolve_config_path(cli_args.config_path)?;
+        println!("{}", ConfigManager::check_config(&config_path)?);
+        return Ok(());
+    }
+
+    let mut config_manager = ConfigManager::new(cli_args.config_path.clone())?;
+    // tee_stdoutの場合は、標準出力を整形結果だけにするためバージョンを表示しない
+    if !one_shot && !cli_args.quiet && !config_manager.get_config().app.tee_stdout {
+        show_self_version();
+    }
+    if let Some(log_file) = &config_manager.get_config().logging.file {
+        match log_writer.set_file(log_file) {
+            Ok(()) => info!("Logging to {}", log_file.display()),
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+    if cli_args.verbose > 0 {
+        let source = if config_manager.is_inline() {
+            "CLIPBOARD_FORMATTER_CONFIG".to_string()
+        } else {
+            config_manager.get_config_path().display().to_string()
+        };
+        info!(
+            "{}",
+            describe_resolved_config(config_manager.get_config(), &source)
+        );
+    }
+    // ログイン直後などディスプレイサーバーの準備ができていない場合に備えて再試行する
+    let app_settings = &config_manager.get_config().app;
+    let handler_options = HandlerOptions {
+        dry_run: cli_args.dry_run,
+        log_format: cli_args.log_format,
+        selection: app_settings.selection,
+    };
+    let mut clipboard_handler = retry_with_backoff(
+        app_settings.startup_retry_attempts,
+        Duration::from_millis(app_settings.startup_retry_delay),
+        || ClipboardHandler::new(handler_options),
+    )
+    .context("Failed to create clipboard handler")?;
+    match create_source_lookup(app_settings.selection) {
+        Ok(source_lookup) => {
+            clipboard_handler = clipboard_handler.with_source_lookup(source_lookup)
+        }
+        Err(e) => debug!("{e}; only skipping content this process wrote"),
+    }
+    if let Some(focus_lookup) = create_focus_lookup() {
+        clipboard_handler = clipboard_handler.with_focus_lookup(focus_lookup);
+    }
+    if app_settings.stash_original_to == Some(StashTarget::Primary) {
+        if app_settings.selection == Selection::Primary {
+            warn!(
+                "stash_original_to = \"primary\" is ignored because selection is also \"primary\""
+            );
+        } else {
+            match open_primary_stash() {
+                Ok(Some(stash)) => clipboard_handler = clipboard_handler.with_original_stash(stash),
+                Ok(None) => info!(
+                    "The PRIMARY selection is not available on this platform; use --undo to restore the original"
+                ),
+                Err(e) => warn!("{e}; use --undo to restore the original"),
+            }
+        }
+    }
+    match default_undo_state_path() {
+        Some(path) => clipboard_handler = clipboard_handler.with_undo_state_path(path),
+        None => warn!("Failed to get the state directory; --undo is not available"),
+    }
+
+    if cli_args.undo {
+        clipboard_handler.undo()?;
+        return Ok(());
+    }
+
+    if cli_args.once {
+        clipboard_handler.process_clipboard(config_manager.get_config())?;
+        return Ok(());
+    }
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_flag = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || shutdown_flag.store(true, Ordering::SeqCst))
+        .context("Failed to install signal handler")?;
+    install_termination_signal_handler(&shutdown)?;
+    if let Err(e) = install_reload_signal_handler() {
+        warn!("{e}");
+    }
+
+    // 設定ファイルを監視しない場合は起動時に読み込んだ設定を使い続ける
+    let mut config_watcher = if cli_args.watch_off || !config_manager.get_config().app.watch_config
+    {
+        info!("Config hot-reload is disabled");
+        None
+    } else {
+        let mut config_watcher = ConfigWatcher::default();
+        config_watcher.watch(&config_manager)?;
+        Some(config_watcher)
+    };
+
+    let change_listener = match config_manager.get_config().app.clipboard_mode {
+        ClipboardMode::Poll => None,
+        ClipboardMode::Event => {
+            match create_change_listener(config_manager.get_config().app.selection) {
+                Ok(listener) => {
+                    info!("Watching clipboard change events");
+                    Some(listener)
+                }
+                Err(e) => {
+                    warn!("{e}; falling back to polling");
+                    None
+                }
+            }
+        }
+    };
+
+    if config_manager.get_config().app.format_on == FormatOn::Paste {
+        match create_paste_offer(config_manager.get_config().app.selection) {
+            Ok(paste_offer) => {
+                clipboard_handler = clipboard_handler.with_paste_offer(paste_offer);
+                info!("Formatting copied content only when it is pasted");
+            }
+            Err(e) => warn!("{e}; formatting on copy instead"),
+        }
+    }
+
+    let paused = Arc::new(AtomicBool::new(false));
+    let hotkey_settings = &config_manager.get_config().hotkeys;
+    let hotkey_registry = if hotkey_settings.toggle.is_some() || hotkey_settings.preview.is_some() {
+        HotkeyRegistry::new()
+            .inspect_err(|e| warn!("Failed to register hotkey: {e}"))
+            .ok()
+    } else {
+        None
+    };
+    if let (Some(spec), Some(registry)) = (&hotkey_settings.toggle, &hotkey_registry) {
+        let paused = Arc::clone(&paused);
+        match parse_hotkey(spec)
+            .and_then(|hotkey| registry.register(hotkey, move || toggle_formatting(&paused)))
+        {
+            Ok(()) => info!("Press {spec} to pause or resume formatting"),
+            Err(e) => warn!("Failed to register hotkey: {e}"),
+        }
+    }
+    if let (Some(spec), Some(registry)) = (&hotkey_settings.preview, &hotkey_registry) {
+        match parse_hotkey(spec).and_then(|hotkey| {
+            registry.register(hotkey, || PREVIEW_REQUESTED.store(true, Ordering::SeqCst))
+        }) {
+            Ok(()) => info!("Press {spec} to preview formatting without changing the clipboard"),
+            Err(e) => warn!("Failed to register hotkey: {e}"),
+        }
+    }
+    // クリップボードの変更通知がなければ、待機の間にホットキーのイベントを処理する
+    let change_listener = match (change_listener, &hotkey_registry) {
+        (None, Some(registry)) => registry.event_pump(),
+        (change_listener, _) => change_listener,
+    };
+
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    let metrics_enabled = match config_manager.get_config().metrics.port {
+        Some(port) => match start_metrics_server(port, Arc::clone(&metrics)) {
+            Ok(address) => {
+                info!("Serving metrics on http://{address}/metrics");
+                true
+            }
+            Err(e) => {
+                warn!("{e:#}");
+                false
+            }
+        },
+        None => false,
+    };
+
+    run_loop(
+        &mut clipboard_handler,
+        &mut config_manager,
+        config_watcher.as_mut(),
+        &shutdown,
+        &paused,
+        change_listener.as_deref(),
+        metrics_enabled.then_some(&*metrics),
+    );
+    Ok(())
+}
+
+fn run_loop<C: Clipboard>(
+    clipboard_handler: &mut ClipboardHandler<C>,
+    config_manager: &mut ConfigManager,
+    mut config_watcher: Option<&mut ConfigWatcher>,
+    shutdown: &AtomicBool,
+    paused: &AtomicBool,
+    change_listener: Option<&dyn ClipboardChangeListener>,
+    metrics: Option<&Mutex<Metrics>>,
+) {
+    let mut previous_clipboard = ContentFingerprint::default();
+    let mut clipboard_changed = true;
+    let mut poll_stats = PollStats::default();
+    let mut poll_stats_since = Instant::now();
+    let mut heartbeat = Heartbeat::default();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if clipboard_changed {
+            let mut iteration_stats = PollStats::default();
+            previous_clipboard = handle_clipboard_processing(
+                clipboard_handler,
+                config_manager.get_config(),
+                previous_clipboard,
+                paused.load(Ordering::SeqCst),
+                &mut iteration_stats,
+            );
+            poll_stats.merge(&iteration_stats);
+            if let Some(metrics) = metrics {
+                lock_metrics(metrics).record_polls(&iteration_stats, SystemTime::now());
+            }
+        }
+        if poll_stats_since.elapsed() >= POLL_STATS_INTERVAL {
+            poll_stats.log_summary(poll_stats_since.elapsed());
+            clipboard_handler.format_latency.log_summary();
+            poll_stats = PollStats::default();
+            poll_stats_since = Instant::now();
+        }
+
+        handle_preview_request(
+            clipboard_handler,
+            config_manager.get_config(),
+            &PREVIEW_REQUESTED,
+        );
+        handle_paste(clipboard_handler, config_manager.get_config());
+        clipboard_handler.flush_notification_digest(config_manager.get_config(), Instant::now());
+
+        let mut reloaded = handle_reload_request(config_manager, &RELOAD_REQUESTED);
+        if let Some(config_watcher) = config_watcher.as_deref_mut() {
+            reloaded |= handle_config_reload(config_manager, &config_watcher.rx);
+            if reloaded {
+                if let Err(e) = config_watcher.watch(config_manager) {
+                    warn!("{e}");
+                }
+            }
+        }
+        if let Some(metrics) = metrics {
+            lock_metrics(metrics).reloads = config_manager.reload_totals;
+        }
+        heartbeat.beat(
+            config_manager.get_config().app.heartbeat_file.as_deref(),
+            Instant::now(),
+        );
+
+        let mut poll_interval =
+            Duration::from_millis(config_manager.get_config().app.clipboard_poll_interval);
+        // 整形を見送った内容があれば、変更がなくても間隔が経過した時点で整形する
+        let pending = clipboard_handler.format_pending;
+        if pending {
+            let delay = [
+                clipboard_handler.format_delay(config_manager.get_config()),
+                clipboard_handler.write_backoff(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            if let Some(delay) = delay {
+                poll_interval = poll_interval.min(delay);
+            }
+        }
+        clipboard_changed = match change_listener {
+            Some(listener) => listener.wait_for_change(poll_interval) || pending,
+            None => {
+                thread::sleep(poll_interval);
+                true
+            }
+        };
+    }
+    info!("Shutting down");
+}
+
+fn setup_file_watcher(
+    config_path: &Path,
+    config: &AppConfig,
+    tx: Sender<notify::Result<notify::Event>>,
+) -> Result<RecommendedWatcher> {
+    let notify_config = Config::default()
+        .with_poll_interval(Duration::from_millis(config.app.config_reload_interval));
+    let mut watcher: RecommendedWatcher =
+        Watcher::new(tx, notify_config).context("Failed to initialize file watcher")?;
+    // ファイルを直接監視すると削除された時点で監視が外れるため、ディレクトリを監視して
+    // 削除後に作り直された設定ファイルも検知できるようにする
+    let config_dir = match config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher
+        .watch(config_dir, RecursiveMode::NonRecursive)
+        .context("Failed to watch config file")?;
+    Ok(watcher)
+}
+
+// 設定ファイルの監視。config_reload_intervalが変わったらwatcherを作り直す
+pub(crate) struct ConfigWatcher {
+    tx: Sender<notify::Result<notify::Event>>,
+    rx: Receiver<notify::Result<notify::Event>>,
+    pub(crate) watcher: Option<RecommendedWatcher>,
+    reload_interval: u64,
+}
+
+impl Default for ConfigWatcher {
+    fn default() -> Self {
+        let (tx, rx) = channel();
+        Self {
+            tx,
+            rx,
+            watcher: None,
+            reload_interval: 0,
+        }
+    }
+}
+
+impl ConfigWatcher {
+    // watcherを作成または作り直した場合はtrueを返す
+    pub(crate) fn watch(&mut self, config_manager: &ConfigManager) -> Result<bool> {
+        let config = config_manager.get_config();
+        if config_manager.is_inline()
+            || self.watcher.is_some() && self.reload_interval == config.app.config_reload_interval
+        {
+            return Ok(false);
+        }
+        let watcher =
+            setup_file_watcher(config_manager.get_config_path(), config, self.tx.clone())?;
+        if self.watcher.replace(watcher).is_some() {
+            info!(
+                "Config reload interval changed to {}ms",
+                config.app.config_reload_interval
+            );
+        }
+        self.reload_interval = config.app.config_reload_interval;
+        Ok(true)
+    }
+}
+
+// SIGHUPを受け取ると立つフラグ。ループ内で確認して設定ファイルを再読み込みする
+static RELOAD_REQUESTED: LazyLock<Arc<AtomicBool>> =
+    LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+
+#[cfg(unix)]
+fn install_reload_signal_handler() -> Result<()> {
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&RELOAD_REQUESTED))
+        .context("Failed to install SIGHUP handler")?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install_reload_signal_handler() -> Result<()> {
+    Ok(())
+}
+
+// SIGTERMを受け取ると終了のフラグを立てる（SIGINTはctrlcで受け取る）
+#[cfg(unix)]
+fn install_termination_signal_handler(shutdown: &Arc<AtomicBool>) -> Result<()> {
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(shutdown))
+        .context("Failed to install SIGTERM handler")?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install_termination_signal_handler(_shutdown: &Arc<AtomicBool>) -> Result<()> {
+    Ok(())
+}
+
+// 再読み込みが要求されていれば設定ファイルを読み込み、trueを返す
+fn handle_reload_request(config_manager: &mut ConfigManager, requested: &AtomicBool) -> bool {
+    if !requested.swap(false, Ordering::SeqCst) {
+        return false;
+    }
+    info!("Reloading config on SIGHUP");
+    let _ = config_manager.reload_config();
+    true
+}
+
+fn handle_config_reload(
+    config_manager: &mut ConfigManager,
+    rx: &Receiver<notify::Result<notify::Event>>,
+) -> bool {
+    // ディレクトリ内の他のファイル（エディタの一時ファイルなど）のイベントも届くため、溜まっている分をまとめて確認する
+    let config_path = config_manager.get_config_path();
+    let mut config_changed = false;
+    loop {
+        match rx.try_recv() {
+            Ok(event) => config_changed |= is_config_event(&event, config_path),
+            Err(std::sync::mpsc::TryRecvError::Empty) => {
+                // No events, continue normally
+                break;
+            }
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                warn!("File watcher disconnected");
+                break;
+            }
+        }
+    }
+    if !config_changed {
+        return false;
+    }
+    // エディタは複数回に分けて書き込むことがあるため、設定ファイルのイベントが落ち着くまで待ってから1回だけ読み込む。
+    // ログファイルなどが同じディレクトリで書き込まれ続けても監視を止めないよう、待ち時間の合計には上限を設ける
+    let debounce = Duration::from_millis(config_manager.get_config().app.config_reload_debounce);
+    let deadline = Instant::now() + debounce * CONFIG_RELOAD_MAX_DEBOUNCES;
+    let mut quiet_until = Instant::now() + debounce;
+    while let Some(timeout) = quiet_until
+        .min(deadline)
+        .checked_duration_since(Instant::now())
+    {
+        match rx.recv_timeout(timeout) {
+            Ok(event) if is_config_event(&event, config_path) => {
+                quiet_until = Instant::now() + debounce;
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    // dotfilesの同期などで削除された場合は、作り直されるまで読み込み済みの設定を使い続ける
+    if !config_path.exists() {
+        warn!(
+            "Config file {} was removed; keeping the current config until it is recreated",
+            config_path.display()
+        );
+        return false;
+    }
+    let _ = config_manager.reload_config();
+    true
+}
+
+// 設定ファイルの再読み込みを待つ時間の上限（config_reload_debounceの倍数）
+const CONFIG_RELOAD_MAX_DEBOUNCES: u32 = 10;
+
+fn is_config_event(event: &notify::Result<notify::Event>, config_path: &Path) -> bool {
+    event.iter().any(|event| {
+        event
+            .paths
+            .iter()
+            .any(|path| is_config_event_path(path, config_path))
+    })
+}
+
+// 監視しているのは設定ファイルのディレクトリだけなので、ファイル名が同じなら設定ファイルのイベント
+fn is_config_event_path(path: &Path, config_path: &Path) -> bool {
+    path == config_path || path.file_name().is_some() && path.file_name() == config_path.file_name()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ChannelChangeListener;
+    use crate::config::AppSettings;
+
+    use crate::test_support::MockClipboard;
+    use global_hotkey::hotkey::{Code, Modifiers};
+
+    use tempfile::tempdir;
+
+    // Tests for --test-fixtures
+    #[test]
+    fn test_run_fixtures() {
+        let temp_dir = tempdir().unwrap();
+        let fixtures_path = temp_dir.path().join("fixtures.toml");
+        fs::write(
+            &fixtures_path,
+            r#"
+            [[case]]
+            name = "passing"
+            input = "ＡＢＣ，"
+            expected = "ABC, "
+
+            [[case]]
+            input = "ＡＢＣ"
+            expected = "ＡＢＣ"
+            "#,
+        )
+        .unwrap();
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+
+        let report = run_fixtures(&fixtures_path, &config).unwrap();
+        assert_eq!(report.passed, 1);
+        // 名前のないケースは番号で表示する
+        assert_eq!(
+            report.failures,
+            ["case 2: input \"ＡＢＣ\", expected \"ＡＢＣ\", got \"ABC\""]
+        );
+    }
+
+    #[test]
+    fn test_run_fixtures_json() {
+        let temp_dir = tempdir().unwrap();
+        let fixtures_path = temp_dir.path().join("fixtures.json");
+        fs::write(
+            &fixtures_path,
+            r#"{"case": [{"input": "１２３", "expected": "123"}]}"#,
+        )
+        .unwrap();
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+
+        let report = run_fixtures(&fixtures_path, &config).unwrap();
+        assert_eq!(
+            report,
+            FixtureReport {
+                passed: 1,
+                failures: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn test_example_fixtures_pass_with_default_config() {
+        let config = AppConfig::from_toml(DEFAULT_CONFIG).unwrap();
+        let report = run_fixtures(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/fixtures.toml"),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(report.failures, Vec::<String>::new());
+        assert_eq!(report.passed, 3);
+    }
+
+    // Tests for --doctor
+    #[test]
+    fn test_check_config_writable() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, DEFAULT_CONFIG).unwrap();
+        let detail = check_config_writable(&config_path).unwrap();
+        assert!(detail.ends_with("is writable"), "{detail}");
+
+        // 未作成のディレクトリ内のパスは、存在する親ディレクトリで確認する
+        let config_path = temp_dir.path().join("a").join("b").join("config.toml");
+        let detail = check_config_writable(&config_path).unwrap();
+        assert!(detail.ends_with("can be created"), "{detail}");
+        assert!(!temp_dir.path().join("a").exists());
+    }
+
+    #[test]
+    fn test_check_config_rules() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+
+        // 設定ファイルがなければデフォルトの設定を確認する
+        let detail = check_config_rules(&config_path).unwrap();
+        assert!(detail.starts_with("default config"), "{detail}");
+        assert!(detail.contains("4 replacement rules"), "{detail}");
+        assert!(!config_path.exists());
+
+        fs::write(&config_path, "[app]\nclipboard_poll_interval = \"fast\"\n").unwrap();
+        assert!(check_config_rules(&config_path).is_err());
+    }
+
+    #[test]
+    fn test_check_builtin_regexes() {
+        assert_eq!(
+            check_builtin_regexes().unwrap(),
+            "4 built-in patterns compiled"
+        );
+    }
+
+    #[test]
+    fn test_doctor_check_report() {
+        let ok = DoctorCheck {
+            name: "clipboard",
+            result: Ok("clipboard provider created".to_string()),
+            critical: true,
+        };
+        assert_eq!(ok.report(), "[OK]   clipboard: clipboard provider created");
+        assert!(!ok.failed());
+
+        let fail = DoctorCheck {
+            name: "clipboard",
+            result: Err(anyhow::anyhow!("no display")),
+            critical: true,
+        };
+        assert_eq!(fail.report(), "[FAIL] clipboard: no display");
+        assert!(fail.failed());
+
+        // 重要でない項目の失敗は警告として扱う
+        let warn = DoctorCheck {
+            name: "config writable",
+            result: Err(anyhow::anyhow!("read-only")),
+            critical: false,
+        };
+        assert_eq!(warn.report(), "[WARN] config writable: read-only");
+        assert!(!warn.failed());
+    }
+
+    // Tests for logging to a file
+    #[test]
+    fn test_log_writer_writes_to_file() {
+        let temp_dir = tempdir().unwrap();
+        let log_path = temp_dir.path().join("logs").join("clipboard-formatter.log");
+        let mut writer = LogWriter::default();
+
+        // ファイルを指定する前のログはファイルに書き込まれない
+        writer.write_all(b"before\n").unwrap();
+        writer.set_file(&log_path).unwrap();
+        let mut logger = writer.clone();
+        logger.write_all(b"[INFO] Formatted\n").unwrap();
+        logger.flush().unwrap();
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "[INFO] Formatted\n");
+
+        // 既存のファイルには追記する
+        let mut writer = LogWriter::default();
+        writer.set_file(&log_path).unwrap();
+        writer.write_all(b"[INFO] Again\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(&log_path).unwrap(),
+            "[INFO] Formatted\n[INFO] Again\n"
+        );
+    }
+
+    // Tests for parse_args
+    #[test]
+    fn test_parse_args_no_args() {
+        let cli_args = parse_args(Vec::<String>::new()).unwrap();
+        assert_eq!(cli_args, CliArgs::default());
+    }
+
+    #[test]
+    fn test_parse_args_once() {
+        let cli_args = parse_args(vec!["--once".to_string()]).unwrap();
+        assert!(cli_args.once);
+
+        // --oneshotは--onceの別名
+        let cli_args = parse_args(vec!["--oneshot".to_string()]).unwrap();
+        assert!(cli_args.once);
+    }
+
+    #[test]
+    fn test_parse_args_dry_run() {
+        let cli_args = parse_args(vec!["--dry-run".to_string(), "--once".to_string()]).unwrap();
+        assert!(cli_args.dry_run);
+        assert!(cli_args.once);
+    }
+
+    #[test]
+    fn test_parse_args_undo() {
+        let cli_args = parse_args(vec!["--undo".to_string()]).unwrap();
+        assert!(cli_args.undo);
+    }
+
+    #[test]
+    fn test_parse_args_print_config_path() {
+        let cli_args = parse_args(vec![
+            "--print-config-path".to_string(),
+            "--config=work.toml".to_string(),
+        ])
+        .unwrap();
+        assert!(cli_args.print_config_path);
+        assert_eq!(cli_args.config_path, Some(PathBuf::from("work.toml")));
+    }
+
+    #[test]
+    fn test_parse_args_watch_off() {
+        let cli_args = parse_args(vec!["--init".to_string(), "--force".to_string()]).unwrap();
+        assert!(cli_args.init);
+        assert!(cli_args.force);
+
+        let cli_args = parse_args(vec!["--watch-off".to_string()]).unwrap();
+        assert!(cli_args.watch_off);
+        assert!(!parse_args(vec![]).unwrap().watch_off);
+    }
+
+    #[test]
+    fn test_parse_args_doctor() {
+        let cli_args = parse_args(vec!["--doctor".to_string()]).unwrap();
+        assert!(cli_args.doctor);
+    }
+
+    #[test]
+    fn test_parse_args_stdin() {
+        let cli_args = parse_args(vec!["--stdin".to_string()]).unwrap();
+        assert!(cli_args.stdin);
+    }
+
+    #[test]
+    fn test_parse_args_format_string() {
+        let cli_args =
+            parse_args(vec!["--format-string".to_string(), "ＡＢＣ".to_string()]).unwrap();
+        assert_eq!(cli_args.format_string.as_deref(), Some("ＡＢＣ"));
+        let cli_args = parse_args(vec!["--format-string=--ＡＢＣ".to_string()]).unwrap();
+        assert_eq!(cli_args.format_string.as_deref(), Some("--ＡＢＣ"));
+        assert!(parse_args(vec!["--format-string".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_test_fixtures() {
+        let cli_args = parse_args(vec![
+            "--test-fixtures".to_string(),
+            "fixtures.toml".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(cli_args.test_fixtures, Some(PathBuf::from("fixtures.toml")));
+        let cli_args = parse_args(vec!["--test-fixtures=cases.json".to_string()]).unwrap();
+        assert_eq!(cli_args.test_fixtures, Some(PathBuf::from("cases.json")));
+        assert!(parse_args(vec!["--test-fixtures".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_verbose() {
+        assert_eq!(parse_args(Vec::<String>::new()).unwrap().verbose, 0);
+        assert_eq!(parse_args(vec!["-v".to_string()]).unwrap().verbose, 1);
+        assert_eq!(parse_args(vec!["-vv".to_string()]).unwrap().verbose, 2);
+        assert_eq!(
+            parse_args(vec!["--verbose".to_string(), "-v".to_string()])
+                .unwrap()
+                .verbose,
+            2
+        );
+        assert!(parse_args(vec!["-q".to_string(), "-v".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_quiet_and_version() {
+        let cli_args = parse_args(vec!["-q".to_string(), "--version".to_string()]).unwrap();
+        assert!(cli_args.quiet);
+        assert!(cli_args.version);
+        let cli_args = parse_args(vec!["--quiet".to_string(), "-V".to_string()]).unwrap();
+        assert!(cli_args.quiet);
+        assert!(cli_args.version);
+    }
+
+    #[test]
+    fn test_parse_args_log_format() {
+        let cli_args = parse_args(vec!["--log-format=json".to_string()]).unwrap();
+        assert_eq!(cli_args.log_format, LogFormat::Json);
+        let cli_args = parse_args(vec!["--log-format=text".to_string()]).unwrap();
+        assert_eq!(cli_args.log_format, LogFormat::Text);
+        assert!(parse_args(vec!["--log-format=xml".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_config_path() {
+        let cli_args =
+            parse_args(vec!["--config".to_string(), "/tmp/work.toml".to_string()]).unwrap();
+        assert_eq!(cli_args.config_path, Some(PathBuf::from("/tmp/work.toml")));
+
+        let cli_args = parse_args(vec!["--config=/tmp/home.toml".to_string()]).unwrap();
+        assert_eq!(cli_args.config_path, Some(PathBuf::from("/tmp/home.toml")));
+
+        // パスが指定されていない場合はエラー
+        assert!(parse_args(vec!["--config".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_help() {
+        let cli_args = parse_args(vec!["-h".to_string()]).unwrap();
+        assert!(cli_args.help);
+        let cli_args = parse_args(vec!["--help".to_string()]).unwrap();
+        assert!(cli_args.help);
+    }
+
+    #[test]
+    fn test_parse_args_unknown_argument() {
+        let result = parse_args(vec!["--unknown".to_string()]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_watcher_recreated_when_reload_interval_changes() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let mut config_watcher = ConfigWatcher::default();
+        assert!(config_watcher.watch(&config_manager).unwrap());
+
+        // 間隔が変わらなければ作り直さない
+        config_manager.reload_config().unwrap();
+        assert!(!config_watcher.watch(&config_manager).unwrap());
+
+        let text = fs::read_to_string(&config_path).unwrap();
+        fs::write(
+            &config_path,
+            text.replace(
+                "config_reload_interval = 5000",
+                "config_reload_interval = 1000",
+            ),
+        )
+        .unwrap();
+        config_manager.reload_config().unwrap();
+        assert!(config_watcher.watch(&config_manager).unwrap());
+        assert_eq!(config_watcher.reload_interval, 1000);
+    }
+
+    // Tests for config reload debouncing
+    fn modify_event(path: &Path) -> notify::Result<notify::Event> {
+        Ok(
+            notify::Event::new(notify::EventKind::Modify(notify::event::ModifyKind::Any))
+                .add_path(path.to_path_buf()),
+        )
+    }
+
+    #[test]
+    fn test_config_reload_debounces_rapid_events() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let (tx, rx) = channel();
+
+        // 切り詰め→書き込みのように、デバウンス期間内に2回イベントが届く
+        tx.send(modify_event(&config_path)).unwrap();
+        let sender = thread::spawn({
+            let config_path = config_path.clone();
+            move || {
+                thread::sleep(Duration::from_millis(50));
+                tx.send(modify_event(&config_path)).unwrap();
+            }
+        });
+
+        assert!(handle_config_reload(&mut config_manager, &rx));
+        sender.join().unwrap();
+        // 2回目のイベントは最初の再読み込みにまとめられている
+        assert!(!handle_config_reload(&mut config_manager, &rx));
+    }
+
+    #[test]
+    fn test_config_reload_does_not_wait_for_other_files() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let log_path = temp_dir.path().join("clipboard-formatter.log");
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let (tx, rx) = channel();
+
+        // 同じディレクトリのログファイルへの書き込みが続いても、設定ファイルのイベントが
+        // 落ち着いた時点で再読み込みする
+        tx.send(modify_event(&config_path)).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let sender = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                while !stop.load(Ordering::SeqCst) && tx.send(modify_event(&log_path)).is_ok() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        });
+
+        let started = Instant::now();
+        assert!(handle_config_reload(&mut config_manager, &rx));
+        let debounce =
+            Duration::from_millis(config_manager.get_config().app.config_reload_debounce);
+        assert!(started.elapsed() < debounce * 5, "{:?}", started.elapsed());
+        stop.store(true, Ordering::SeqCst);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_config_reload_caps_debounce() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let (tx, rx) = channel();
+
+        // 設定ファイル自体への書き込みが続いても、待ち時間の上限で再読み込みする
+        tx.send(modify_event(&config_path)).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let sender = thread::spawn({
+            let stop = Arc::clone(&stop);
+            let config_path = config_path.clone();
+            move || {
+                while !stop.load(Ordering::SeqCst) && tx.send(modify_event(&config_path)).is_ok() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        });
+
+        let started = Instant::now();
+        assert!(handle_config_reload(&mut config_manager, &rx));
+        let debounce =
+            Duration::from_millis(config_manager.get_config().app.config_reload_debounce);
+        assert!(
+            started.elapsed() < debounce * (CONFIG_RELOAD_MAX_DEBOUNCES + 5),
+            "{:?}",
+            started.elapsed()
+        );
+        stop.store(true, Ordering::SeqCst);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_reload_request() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let requested = AtomicBool::new(false);
+
+        fs::write(
+            &config_path,
+            DEFAULT_CONFIG.replace(
+                "clipboard_poll_interval = 300",
+                "clipboard_poll_interval = 50",
+            ),
+        )
+        .unwrap();
+        // フラグが立っていなければ読み込まない
+        assert!(!handle_reload_request(&mut config_manager, &requested));
+        assert_eq!(config_manager.get_config().app.clipboard_poll_interval, 300);
+
+        // フラグが立っていれば読み込み、フラグを下ろす
+        requested.store(true, Ordering::SeqCst);
+        assert!(handle_reload_request(&mut config_manager, &requested));
+        assert_eq!(config_manager.get_config().app.clipboard_poll_interval, 50);
+        assert!(!requested.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_config_reload_ignores_other_paths() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config_manager = ConfigManager::new(Some(config_path)).unwrap();
+        let (tx, rx) = channel();
+
+        tx.send(modify_event(&temp_dir.path().join("other.toml")))
+            .unwrap();
+        assert!(!handle_config_reload(&mut config_manager, &rx));
+    }
+
+    #[test]
+    fn test_config_reload_keeps_config_when_removed() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let (tx, rx) = channel();
+
+        fs::remove_file(&config_path).unwrap();
+        tx.send(Ok(notify::Event::new(notify::EventKind::Remove(
+            notify::event::RemoveKind::File,
+        ))
+        .add_path(config_path.clone())))
+            .unwrap();
+        // 削除された場合は読み込みに失敗したとみなさず、読み込み済みの設定を使い続ける
+        assert!(!handle_config_reload(&mut config_manager, &rx));
+        assert_eq!(config_manager.reload_failures, 0);
+        assert_eq!(config_manager.get_config().app.clipboard_poll_interval, 300);
+        // 削除されたファイルを作り直さない
+        assert!(!config_path.exists());
+    }
+
+    #[test]
+    fn test_config_watcher_detects_recreated_config() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config_manager = ConfigManager::new(Some(config_path.clone())).unwrap();
+        let mut config_watcher = ConfigWatcher::default();
+        config_watcher.watch(&config_manager).unwrap();
+
+        // 削除されても監視は続き、作り直された設定ファイルを読み込む
+        fs::remove_file(&config_path).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!handle_config_reload(
+            &mut config_manager,
+            &config_watcher.rx
+        ));
+        fs::write(
+            &config_path,
+            DEFAULT_CONFIG.replace(
+                "clipboard_poll_interval = 300",
+                "clipboard_poll_interval = 50",
+            ),
+        )
+        .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !handle_config_reload(&mut config_manager, &config_watcher.rx) {
+            assert!(
+                Instant::now() < deadline,
+                "recreated config was not detected"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(config_manager.get_config().app.clipboard_poll_interval, 50);
+    }
+
+    #[test]
+    fn test_is_config_event_path() {
+        let config_path = Path::new("/home/user/.config/clipboard-formatter/config.toml");
+        assert!(is_config_event_path(config_path, config_path));
+        assert!(is_config_event_path(
+            Path::new("./config.toml"),
+            Path::new("config.toml")
+        ));
+        assert!(!is_config_event_path(
+            &config_path.with_file_name("undo.json"),
+            config_path
+        ));
+    }
+
+    #[test]
+    fn test_toggle_formatting() {
+        let paused = AtomicBool::new(false);
+        toggle_formatting(&paused);
+        assert!(paused.load(Ordering::SeqCst));
+        toggle_formatting(&paused);
+        assert!(!paused.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_parse_hotkey() {
+        assert_eq!(
+            parse_hotkey("ctrl+alt+p").unwrap(),
+            HotKey::new(Some(Modifiers::CONTROL | Modifiers::ALT), Code::KeyP)
+        );
+        assert_eq!(
+            parse_hotkey("Win + Shift + 1").unwrap(),
+            HotKey::new(Some(Modifiers::SUPER | Modifiers::SHIFT), Code::Digit1)
+        );
+        // 英数字以外のキーも指定できる
+        assert_eq!(
+            parse_hotkey("ctrl+F5").unwrap(),
+            HotKey::new(Some(Modifiers::CONTROL), Code::F5)
+        );
+        assert_eq!(
+            parse_hotkey("super+ArrowUp").unwrap(),
+            HotKey::new(Some(Modifiers::SUPER), Code::ArrowUp)
+        );
+        assert!(parse_hotkey("ctrl+alt").is_err());
+        assert!(parse_hotkey("ctrl+p+q").is_err());
+        assert!(parse_hotkey("ctrl+nokey").is_err());
+    }
+
+    #[test]
+    fn test_run_loop_records_metrics() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let mut config_manager = fast_poll_config_manager();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let metrics = Mutex::new(Metrics::default());
+        let stopper = stop_after(&shutdown, 50);
+
+        run_loop(
+            &mut handler,
+            &mut config_manager,
+            None,
+            &shutdown,
+            &AtomicBool::new(false),
+            None,
+            Some(&metrics),
+        );
+        stopper.join().unwrap();
+
+        let metrics = metrics.into_inner().unwrap();
+        assert_eq!(metrics.totals.formatted, 1);
+        assert_eq!(metrics.totals.polls, handler.ctx.get_count);
+        assert!(metrics.last_formatted_at.is_some());
+    }
+
+    // Tests for run_loop
+    // Tests for run_loop
+    #[test]
+    fn test_run_loop_stops_on_shutdown_flag() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let mut config_manager = ConfigManager::with_config(
+            PathBuf::new(),
+            AppConfig {
+                app: AppSettings {
+                    clipboard_poll_interval: 10,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let mut config_watcher = ConfigWatcher::default();
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let shutdown_flag = Arc::clone(&shutdown);
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            shutdown_flag.store(true, Ordering::SeqCst);
+        });
+
+        // シャットダウンフラグが立つとループを抜ける
+        run_loop(
+            &mut handler,
+            &mut config_manager,
+            Some(&mut config_watcher),
+            &shutdown,
+            &AtomicBool::new(false),
+            None,
+            None,
+        );
+        stopper.join().unwrap();
+
+        assert_eq!(handler.ctx.contents, "ABC");
+    }
+
+    #[test]
+    fn test_run_loop_without_config_watcher() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let mut config_manager = fast_poll_config_manager();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stopper = stop_after(&shutdown, 50);
+
+        // 設定ファイルを監視しなくても整形とシャットダウンは行われる
+        run_loop(
+            &mut handler,
+            &mut config_manager,
+            None,
+            &shutdown,
+            &AtomicBool::new(false),
+            None,
+            None,
+        );
+        stopper.join().unwrap();
+
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.ctx.set_count, 1);
+    }
+
+    #[test]
+    fn test_run_loop_does_not_poll_after_shutdown() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let mut config_manager = ConfigManager::with_config(PathBuf::new(), AppConfig::default());
+        let mut config_watcher = ConfigWatcher::default();
+        let shutdown = AtomicBool::new(true);
+
+        run_loop(
+            &mut handler,
+            &mut config_manager,
+            Some(&mut config_watcher),
+            &shutdown,
+            &AtomicBool::new(false),
+            None,
+            None,
+        );
+
+        assert_eq!(handler.ctx.contents, "ＡＢＣ");
+        assert_eq!(handler.ctx.set_count, 0);
+    }
+
+    // Tests for clipboard change listener
+    fn stop_after(shutdown: &Arc<AtomicBool>, millis: u64) -> thread::JoinHandle<()> {
+        let shutdown_flag = Arc::clone(shutdown);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(millis));
+            shutdown_flag.store(true, Ordering::SeqCst);
+        })
+    }
+
+    fn fast_poll_config_manager() -> ConfigManager {
+        ConfigManager::with_config(
+            PathBuf::new(),
+            AppConfig {
+                app: AppSettings {
+                    clipboard_poll_interval: 5,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_run_loop_event_mode_reads_only_on_change() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let mut config_manager = fast_poll_config_manager();
+        let mut config_watcher = ConfigWatcher::default();
+        let (_change_tx, change_rx) = channel();
+        let listener = ChannelChangeListener { rx: change_rx };
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        // 変更通知がなければ起動時の1回しか処理しない
+        let stopper = stop_after(&shutdown, 100);
+        run_loop(
+            &mut handler,
+            &mut config_manager,
+            Some(&mut config_watcher),
+            &shutdown,
+            &AtomicBool::new(false),
+            Some(&listener),
+            None,
+        );
+        stopper.join().unwrap();
+
+        assert_eq!(handler.ctx.contents, "ABC");
+        assert_eq!(handler.ctx.get_count, 1);
+    }
+
+    #[test]
+    fn test_run_loop_poll_mode_reads_every_iteration() {
+        let mut handler = ClipboardHandler::with_clipboard(
+            MockClipboard::with_contents("ＡＢＣ"),
+            HandlerOptions::default(),
+        );
+        let mut config_manager = fast_poll_config_manager();
+        let mut config_watcher = ConfigWatcher::default();
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let stopper = stop_after(&shutdown, 100);
+        run_loop(
+            &mut handler,
+            &mut config_manager,
+            Some(&mut config_watcher),
+            &shutdown,
+            &AtomicBool::new(false),
+            None,
+            None,
+        );
+        stopper.join().unwrap();
+
+        assert!(handler.ctx.get_count > 2);
+    }
+}
@@ -0,0 +1,107 @@
+//! 複数のモジュールのテストで使う共通の補助
+
+use crate::clipboard::{Clipboard, ClipboardError};
+use crate::config::{AppConfig, AppSettings, Replacements};
+use anyhow::Result;
+use std::sync::Mutex;
+
+// 環境変数を書き換えるテストを並行して実行しないためのロック
+pub(crate) static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+// テスト用の設定を組み立てる。指定しなかった項目はデフォルト値のまま
+#[derive(Default)]
+pub(crate) struct ConfigBuilder(AppConfig);
+
+impl ConfigBuilder {
+    pub(crate) fn replacements(mut self, replacements: Replacements) -> Self {
+        self.0.replacements = replacements;
+        self
+    }
+
+    pub(crate) fn replacement(mut self, original: &str, replacement: &str) -> Self {
+        self.0
+            .replacements
+            .insert(original.to_string(), replacement.into());
+        self
+    }
+
+    pub(crate) fn exclusions(mut self, exclusions: &[char]) -> Self {
+        self.0.exclusions = exclusions.to_vec();
+        self
+    }
+
+    pub(crate) fn app(mut self, update: impl FnOnce(&mut AppSettings)) -> Self {
+        update(&mut self.0.app);
+        self
+    }
+
+    pub(crate) fn build(self) -> AppConfig {
+        self.0
+    }
+}
+
+pub(crate) const REPLACEMENT_LIST_CONFIG: &str = r#"
+    [app]
+    clipboard_poll_interval = 1000
+    config_reload_interval = 5000
+    remove_duplicate_previous_lines = false
+    convert_katakana = false
+    direction = "to_half"
+
+    [replacements]
+    "ｘ" = "x"
+
+    [[replacement]]
+    from = "x"
+    to = "xy"
+
+    [[replacement]]
+    from = "y"
+    to = "z"
+    enabled = false
+
+    [[replacement]]
+    from = "x"
+    to = "w"
+    "#;
+
+// Tests for ClipboardHandler with a mock clipboard
+#[derive(Default)]
+pub(crate) struct MockClipboard {
+    pub(crate) contents: String,
+    pub(crate) get_count: usize,
+    pub(crate) set_count: usize,
+    // trueの場合は画像などテキスト以外の内容が入っているものとして扱う
+    pub(crate) non_text: bool,
+    // この回数だけ書き込みに失敗する
+    pub(crate) set_failures: usize,
+}
+
+impl MockClipboard {
+    pub(crate) fn with_contents(contents: &str) -> Self {
+        Self {
+            contents: contents.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Clipboard for MockClipboard {
+    fn get_contents(&mut self) -> Result<String, ClipboardError> {
+        self.get_count += 1;
+        if self.non_text {
+            return Err(ClipboardError::NonText("image/png".to_string()));
+        }
+        Ok(self.contents.clone())
+    }
+
+    fn set_contents(&mut self, content: String) -> Result<(), ClipboardError> {
+        self.set_count += 1;
+        if self.set_failures > 0 {
+            self.set_failures -= 1;
+            return Err(ClipboardError::SetContents("clipboard is busy".to_string()));
+        }
+        self.contents = content;
+        Ok(())
+    }
+}
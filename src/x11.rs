@@ -0,0 +1,637 @@
+//! X11のセレクションを使う機能（変更の通知、貼り付けの検知、提供元とフォーカスの確認）
+
+use crate::clipboard::ClipboardChangeListener;
+use crate::config::Selection;
+use anyhow::Result;
+use std::sync::Mutex;
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+use {
+    crate::clipboard::ChannelChangeListener,
+    anyhow::Context,
+    log::debug,
+    std::fs,
+    std::path::Path,
+    std::sync::{mpsc::channel, Arc},
+    std::thread,
+};
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub(crate) fn create_change_listener(
+    selection: Selection,
+) -> Result<Box<dyn ClipboardChangeListener>> {
+    use xcb::xfixes;
+
+    let (connection, screen) = xcb::Connection::connect(None)
+        .map_err(|e| anyhow::anyhow!("Failed to connect to X server: {e}"))?;
+    let root = connection
+        .get_setup()
+        .roots()
+        .nth(screen as usize)
+        .context("Failed to get X11 screen")?
+        .root();
+    let selection_atom = xcb::intern_atom(&connection, false, selection.atom_name())
+        .get_reply()
+        .map_err(|e| anyhow::anyhow!("Failed to intern {} atom: {e}", selection.atom_name()))?
+        .atom();
+    let xfixes_extension = xcb::query_extension(&connection, "XFIXES")
+        .get_reply()
+        .map_err(|e| anyhow::anyhow!("Failed to query XFIXES extension: {e}"))?;
+    if !xfixes_extension.present() {
+        anyhow::bail!("XFIXES extension is not available");
+    }
+    xfixes::query_version(&connection, 5, 0)
+        .get_reply()
+        .map_err(|e| anyhow::anyhow!("Failed to query XFIXES version: {e}"))?;
+    xfixes::select_selection_input(
+        &connection,
+        root,
+        selection_atom,
+        xfixes::SELECTION_EVENT_MASK_SET_SELECTION_OWNER
+            | xfixes::SELECTION_EVENT_MASK_SELECTION_WINDOW_DESTROY
+            | xfixes::SELECTION_EVENT_MASK_SELECTION_CLIENT_CLOSE,
+    );
+    connection.flush();
+
+    let selection_notify = xfixes_extension.first_event() + xfixes::SELECTION_NOTIFY;
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        while let Some(event) = connection.wait_for_event() {
+            if event.response_type() & !0x80 == selection_notify && tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Box::new(ChannelChangeListener { rx }))
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+pub(crate) fn create_change_listener(
+    _selection: Selection,
+) -> Result<Box<dyn ClipboardChangeListener>> {
+    anyhow::bail!("Clipboard change notifications are not supported on this platform")
+}
+
+// format_on = "paste"で使う、整形後の内容を用意して貼り付けを検知するセレクションの所有者。
+// 貼り付けそのものを通知するAPIはないため、セレクションの所有者になり、
+// 他のアプリケーションからテキストを要求されたことを貼り付けとみなす
+pub(crate) trait PasteOffer {
+    // 整形後の内容を用意してセレクションの所有者になる
+    fn offer(&self, formatted: String) -> Result<()>;
+    // 用意した内容が要求されていればtrueを返す（1回の貼り付けにつき1回だけ）
+    fn take_paste(&self) -> bool;
+    // 他のアプリケーションがコピーして所有者でなくなるまでtrue
+    fn is_offering(&self) -> bool;
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PasteOfferState {
+    pub(crate) content: Option<String>,
+    pub(crate) pasted: bool,
+}
+
+// ChangePropertyリクエストのうち、内容以外の部分のバイト数
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+const CHANGE_PROPERTY_HEADER_BYTES: usize = 24;
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+struct X11PasteOffer {
+    connection: Arc<xcb::Connection>,
+    window: xcb::Window,
+    selection: xcb::Atom,
+    state: Arc<Mutex<PasteOfferState>>,
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+impl PasteOffer for X11PasteOffer {
+    fn offer(&self, formatted: String) -> Result<()> {
+        // INCRによる分割転送には対応していないため、1回のChangePropertyに収まらない内容は用意しない。
+        // 上限を超えるリクエストを送るとxcbが接続を閉じてしまう
+        let max_bytes = (self.connection.get_maximum_request_length() as usize * 4)
+            .saturating_sub(CHANGE_PROPERTY_HEADER_BYTES);
+        if formatted.len() > max_bytes {
+            anyhow::bail!(
+                "Formatted content is too large to offer for pasting ({} bytes, max {max_bytes} bytes)",
+                formatted.len()
+            );
+        }
+        *lock_paste_offer(&self.state) = PasteOfferState {
+            content: Some(formatted),
+            pasted: false,
+        };
+        xcb::set_selection_owner(
+            &self.connection,
+            self.window,
+            self.selection,
+            xcb::CURRENT_TIME,
+        );
+        let owner = xcb::get_selection_owner(&self.connection, self.selection)
+            .get_reply()
+            .map_err(|e| {
+                anyhow::anyhow!("Failed to get selection owner: X error {}", e.error_code())
+            })?
+            .owner();
+        if owner != self.window {
+            lock_paste_offer(&self.state).content = None;
+            anyhow::bail!("Failed to take ownership of the selection");
+        }
+        Ok(())
+    }
+
+    fn take_paste(&self) -> bool {
+        std::mem::take(&mut lock_paste_offer(&self.state).pasted)
+    }
+
+    fn is_offering(&self) -> bool {
+        lock_paste_offer(&self.state).content.is_some()
+    }
+}
+
+fn lock_paste_offer(state: &Mutex<PasteOfferState>) -> std::sync::MutexGuard<'_, PasteOfferState> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// X11で貼り付け用の内容を提供するウィンドウを作成し、セレクションの要求に応答するスレッドを起動する
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub(crate) fn create_paste_offer(selection: Selection) -> Result<Box<dyn PasteOffer>> {
+    let (connection, screen) = xcb::Connection::connect(None)
+        .map_err(|e| anyhow::anyhow!("Failed to connect to X server: {e}"))?;
+    let root = connection
+        .get_setup()
+        .roots()
+        .nth(screen as usize)
+        .context("Failed to get X11 screen")?
+        .root();
+    let intern = |name: &str| {
+        xcb::intern_atom(&connection, false, name)
+            .get_reply()
+            .map(|reply| reply.atom())
+            .map_err(|e| anyhow::anyhow!("Failed to intern {name} atom: {e}"))
+    };
+    let selection_atom = intern(selection.atom_name())?;
+    let targets = intern("TARGETS")?;
+    let utf8_string = intern("UTF8_STRING")?;
+    let text_targets = [utf8_string, xcb::ATOM_STRING, intern("TEXT")?];
+    let window = connection.generate_id();
+    xcb::create_window(
+        &connection,
+        xcb::COPY_FROM_PARENT as u8,
+        window,
+        root,
+        0,
+        0,
+        1,
+        1,
+        0,
+        xcb::WINDOW_CLASS_INPUT_ONLY as u16,
+        xcb::COPY_FROM_PARENT,
+        &[],
+    );
+    connection.flush();
+
+    let connection = Arc::new(connection);
+    let state = Arc::new(Mutex::new(PasteOfferState::default()));
+    {
+        let connection = Arc::clone(&connection);
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            while let Some(event) = connection.wait_for_event() {
+                match event.response_type() & !0x80 {
+                    xcb::SELECTION_CLEAR => lock_paste_offer(&state).content = None,
+                    xcb::SELECTION_REQUEST => {
+                        let request: &xcb::SelectionRequestEvent =
+                            unsafe { xcb::cast_event(&event) };
+                        // 古いクライアントはpropertyにNONEを指定するため、その場合はtargetを使う
+                        let property = match request.property() {
+                            xcb::NONE => request.target(),
+                            property => property,
+                        };
+                        let mut state = lock_paste_offer(&state);
+                        let replied = match &state.content {
+                            Some(_) if request.target() == targets => {
+                                let mut supported = vec![targets];
+                                supported.extend(text_targets);
+                                xcb::change_property(
+                                    &connection,
+                                    xcb::PROP_MODE_REPLACE as u8,
+                                    request.requestor(),
+                                    property,
+                                    xcb::ATOM_ATOM,
+                                    32,
+                                    &supported,
+                                );
+                                true
+                            }
+                            Some(content) if text_targets.contains(&request.target()) => {
+                                xcb::change_property(
+                                    &connection,
+                                    xcb::PROP_MODE_REPLACE as u8,
+                                    request.requestor(),
+                                    property,
+                                    utf8_string,
+                                    8,
+                                    content.as_bytes(),
+                                );
+                                state.pasted = true;
+                                true
+                            }
+                            _ => false,
+                        };
+                        drop(state);
+                        let notify = xcb::SelectionNotifyEvent::new(
+                            request.time(),
+                            request.requestor(),
+                            request.selection(),
+                            request.target(),
+                            if replied { property } else { xcb::NONE },
+                        );
+                        xcb::send_event(
+                            &connection,
+                            false,
+                            request.requestor(),
+                            xcb::EVENT_MASK_NO_EVENT,
+                            &notify,
+                        );
+                        connection.flush();
+                    }
+                    _ => {}
+                }
+            }
+            // 接続が閉じられると要求に応答できないため、内容を用意していない状態に戻す
+            lock_paste_offer(&state).content = None;
+        });
+    }
+    Ok(Box::new(X11PasteOffer {
+        connection,
+        window,
+        selection: selection_atom,
+        state,
+    }))
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+pub(crate) fn create_paste_offer(_selection: Selection) -> Result<Box<dyn PasteOffer>> {
+    anyhow::bail!("Detecting pastes is not supported on this platform")
+}
+
+// 大文字小文字を区別せず、いずれかの文字列がウィンドウ情報に含まれていればtrueを返す
+pub(crate) fn is_focus_blocked(focused: &str, patterns: &[String]) -> bool {
+    let focused = focused.to_lowercase();
+    patterns
+        .iter()
+        .filter(|pattern| !pattern.is_empty())
+        .any(|pattern| focused.contains(&pattern.to_lowercase()))
+}
+
+// クリップボードの内容の提供元。X11ではセレクションを所有するウィンドウの情報から取得する
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ClipboardSource {
+    pub(crate) pid: Option<u32>,
+    // WM_CLASSまたは実行ファイル名
+    pub(crate) name: Option<String>,
+}
+
+// 自分自身のプロセス、他に起動しているclipboard-formatter、除外リストのアプリケーションが提供元ならtrue
+pub(crate) fn is_source_blocked(
+    source: &ClipboardSource,
+    own_pid: u32,
+    patterns: &[String],
+) -> bool {
+    if source.pid == Some(own_pid) {
+        return true;
+    }
+    source.name.as_deref().is_some_and(|name| {
+        is_focus_blocked(name, &[env!("CARGO_PKG_NAME").to_string()])
+            || is_focus_blocked(name, patterns)
+    })
+}
+
+// クリップボードの内容の提供元を調べる。作成できない環境（X11以外）では、
+// 直前に書き込んだ内容と一致するかどうかによる確認だけを行う
+pub(crate) trait SourceLookup {
+    // 所有者が提供元の情報を設定していない場合（clipboard-formatter自身の書き込みを含む）はNone
+    fn source(&self) -> Option<ClipboardSource>;
+}
+
+// 変更のたびに接続し直さないよう、X11サーバーへの接続とアトムを保持する
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+struct X11SourceLookup {
+    connection: xcb::Connection,
+    selection: xcb::Atom,
+    net_wm_pid: xcb::Atom,
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub(crate) fn create_source_lookup(selection: Selection) -> Result<Box<dyn SourceLookup>> {
+    let (connection, _) = xcb::Connection::connect(None)
+        .map_err(|e| anyhow::anyhow!("Failed to connect to X server: {e}"))?;
+    let intern = |name: &str| {
+        xcb::intern_atom(&connection, false, name)
+            .get_reply()
+            .map(|reply| reply.atom())
+            .map_err(|e| anyhow::anyhow!("Failed to intern {name} atom: {e}"))
+    };
+    let selection = intern(selection.atom_name())?;
+    let net_wm_pid = intern("_NET_WM_PID")?;
+    Ok(Box::new(X11SourceLookup {
+        connection,
+        selection,
+        net_wm_pid,
+    }))
+}
+
+// セレクションの所有者の_NET_WM_PIDとWM_CLASSを取得する
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+impl SourceLookup for X11SourceLookup {
+    fn source(&self) -> Option<ClipboardSource> {
+        let property = |window: xcb::Window, atom: xcb::Atom| {
+            xcb::get_property(
+                &self.connection,
+                false,
+                window,
+                atom,
+                xcb::ATOM_ANY,
+                0,
+                1024,
+            )
+            .get_reply()
+            .ok()
+        };
+        let owner = xcb::get_selection_owner(&self.connection, self.selection)
+            .get_reply()
+            .ok()?
+            .owner();
+        if owner == xcb::NONE {
+            return None;
+        }
+        let pid = property(owner, self.net_wm_pid)
+            .and_then(|reply| reply.value::<u32>().first().copied());
+        let name = property(owner, xcb::ATOM_WM_CLASS)
+            .map(|class| {
+                String::from_utf8_lossy(class.value::<u8>())
+                    .split('\0')
+                    .filter(|s| !s.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|name| !name.is_empty())
+            .or_else(|| pid.and_then(process_name));
+        if pid.is_none() && name.is_none() {
+            return None;
+        }
+        Some(ClipboardSource { pid, name })
+    }
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+pub(crate) fn create_source_lookup(_selection: Selection) -> Result<Box<dyn SourceLookup>> {
+    anyhow::bail!("Detecting the clipboard source is not supported on this platform")
+}
+
+// /proc/<pid>/cmdlineから実行ファイル名を取得する（commは15文字で切り詰められるため使わない）
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn process_name(pid: u32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let program = cmdline.split(|&b| b == 0).next()?;
+    let program = String::from_utf8_lossy(program);
+    Path::new(program.as_ref())
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+// フォーカス中のウィンドウのWM_CLASSとタイトルを調べる。作成できない環境（X11以外）では調べない
+pub(crate) trait FocusLookup {
+    // 取得できない場合はNone
+    fn focused_window(&mut self) -> Option<String>;
+}
+
+// 整形のたびに接続し直さないよう、X11サーバーへの接続とアトムを保持する。
+// 最初に調べるときに接続し、接続にエラーが起きた場合は次に調べるときに接続し直す
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+#[derive(Default)]
+struct X11FocusLookup {
+    connection: Option<X11FocusConnection>,
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+struct X11FocusConnection {
+    connection: xcb::Connection,
+    root: xcb::Window,
+    net_active_window: xcb::Atom,
+    net_wm_name: xcb::Atom,
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+impl X11FocusConnection {
+    fn connect() -> Result<Self> {
+        let (connection, screen) = xcb::Connection::connect(None)
+            .map_err(|e| anyhow::anyhow!("Failed to connect to X server: {e}"))?;
+        let root = connection
+            .get_setup()
+            .roots()
+            .nth(screen as usize)
+            .context("X server has no screen for the connection")?
+            .root();
+        let intern = |name: &str| {
+            xcb::intern_atom(&connection, false, name)
+                .get_reply()
+                .map(|reply| reply.atom())
+                .map_err(|e| anyhow::anyhow!("Failed to intern {name} atom: {e}"))
+        };
+        let net_active_window = intern("_NET_ACTIVE_WINDOW")?;
+        let net_wm_name = intern("_NET_WM_NAME")?;
+        Ok(Self {
+            connection,
+            root,
+            net_active_window,
+            net_wm_name,
+        })
+    }
+
+    fn focused_window(&self) -> Option<String> {
+        let property = |window: xcb::Window, atom: xcb::Atom| {
+            xcb::get_property(
+                &self.connection,
+                false,
+                window,
+                atom,
+                xcb::ATOM_ANY,
+                0,
+                1024,
+            )
+            .get_reply()
+            .ok()
+        };
+
+        let active = property(self.root, self.net_active_window)?;
+        let window = *active.value::<xcb::Window>().first()?;
+        if window == xcb::NONE {
+            return None;
+        }
+        let mut parts = Vec::new();
+        if let Some(class) = property(window, xcb::ATOM_WM_CLASS) {
+            // WM_CLASSはインスタンス名とクラス名がNUL区切りで並んでいる
+            parts.extend(
+                String::from_utf8_lossy(class.value::<u8>())
+                    .split('\0')
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string),
+            );
+        }
+        let title = property(window, self.net_wm_name)
+            .filter(|reply| !reply.value::<u8>().is_empty())
+            .or_else(|| property(window, xcb::ATOM_WM_NAME));
+        if let Some(title) = title {
+            parts.push(String::from_utf8_lossy(title.value::<u8>()).into_owned());
+        }
+        Some(parts.join(" "))
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+impl FocusLookup for X11FocusLookup {
+    fn focused_window(&mut self) -> Option<String> {
+        if self.connection.is_none() {
+            match X11FocusConnection::connect() {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    debug!("{e}; not checking the focused window");
+                    return None;
+                }
+            }
+        }
+        let connection = self.connection.as_ref()?;
+        let window = connection.focused_window();
+        if connection.connection.has_error().is_err() {
+            self.connection = None;
+        }
+        window
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub(crate) fn create_focus_lookup() -> Option<Box<dyn FocusLookup>> {
+    Some(Box::new(X11FocusLookup::default()))
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+pub(crate) fn create_focus_lookup() -> Option<Box<dyn FocusLookup>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_focus_blocked() {
+        let patterns = vec!["KeePassXC".to_string(), "- Private".to_string()];
+        // WM_CLASSに一致（大文字小文字は区別しない）
+        assert!(is_focus_blocked(
+            "keepassxc KeePassXC Passwords.kdbx",
+            &patterns
+        ));
+        // タイトルに一致
+        assert!(is_focus_blocked(
+            "firefox firefox Example - Private",
+            &patterns
+        ));
+        assert!(!is_focus_blocked("Alacritty Alacritty ~/work", &patterns));
+        // 空文字列はすべてのウィンドウに一致させない
+        assert!(!is_focus_blocked("Alacritty", &["".to_string()]));
+        assert!(!is_focus_blocked("Alacritty", &[]));
+    }
+
+    #[test]
+    fn test_is_source_blocked() {
+        let patterns = vec!["KeePassXC".to_string()];
+        let source = |pid: Option<u32>, name: Option<&str>| ClipboardSource {
+            pid,
+            name: name.map(str::to_string),
+        };
+        // 自分自身のプロセス
+        assert!(is_source_blocked(&source(Some(42), None), 42, &patterns));
+        // 別に起動しているclipboard-formatter
+        assert!(is_source_blocked(
+            &source(Some(7), Some("clipboard-formatter")),
+            42,
+            &patterns
+        ));
+        // 除外リストのアプリケーション（WM_CLASS、大文字小文字は区別しない）
+        assert!(is_source_blocked(
+            &source(Some(7), Some("keepassxc KeePassXC")),
+            42,
+            &patterns
+        ));
+        assert!(!is_source_blocked(
+            &source(Some(7), Some("Alacritty Alacritty")),
+            42,
+            &patterns
+        ));
+        // 情報がない場合は除外しない
+        assert!(!is_source_blocked(
+            &ClipboardSource::default(),
+            42,
+            &patterns
+        ));
+    }
+}
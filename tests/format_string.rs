@@ -0,0 +1,33 @@
+use std::fs;
+use std::process::Command;
+
+const CONFIG: &str = r#"
+exclusions = ["！"]
+
+[app]
+clipboard_poll_interval = 300
+config_reload_interval = 5000
+
+[replacements]
+"，" = ", "
+"#;
+
+#[test]
+fn test_format_string() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config_path = temp_dir.path().join("config.toml");
+    fs::write(&config_path, CONFIG).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_clipboard-formatter"))
+        .args(["--format-string", "ＡＢＣ，１２３！"])
+        .arg("--config")
+        .arg(&config_path)
+        .env_remove("CLIPBOARD_FORMATTER_CONFIG")
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+
+    // 整形結果だけが改行付きで標準出力に書き出される
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ABC, 123！\n");
+}
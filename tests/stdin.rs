@@ -0,0 +1,81 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+const CONFIG: &str = r#"
+exclusions = ["！"]
+
+[app]
+clipboard_poll_interval = 300
+config_reload_interval = 5000
+direction = "to_half"
+
+[replacements]
+"，" = ", "
+"#;
+
+fn run_with_stdin(config_path: &Path, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_clipboard-formatter"))
+        .args(args)
+        .arg("--config")
+        .arg(config_path)
+        .env_remove("CLIPBOARD_FORMATTER_CONFIG")
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_stdin_filter() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config_path = temp_dir.path().join("config.toml");
+    fs::write(&config_path, CONFIG).unwrap();
+
+    let output = run_with_stdin(&config_path, &["--stdin"], "ＡＢＣ，１２３！\n２行目\n");
+
+    // 標準出力にはバージョンなどを含まず、整形結果だけが書き出される
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "ABC, 123！\n2行目\n"
+    );
+}
+
+#[test]
+fn test_stdin_filter_quiet() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config_path = temp_dir.path().join("config.toml");
+    fs::write(&config_path, CONFIG).unwrap();
+
+    let output = run_with_stdin(&config_path, &["--stdin", "--quiet"], "Ｘ");
+
+    assert!(output.status.success(), "{output:?}");
+    // --quietの場合は情報ログも出力しない
+    assert!(output.stderr.is_empty(), "{output:?}");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "X");
+}
+
+#[test]
+fn test_version() {
+    let output = Command::new(env!("CARGO_BIN_EXE_clipboard-formatter"))
+        .arg("--version")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("clipboard-formatter v{}\n", env!("CARGO_PKG_VERSION"))
+    );
+}